@@ -14,21 +14,101 @@
  */
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Args, Parser};
-use image::Rgba;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use image::{ImageFormat, Rgba};
+use moxcms::ColorProfile;
 
+use crate::cms;
+use crate::config::Preset;
+use crate::edges::EdgeOperator;
+use crate::io::is_stdio;
+use crate::mask::MaskMode;
+use crate::metrics::StatsFormat;
+use crate::palette::Quantizer;
+use crate::partition::{CellEdges, Partition};
 use crate::quad;
-use crate::utils::Vec2;
+use crate::search::Target;
+use crate::utils::{LumaStandard, Vec2};
 
 const VALUE_NAME_COLOR: &str = "COLOR";
 const VALUE_NAME_IMAGE: &str = "IMAGE";
+const VALUE_NAME_FORMAT: &str = "FORMAT";
+const VALUE_NAME_TEMPLATE: &str = "TEMPLATE";
+const VALUE_NAME_PROFILE: &str = "PROFILE";
+const VALUE_NAME_QUALITY: &str = "QUALITY";
+const VALUE_NAME_COUNT: &str = "COUNT";
+const VALUE_NAME_FRACTION: &str = "FRACTION";
+const VALUE_NAME_BITS: &str = "BITS";
+const VALUE_NAME_WEIGHTS: &str = "R,G,B,A";
+const VALUE_NAME_SIZE: &str = "SIZE";
+const VALUE_NAME_BYTES: &str = "BYTES";
+const VALUE_NAME_DECIBELS: &str = "DB";
+const VALUE_NAME_FILE: &str = "FILE";
+const VALUE_NAME_POINT: &str = "X,Y";
 const ARG_GRP_IN: &str = "input_args";
 const ARG_GRP_OUT: &str = "output_args";
+const ARG_GRP_TARGET: &str = "target_args";
 
 #[derive(Parser)]
 #[command(name = "Quadtree Over Media")]
 #[command(version, about, long_about = None)]
 pub(super) struct CliArgs {
+    #[command(subcommand)]
+    pub command: Commands,
+
+    /// Output verbosity, repeat for more verbosity
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// TOML or YAML file with the values of any of the options, YAML requires the `yaml` feature
+    ///
+    /// Keys are the long names of the options, e.g.: `min-depth = 2` `fill = true`
+    /// `palette = ["black", "white"]`. Options given on the command line override the ones of the file
+    /// and those they can't be used with, options of other commands are ignored.
+    /// Flags set by the file or the preset are turned off with `--no-<flag>`, e.g.: `--no-fill`
+    #[arg(long, value_name = VALUE_NAME_FILE, global = true)]
+    #[allow(dead_code)] // read before parsing the other options
+    pub config: Option<PathBuf>,
+
+    /// Named set of options, overridden by the config file and the command line
+    ///
+    /// Can also be given in the config file
+    #[arg(long, value_enum, global = true)]
+    #[allow(dead_code)] // read before parsing the other options
+    pub preset: Option<Preset>,
+}
+
+#[derive(Subcommand)]
+pub(super) enum Commands {
+    /// Draw the quads over the media, the default when no command is given
+    Render(Box<RenderArgs>),
+    /// Write the leaves of the quadtree as JSON or binary
+    Export(ExportArgs),
+    /// Compress the leaves and their colors into a file `decode` draws back
+    Encode(EncodeArgs),
+    /// Draw the quads of a file written by `encode`
+    Decode(DecodeArgs),
+    /// Print statistics of the quads, the time taken and the MSE, PSNR and SSIM of the filled quads
+    Stats(StatsArgs),
+    /// Print the MSE, PSNR and SSIM between two media of the same size
+    Compare(CompareArgs),
+}
+
+impl Commands {
+    /// if the output of the command is written to stdout
+    pub fn writes_stdout(&self) -> bool {
+        match self {
+            Commands::Render(render) => is_stdio(&render.io.output),
+            Commands::Export(export) => is_stdio(&export.output),
+            Commands::Encode(encode) => is_stdio(&encode.output),
+            Commands::Decode(decode) => is_stdio(&decode.output),
+            Commands::Stats(_) | Commands::Compare(_) => true,
+        }
+    }
+}
+
+#[derive(Args)]
+pub(super) struct RenderArgs {
     #[command(flatten)]
     pub io: IOArgs,
 
@@ -38,9 +118,114 @@ pub(super) struct CliArgs {
     #[command(flatten)]
     pub image: DrawingArgs,
 
-    /// Output verbosity, repeat for more verbosity
-    #[arg(short, long, action = clap::ArgAction::Count)]
-    pub verbose: u8,
+    #[command(flatten)]
+    pub search: SearchArgs,
+}
+
+#[derive(Args)]
+pub(super) struct ExportArgs {
+    /// Path to input media
+    ///
+    /// Use `-` to read the media from stdin
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub input: PathBuf,
+
+    /// Path to the output file
+    ///
+    /// Use `-` to write it to stdout
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_FILE)]
+    pub output: PathBuf,
+
+    /// Format of the output file
+    #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+    pub format: ExportFormat,
+
+    /// Export only the leaf containing this pixel, JSON only
+    #[arg(long, value_parser = parse_point, value_name = VALUE_NAME_POINT)]
+    pub at: Option<Vec2>,
+
+    /// Add the positions of the leaves touching each side of a leaf, JSON only
+    #[arg(long, value_parser)]
+    pub neighbors: bool,
+
+    #[command(flatten)]
+    pub calc: QuadArgs,
+}
+
+#[derive(Args)]
+pub(super) struct EncodeArgs {
+    /// Path to input media
+    ///
+    /// Use `-` to read the media from stdin
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub input: PathBuf,
+
+    /// Path to the encoded file
+    ///
+    /// Use `-` to write it to stdout
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_FILE)]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub calc: QuadArgs,
+}
+
+#[derive(Args)]
+pub(super) struct DecodeArgs {
+    /// Path to a file written by `encode`
+    ///
+    /// Use `-` to read it from stdin
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_FILE)]
+    pub input: PathBuf,
+
+    /// Path to output media
+    ///
+    /// Use `-` to write the media to stdout, requires `--output-format`
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub output: PathBuf,
+
+    /// Format of the output media, overrides the one guessed from the extension
+    #[arg(long, value_parser = parse_format, value_name = VALUE_NAME_FORMAT)]
+    pub output_format: Option<ImageFormat>,
+
+    #[command(flatten)]
+    pub encoding: EncodingArgs,
+
+    #[command(flatten)]
+    pub image: DrawingArgs,
+}
+
+#[derive(Args)]
+pub(super) struct StatsArgs {
+    /// Path to input media
+    ///
+    /// Use `-` to read the media from stdin
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub input: PathBuf,
+
+    /// Format of the statistics
+    #[arg(long, value_enum, default_value_t = StatsFormat::Human)]
+    pub format: StatsFormat,
+
+    #[command(flatten)]
+    pub calc: QuadArgs,
+}
+
+#[derive(Args)]
+pub(super) struct CompareArgs {
+    /// Path to input media
+    ///
+    /// Use `-` to read the media from stdin
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub input: PathBuf,
+
+    /// Path to the media the input is compared to
+    #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub reference: PathBuf,
+
+    /// Format of the comparison
+    #[arg(long, value_enum, default_value_t = StatsFormat::Human)]
+    pub format: StatsFormat,
 }
 
 #[derive(Args)]
@@ -48,23 +233,89 @@ pub(super) struct CliArgs {
 #[command(group(ArgGroup::new(ARG_GRP_OUT).required(true)))]
 pub(super) struct IOArgs {
     /// Path to input media or media folder
+    ///
+    /// Use `-` to read the media from stdin
     #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE, group = ARG_GRP_IN)]
     pub input: PathBuf,
 
     /// Path to output media or target folder
     ///
-    /// Suggested formats are PNG, JPEG, and BMP
+    /// Suggested formats are PNG, JPEG, and BMP.
+    /// Use `-` to write the media to stdout, requires `--output-format`
     #[arg(long, short, value_parser, value_name = VALUE_NAME_IMAGE, group = ARG_GRP_OUT)]
     pub output: PathBuf,
 
+    #[command(flatten)]
+    pub encoding: EncodingArgs,
+
+    /// Format of the output media, overrides the one guessed from the extension
+    ///
+    /// Passed as a file extension, e.g.: `png` `jpg` `webp`
+    #[arg(long, value_parser = parse_format, value_name = VALUE_NAME_FORMAT)]
+    pub output_format: Option<ImageFormat>,
+
+    /// Template for the names of the output media when processing a folder
+    ///
+    /// Supported placeholders are `{stem}` and `{ext}` of the input file,
+    /// `{depth}` (min depth), `{size}` (min quad size) and `{threshold}`.
+    /// If `--output-format` is specified `{ext}` is the extension of that format.
+    /// e.g.: `{stem}-quads.{ext}` `{stem}-t{threshold}.png`
+    #[arg(long, value_name = VALUE_NAME_TEMPLATE, default_value = DEFAULT_OUTPUT_NAME)]
+    pub output_name: String,
+
+    /// Copy the EXIF, XMP and ICC metadata of the input media to the output
+    ///
+    /// Supported only for PNG, JPEG and WebP
+    #[arg(long, value_parser)]
+    pub keep_metadata: bool,
+
+    /// Color space the quads are calculated and drawn in
+    ///
+    /// Input media are converted from their embedded ICC profile, or sRGB if they have none,
+    /// and the output is tagged with this profile.
+    /// Passed as `srgb` `display-p3` `adobe-rgb` `prophoto` `bt2020` or a path to an ICC file
+    #[arg(long, value_parser = parse_profile, value_name = VALUE_NAME_PROFILE)]
+    pub working_space: Option<ColorProfile>,
+
+    /// Color profile the output is converted to and tagged with
+    ///
+    /// Requires `--working-space`, accepts the same values
+    #[arg(long, value_parser = parse_profile, value_name = VALUE_NAME_PROFILE, requires = "working_space")]
+    pub output_profile: Option<ColorProfile>,
+
+    /// Print statistics of the quads, the time taken and the MSE, PSNR and SSIM of the filled quads
+    ///
+    /// Printed to stderr when the output is written to stdout
+    #[arg(long, value_enum, value_name = VALUE_NAME_FORMAT)]
+    pub stats: Option<StatsFormat>,
+}
+
+#[derive(Args)]
+pub(crate) struct EncodingArgs {
     /// Compression level of output image
     ///
-    /// Supported only for PNG and JPEG
+    /// Supported only for PNG, JPEG, AVIF (speed and quality) and TGA (`no` disables RLE).
+    /// With `max` PNG output tries every filter, and a palette if it has at most 256 colors
     #[arg(long, value_enum, default_value_t = ImgCompression::Default)]
     pub compression: ImgCompression,
+
+    /// Quality of lossy output from 1 to 100, overrides the one given by `--compression`
+    ///
+    /// Supported for JPEG, AVIF and WebP, which is saved lossy only if this is specified
+    /// and the `webp-lossy` feature is enabled
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), value_name = VALUE_NAME_QUALITY)]
+    pub quality: Option<u8>,
+
+    /// Chroma subsampling of JPEG output
+    #[arg(long, value_enum, default_value_t = ChromaSubsampling::S444)]
+    pub chroma_subsampling: ChromaSubsampling,
+
+    /// Save PNG output with a palette, quantizing media with more than 256 colors
+    #[arg(long, value_parser)]
+    pub indexed: bool,
 }
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 pub(super) struct QuadArgs {
     /// Minimun number of iterations that will always be performed
     ///
@@ -91,6 +342,90 @@ pub(super) struct QuadArgs {
     /// [default: rgba(10,10,10,255)]
     #[arg(long, short, value_parser = parse_color, value_name = VALUE_NAME_COLOR)]
     pub threshold: Option<Rgba<u8>>,
+
+    /// How the colors of the quadrants are compared
+    #[arg(long, value_enum, default_value_t = quad::Distance::Channels)]
+    pub distance: quad::Distance,
+
+    /// Maximum luma or weighted color distance between quadrants, from 0 to 1
+    ///
+    /// Used with `--distance luma` and `--distance weighted`
+    #[arg(long, value_parser = parse_unit, value_name = VALUE_NAME_FRACTION, default_value_t = quad::DEFAULT_DISTANCE_THRESHOLD)]
+    pub distance_threshold: f64,
+
+    /// Coefficients of the luma
+    #[arg(long, value_enum, default_value_t = LumaStandard::Rec709)]
+    pub luma_standard: LumaStandard,
+
+    /// Weights of the R, G, B and A channels in the weighted distance
+    ///
+    /// Accepts four non negative numbers separated by commas.
+    /// e.g.: `1,2,1,0` weights green twice and ignores alpha
+    #[arg(long, value_parser = parse_weights, value_name = VALUE_NAME_WEIGHTS, default_value = "1,1,1,1")]
+    pub channel_weights: [f64; 4],
+
+    /// Ignore the alpha channel when comparing the colors of the quadrants
+    #[arg(long, value_parser)]
+    pub ignore_alpha: bool,
+
+    /// How the color of a quad is calculated from its pixels
+    ///
+    /// Used both for the fill color and to decide if a quadrant is split
+    #[arg(long, value_enum, default_value_t = quad::Aggregation::Mean)]
+    pub aggregation: quad::Aggregation,
+
+    /// How a quad is split
+    ///
+    /// The binary and kd partitions cut a quad in two rectangles, the minimum depth counts each cut.
+    /// The square partition keeps the quads square at any aspect ratio.
+    /// The triangle and hexagon partitions don't support `--fill-with`
+    #[arg(long, value_enum, default_value_t = Partition::Quad)]
+    pub partition: Partition,
+
+    /// Side of the square cells tiling the image, as a power of two
+    ///
+    /// Used with `--partition square`, sides larger than the image are reduced to fit it.
+    /// [default: the largest power of two fitting in the image]
+    #[arg(long, value_parser = parse_cell_size, value_name = VALUE_NAME_SIZE)]
+    pub cell_size: Option<u32>,
+
+    /// How the cells on the right and bottom edges fit the image
+    ///
+    /// Used with `--partition square`
+    #[arg(long, value_enum, default_value_t = CellEdges::Crop)]
+    pub cell_edges: CellEdges,
+
+    /// What decides if a quad is split
+    #[arg(long, value_enum, default_value_t = quad::Criterion::Color)]
+    pub criterion: quad::Criterion,
+
+    /// Edge density above which a quad is split, from 0 to 1
+    ///
+    /// The density is the mean gradient magnitude of the luma inside the quad.
+    /// Used with `--criterion edges`
+    #[arg(long, value_parser = parse_unit, value_name = VALUE_NAME_FRACTION, default_value_t = quad::DEFAULT_EDGE_THRESHOLD)]
+    pub edge_threshold: f64,
+
+    /// Entropy above which a quad is split, in bits from 0 to 8
+    ///
+    /// The entropy is the one of the histogram of the luma inside the quad.
+    /// Used with `--criterion entropy`
+    #[arg(long, value_parser = parse_entropy, value_name = VALUE_NAME_BITS, default_value_t = quad::DEFAULT_ENTROPY_THRESHOLD)]
+    pub entropy_threshold: f64,
+
+    /// Gradient filter used to detect the edges
+    #[arg(long, value_enum, default_value_t = EdgeOperator::Sobel)]
+    pub edge_operator: EdgeOperator,
+
+    /// Grayscale image marking the regions of interest in white
+    ///
+    /// It's stretched to the size of the input media
+    #[arg(long, value_parser, value_name = VALUE_NAME_IMAGE)]
+    pub mask: Option<PathBuf>,
+
+    /// How the mask affects the subdivision of the quads
+    #[arg(long, value_enum, default_value_t = MaskMode::Modulate)]
+    pub mask_mode: MaskMode,
 }
 
 #[derive(Args)]
@@ -120,6 +455,63 @@ pub(super) struct DrawingArgs {
     /// by the average color of the quad
     #[arg(long, short = 'w', value_parser, value_name = VALUE_NAME_IMAGE)]
     pub fill_with: Option<PathBuf>,
+
+    /// Reduce the fill colors to a palette with at most this number of colors
+    ///
+    /// The palette is built from the colors of the quads weighted by their area.
+    /// Requires `--fill`
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), value_name = VALUE_NAME_COUNT, requires = "fill", conflicts_with = "palette")]
+    pub palette_size: Option<u16>,
+
+    /// Algorithm used to build the palette of `--palette-size`
+    #[arg(long, value_enum, default_value_t = Quantizer::MedianCut)]
+    pub quantizer: Quantizer,
+
+    /// Replace each fill color with the nearest one of this palette
+    ///
+    /// Passed as valid CSS colors, e.g.: `--palette black white "#f00"`.
+    /// Requires `--fill`
+    #[arg(long, value_parser = parse_color, value_name = VALUE_NAME_COLOR, num_args = 1.., requires = "fill")]
+    pub palette: Vec<Rgba<u8>>,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new(ARG_GRP_TARGET).multiple(false)))]
+pub(super) struct SearchArgs {
+    /// Search the lowest threshold giving at most this number of quads
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), value_name = VALUE_NAME_COUNT, group = ARG_GRP_TARGET)]
+    pub target_leaves: Option<u64>,
+
+    /// Search the lowest threshold giving an output of at most this size in bytes
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), value_name = VALUE_NAME_BYTES, group = ARG_GRP_TARGET)]
+    pub target_size: Option<u64>,
+
+    /// Search the highest threshold giving at least this PSNR of the filled quads, in decibels
+    #[arg(long, value_parser = parse_decibels, value_name = VALUE_NAME_DECIBELS, group = ARG_GRP_TARGET)]
+    pub target_psnr: Option<f64>,
+
+    /// Search the minimum quad size as well, after the threshold
+    #[arg(long, value_parser, requires = ARG_GRP_TARGET)]
+    pub search_min_size: bool,
+}
+
+impl SearchArgs {
+    /// the target the threshold is searched for, if any
+    pub fn target(&self) -> Option<Target> {
+        self.target_leaves
+            .map(Target::Leaves)
+            .or(self.target_size.map(Target::FileSize))
+            .or(self.target_psnr.map(Target::Psnr))
+    }
+}
+
+/// how the leaves are exported
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum ExportFormat {
+    /// A JSON object with the size of the media and the leaves
+    Json,
+    /// The uncompressed records of the leaves, as in the files of `encode`
+    Binary,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
@@ -136,6 +528,21 @@ pub(crate) enum ImgCompression {
     No,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum ChromaSubsampling {
+    /// Full resolution color
+    #[value(name = "444")]
+    S444,
+    /// Half horizontal color resolution
+    #[value(name = "422")]
+    S422,
+    /// Half horizontal and vertical color resolution
+    #[value(name = "420")]
+    S420,
+}
+
+pub(crate) const DEFAULT_OUTPUT_NAME: &str = "{stem}.{ext}";
+
 /// uses colorparser to parse the given color
 pub(super) fn parse_color(s: &str) -> Result<Rgba<u8>, String> {
     match csscolorparser::parse(s) {
@@ -144,12 +551,100 @@ pub(super) fn parse_color(s: &str) -> Result<Rgba<u8>, String> {
     }
 }
 
+const ERR_UNIT_RANGE: &str = "value must be between 0 and 1";
+
+/// parses a number between 0 and 1
+pub(super) fn parse_unit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        Ok(_) => Err(ERR_UNIT_RANGE.to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+const ERR_ENTROPY_RANGE: &str = "value must be between 0 and 8";
+
+/// parses an entropy in bits of a luma histogram
+pub(super) fn parse_entropy(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if (0.0..=8.0).contains(&v) => Ok(v),
+        Ok(_) => Err(ERR_ENTROPY_RANGE.to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+const ERR_DECIBELS_RANGE: &str = "value must be positive";
+
+/// parses a positive number of decibels
+pub(super) fn parse_decibels(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err(ERR_DECIBELS_RANGE.to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+const ERR_WEIGHTS_COUNT: &str = "expected 4 weights";
+const ERR_WEIGHTS_RANGE: &str = "weights must be non negative and not all zero";
+
+/// parses the weights of the RGBA channels
+pub(super) fn parse_weights(s: &str) -> Result<[f64; 4], String> {
+    let weights = s
+        .split(',')
+        .map(|w| w.trim().parse::<f64>().map_err(|_| ERR_NAN.to_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights: [f64; 4] = weights
+        .try_into()
+        .map_err(|_| ERR_WEIGHTS_COUNT.to_owned())?;
+    match weights.iter().all(|w| *w >= 0.0) && weights.iter().any(|w| *w > 0.0) {
+        true => Ok(weights),
+        false => Err(ERR_WEIGHTS_RANGE.to_owned()),
+    }
+}
+
+const ERR_NOT_POWER_OF_TWO: &str = "value must be a power of two";
+
+/// parses the side of the square cells
+pub(super) fn parse_cell_size(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(v) if v.is_power_of_two() => Ok(v),
+        Ok(_) => Err(ERR_NOT_POWER_OF_TWO.to_owned()),
+        Err(_) => Err(ERR_NAN.to_owned()),
+    }
+}
+
+const ERR_FORMAT_UNKNOWN: &str = "unknown image format";
+const ERR_FORMAT_NO_WRITE: &str = "image format not supported for output";
+
+/// parses an image format from its extension, only formats that can be written are accepted
+pub(super) fn parse_format(s: &str) -> Result<ImageFormat, String> {
+    match ImageFormat::from_extension(s.trim_start_matches('.')) {
+        Some(f) if f.writing_enabled() => Ok(f),
+        Some(_) => Err(ERR_FORMAT_NO_WRITE.to_owned()),
+        None => Err(ERR_FORMAT_UNKNOWN.to_owned()),
+    }
+}
+
+/// parses one of the built-in color profiles or loads an ICC profile from file
+pub(super) fn parse_profile(s: &str) -> Result<ColorProfile, String> {
+    if let Some(p) = cms::named_profile(s) {
+        return Ok(p);
+    }
+    match std::fs::read(s) {
+        Ok(icc) => ColorProfile::new_from_slice(&icc).map_err(|e| e.to_string()),
+        Err(e) => Err(format!(
+            "not one of {} nor a readable ICC file: {e}",
+            cms::PROFILE_NAMES.join(", ")
+        )),
+    }
+}
+
 const ERR_NOT_VEC2: &str = "not a vec2";
 const ERR_NAN: &str = "not a valid number";
 const ERR_QUAD_TOO_SMALL: &str = "min quad size is too small";
 
-/// parses vec2. Supported formats: `x,y`, `x;y`, `[x,y]`
-pub(super) fn parse_vec2(s: &str) -> Result<Vec2, String> {
+/// parses a position. Supported formats: `x,y`, `x;y`, `[x,y]`
+pub(super) fn parse_point(s: &str) -> Result<Vec2, String> {
     let split: Vec<&str> = s
         .split(|c: char| c.is_ascii_punctuation())
         .filter(|&p| !p.is_empty())
@@ -165,7 +660,12 @@ pub(super) fn parse_vec2(s: &str) -> Result<Vec2, String> {
         Ok(v) => v,
         Err(_) => return Err(ERR_NAN.to_owned()),
     };
-    let v = Vec2 { x, y };
+    Ok(Vec2 { x, y })
+}
+
+/// parses vec2 no smaller than the default min quad size, in the formats of `parse_point`
+pub(super) fn parse_vec2(s: &str) -> Result<Vec2, String> {
+    let v = parse_point(s)?;
     if v < quad::DEFAULT_MIN_SIZE {
         Err(ERR_QUAD_TOO_SMALL.to_owned())
     } else {
@@ -198,6 +698,70 @@ mod tests {
         parse_vec2(vec_str).unwrap()
     }
 
+    #[test_case("png"   => ImageFormat::Png; "png")]
+    #[test_case(".jpg"  => ImageFormat::Jpeg; "dot-jpg")]
+    #[test_case("JPEG"  => ImageFormat::Jpeg; "upper-jpeg")]
+    #[test_case("webp"  => ImageFormat::WebP; "webp")]
+    fn parses_format(format_str: &str) -> ImageFormat {
+        parse_format(format_str).unwrap()
+    }
+
+    #[test_case("nope"  => ERR_FORMAT_UNKNOWN; "unknown")]
+    #[test_case(""      => ERR_FORMAT_UNKNOWN; "empty")]
+    #[test_case("dds"   => ERR_FORMAT_NO_WRITE; "read-only")]
+    fn parses_format_err(format_str: &str) -> String {
+        parse_format(format_str).unwrap_err()
+    }
+
+    #[test_case("0"     => 0.0; "zero")]
+    #[test_case("0.25"  => 0.25; "fraction")]
+    #[test_case("1"     => 1.0; "one")]
+    fn parses_unit(unit_str: &str) -> f64 {
+        parse_unit(unit_str).unwrap()
+    }
+
+    #[test_case("1.5"   => ERR_UNIT_RANGE; "above")]
+    #[test_case("-0.1"  => ERR_UNIT_RANGE; "below")]
+    fn parses_unit_err(unit_str: &str) -> String {
+        parse_unit(unit_str).unwrap_err()
+    }
+
+    #[test_case("8.5"   => ERR_ENTROPY_RANGE; "above")]
+    #[test_case("-1"    => ERR_ENTROPY_RANGE; "below")]
+    fn parses_entropy_err(entropy_str: &str) -> String {
+        parse_entropy(entropy_str).unwrap_err()
+    }
+
+    #[test_case("30"    => Ok(30.0); "integer")]
+    #[test_case("32.5"  => Ok(32.5); "fraction")]
+    #[test_case("0"     => Err(ERR_DECIBELS_RANGE.to_owned()); "zero")]
+    #[test_case("inf"   => Err(ERR_DECIBELS_RANGE.to_owned()); "infinite")]
+    fn parses_decibels(decibels_str: &str) -> Result<f64, String> {
+        parse_decibels(decibels_str)
+    }
+
+    #[test_case("1,2,1,0"   => [1.0, 2.0, 1.0, 0.0]; "ignore-alpha")]
+    #[test_case("0.5, 1, 1, 1" => [0.5, 1.0, 1.0, 1.0]; "spaced")]
+    fn parses_weights(weights_str: &str) -> [f64; 4] {
+        parse_weights(weights_str).unwrap()
+    }
+
+    #[test_case("1,2,1"     => ERR_WEIGHTS_COUNT; "three")]
+    #[test_case("1,2,1,1,1" => ERR_WEIGHTS_COUNT; "five")]
+    #[test_case("1,-2,1,1"  => ERR_WEIGHTS_RANGE; "negative")]
+    #[test_case("0,0,0,0"   => ERR_WEIGHTS_RANGE; "zero")]
+    #[test_case("1,a,1,1"   => ERR_NAN; "nan")]
+    fn parses_weights_err(weights_str: &str) -> String {
+        parse_weights(weights_str).unwrap_err()
+    }
+
+    #[test_case("0"   => ERR_NOT_POWER_OF_TWO; "zero")]
+    #[test_case("96"  => ERR_NOT_POWER_OF_TWO; "not-power")]
+    #[test_case("-64" => ERR_NAN; "negative")]
+    fn parses_cell_size_err(size_str: &str) -> String {
+        parse_cell_size(size_str).unwrap_err()
+    }
+
     #[test_case("£€1@4$%"   => ERR_NAN; "nan-a")]
     #[test_case("a-a"       => ERR_NAN; "nan-b")]
     #[test_case("42"        => ERR_NOT_VEC2; "err-not-vec2-a")]
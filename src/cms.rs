@@ -0,0 +1,226 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::{DynamicImage, ImageBuffer, Rgba};
+use log::{debug, warn};
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+use crate::args::IOArgs;
+
+/// names of the built-in profiles
+pub(crate) const PROFILE_NAMES: [&str; 5] =
+    ["srgb", "display-p3", "adobe-rgb", "prophoto", "bt2020"];
+
+/// returns one of the built-in profiles by name, see `PROFILE_NAMES`
+pub(crate) fn named_profile(name: &str) -> Option<ColorProfile> {
+    match name.to_ascii_lowercase().as_str() {
+        "srgb" => Some(ColorProfile::new_srgb()),
+        "display-p3" | "p3" => Some(ColorProfile::new_display_p3()),
+        "adobe-rgb" => Some(ColorProfile::new_adobe_rgb()),
+        "prophoto" => Some(ColorProfile::new_pro_photo_rgb()),
+        "bt2020" => Some(ColorProfile::new_bt2020()),
+        _ => None,
+    }
+}
+
+/// if the two profiles are the same and a conversion would do nothing
+fn same_profile(a: &ColorProfile, b: &ColorProfile) -> bool {
+    matches!((a.encode(), b.encode()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// converts every pixel of the image from one profile to the other, keeping the bit depth
+pub(crate) fn convert_image(
+    img: &DynamicImage,
+    from: &ColorProfile,
+    to: &ColorProfile,
+) -> Result<DynamicImage, CmsError> {
+    let opts = TransformOptions::default();
+    let (w, h) = (img.width(), img.height());
+    Ok(match img {
+        DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_)
+        | DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_) => {
+            let src = img.to_rgba16();
+            let mut dst = vec![0; src.len()];
+            from.create_transform_16bit(Layout::Rgba, to, Layout::Rgba, opts)?
+                .transform(&src, &mut dst)?;
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(w, h, dst).unwrap())
+        }
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            let src = img.to_rgba32f();
+            let mut dst = vec![0.0; src.len()];
+            from.create_transform_f32(Layout::Rgba, to, Layout::Rgba, opts)?
+                .transform(&src, &mut dst)?;
+            DynamicImage::ImageRgba32F(ImageBuffer::from_raw(w, h, dst).unwrap())
+        }
+        _ => {
+            let src = img.to_rgba8();
+            let mut dst = vec![0; src.len()];
+            from.create_transform_8bit(Layout::Rgba, to, Layout::Rgba, opts)?
+                .transform(&src, &mut dst)?;
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, dst).unwrap())
+        }
+    })
+}
+
+/// converts a single color from one profile to the other
+pub(crate) fn convert_color(
+    color: &Rgba<u8>,
+    from: &ColorProfile,
+    to: &ColorProfile,
+) -> Result<Rgba<u8>, CmsError> {
+    let mut dst = [0; 4];
+    from.create_transform_8bit(Layout::Rgba, to, Layout::Rgba, TransformOptions::default())?
+        .transform(&color.0, &mut dst)?;
+    Ok(Rgba(dst))
+}
+
+/// converts the image from its embedded profile, or sRGB if untagged, to the working space.
+/// Images with a non RGB profile are left untouched.
+pub(crate) fn to_working_space(
+    img: DynamicImage,
+    icc: &Option<Vec<u8>>,
+    working: &Option<ColorProfile>,
+) -> DynamicImage {
+    let Some(working) = working else {
+        return img;
+    };
+    let source = match icc.as_deref().map(ColorProfile::new_from_slice) {
+        Some(Ok(p)) if p.color_space == DataColorSpace::Rgb => p,
+        Some(Ok(_)) => {
+            warn!("embedded color profile is not RGB and will be ignored");
+            return img;
+        }
+        Some(Err(e)) => {
+            warn!("embedded color profile is invalid and will be ignored: {e}");
+            return img;
+        }
+        None => ColorProfile::new_srgb(),
+    };
+    if same_profile(&source, working) {
+        return img;
+    }
+    debug!("converting image to the working space");
+    match convert_image(&img, &source, working) {
+        Ok(converted) => converted,
+        Err(e) => {
+            warn!("cannot convert image to the working space: {e}");
+            img
+        }
+    }
+}
+
+/// converts the image from the working space to the output profile, if both are specified
+pub(crate) fn to_output_space(img: DynamicImage, io: &IOArgs) -> DynamicImage {
+    let (Some(working), Some(output)) = (&io.working_space, &io.output_profile) else {
+        return img;
+    };
+    if same_profile(working, output) {
+        return img;
+    }
+    debug!("converting image to the output profile");
+    match convert_image(&img, working, output) {
+        Ok(converted) => converted,
+        Err(e) => {
+            warn!("cannot convert image to the output profile: {e}");
+            img
+        }
+    }
+}
+
+/// ICC profile the output has to be tagged with, the output profile or else the working space
+pub(crate) fn output_icc(io: &IOArgs) -> Option<Vec<u8>> {
+    let profile = io.output_profile.as_ref().or(io.working_space.as_ref())?;
+    match profile.encode() {
+        Ok(icc) => Some(icc),
+        Err(e) => {
+            warn!("cannot encode the output color profile: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    pub use test_case::test_case;
+
+    #[test_case("srgb"; "srgb")]
+    #[test_case("Display-P3"; "p3")]
+    #[test_case("adobe-rgb"; "adobe")]
+    #[test_case("prophoto"; "prophoto")]
+    #[test_case("bt2020"; "bt2020")]
+    fn finds_named_profile(name: &str) {
+        assert!(named_profile(name).is_some())
+    }
+
+    #[test]
+    fn finds_all_named_profiles() {
+        assert!(PROFILE_NAMES.iter().all(|n| named_profile(n).is_some()))
+    }
+
+    #[test]
+    fn compares_profiles() {
+        assert!(same_profile(
+            &ColorProfile::new_srgb(),
+            &ColorProfile::new_srgb()
+        ));
+        assert!(!same_profile(
+            &ColorProfile::new_srgb(),
+            &ColorProfile::new_display_p3()
+        ));
+    }
+
+    #[test]
+    fn converts_wide_gamut_red() {
+        // the most saturated P3 red is out of the sRGB gamut
+        let c = convert_color(
+            &Rgba([255, 0, 0, 255]),
+            &ColorProfile::new_display_p3(),
+            &ColorProfile::new_srgb(),
+        )
+        .unwrap();
+        assert_eq!(c[0], 255);
+        assert!(c[1] < 8 && c[2] < 8);
+        assert_eq!(c[3], 255);
+    }
+
+    #[test_case(Rgba([200, 100, 50, 255]); "orange")]
+    #[test_case(Rgba([10, 20, 30, 128]); "dark")]
+    fn roundtrips_color(color: Rgba<u8>) {
+        let srgb = ColorProfile::new_srgb();
+        let p3 = ColorProfile::new_display_p3();
+        let back = convert_color(&convert_color(&color, &srgb, &p3).unwrap(), &p3, &srgb).unwrap();
+        assert!((0..4).all(|i| color[i].abs_diff(back[i]) <= 2));
+    }
+
+    #[test]
+    fn converts_keeping_depth() {
+        let img = DynamicImage::new_rgba16(4, 4);
+        let out = convert_image(
+            &img,
+            &ColorProfile::new_srgb(),
+            &ColorProfile::new_display_p3(),
+        )
+        .unwrap();
+        assert_eq!(out.color(), image::ColorType::Rgba16);
+    }
+
+    #[test]
+    fn ignores_untagged_without_working_space() {
+        let img = DynamicImage::new_rgba8(2, 2);
+        assert_eq!(to_working_space(img.clone(), &None, &None), img);
+    }
+}
@@ -0,0 +1,399 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::io::{Error, ErrorKind, Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use image::Rgba;
+use serde::Serialize;
+
+use crate::linear::LinearQuadTree;
+use crate::query::Direction;
+use crate::utils::{Quad, QuadMap, QuadStructure, Vec2};
+
+const MAGIC: &[u8; 4] = b"QOMQ";
+const VERSION: u8 = 1;
+/// the records after the header are compressed with zlib
+const FLAG_COMPRESSED: u8 = 0x1;
+/// magic, version, flags, width, height and number of leaves
+const HEADER_LEN: usize = 4 + 1 + 1 + 4 + 4 + 4;
+/// x, y, width, height, depth and RGBA color
+const RECORD_LEN: usize = 4 * 4 + 1 + 4;
+
+const ERR_NOT_ENCODED: &str = "not a file written by encode";
+const ERR_VERSION: &str = "unsupported version of the encoding";
+const ERR_TRUNCATED: &str = "truncated leaves";
+const ERR_OUT_OF_BOUNDS: &str = "leaf outside of the image";
+const ERR_TOO_LARGE: &str = "image too large to decode";
+const ERR_NO_LEAVES: &str = "no leaves";
+const ERR_NOT_TILED: &str = "leaves don't tile the image";
+
+/// writes the size of the image and the leaves in Z-order, little endian.
+/// Leaves without a color are written transparent
+pub(crate) fn encode(structure: &QuadStructure, compress: bool) -> Vec<u8> {
+    let size = structure.sizes[0];
+    let mut records = Vec::with_capacity(structure.map.len() * RECORD_LEN);
+    for (pos, quad) in LinearQuadTree::from(structure).iter() {
+        let quad_size = structure.size_of(quad);
+        for v in [pos.x, pos.y, quad_size.x, quad_size.y] {
+            records.extend(v.to_le_bytes());
+        }
+        records.push(quad.depth);
+        records.extend(quad.color.unwrap_or(Rgba([0; 4])).0);
+    }
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + records.len());
+    buf.extend(MAGIC);
+    buf.push(VERSION);
+    buf.push(if compress { FLAG_COMPRESSED } else { 0 });
+    for v in [size.x, size.y, structure.map.len() as u32] {
+        buf.extend(v.to_le_bytes());
+    }
+    if compress {
+        let mut z = ZlibEncoder::new(&mut buf, Compression::best());
+        z.write_all(&records).and_then(|_| z.finish()).unwrap();
+    } else {
+        buf.extend(records);
+    }
+    buf
+}
+
+/// reads the leaves written by `encode`
+pub(crate) fn decode(buf: &[u8]) -> Result<QuadStructure, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg);
+    if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+        return Err(invalid(ERR_NOT_ENCODED));
+    }
+    if buf[4] != VERSION {
+        return Err(invalid(ERR_VERSION));
+    }
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+    let size = Vec2 {
+        x: u32_at(buf, 6),
+        y: u32_at(buf, 10),
+    };
+    let count = u32_at(buf, 14) as u64;
+    let area = size.x as u64 * size.y as u64;
+    // the header can't be trusted with the allocation of the image
+    let limits = image::Limits::default();
+    if limits.check_dimensions(size.x, size.y).is_err()
+        || limits.max_alloc.is_some_and(|max| area * 4 > max)
+    {
+        return Err(invalid(ERR_TOO_LARGE));
+    }
+    // every leaf covers at least a pixel
+    if count == 0 || area == 0 {
+        return Err(invalid(ERR_NO_LEAVES));
+    }
+    if count > area {
+        return Err(invalid(ERR_NOT_TILED));
+    }
+
+    let len = count * RECORD_LEN as u64;
+    let mut records = Vec::new();
+    match buf[5] & FLAG_COMPRESSED {
+        0 => (&buf[HEADER_LEN..]).take(len).read_to_end(&mut records)?,
+        _ => ZlibDecoder::new(&buf[HEADER_LEN..])
+            .take(len)
+            .read_to_end(&mut records)?,
+    };
+    if records.len() as u64 != len {
+        return Err(invalid(ERR_TRUNCATED));
+    }
+    let count = count as usize;
+
+    let mut map = QuadMap::with_capacity(count);
+    let mut sizes = vec![size];
+    // pixels covered by a leaf, one bit each, as no pixel can be covered twice
+    let mut covered = vec![0u64; area.div_ceil(64) as usize];
+    let mut covered_area = 0;
+    for r in records.chunks_exact(RECORD_LEN) {
+        let pos = Vec2 {
+            x: u32_at(r, 0),
+            y: u32_at(r, 4),
+        };
+        let quad_size = Vec2 {
+            x: u32_at(r, 8),
+            y: u32_at(r, 12),
+        };
+        if pos.x as u64 + quad_size.x as u64 > size.x as u64
+            || pos.y as u64 + quad_size.y as u64 > size.y as u64
+        {
+            return Err(invalid(ERR_OUT_OF_BOUNDS));
+        }
+        for y in pos.y..pos.y + quad_size.y {
+            for x in pos.x..pos.x + quad_size.x {
+                let i = y as usize * size.x as usize + x as usize;
+                let (word, bit) = (i / 64, 1 << (i % 64));
+                if covered[word] & bit != 0 {
+                    return Err(invalid(ERR_NOT_TILED));
+                }
+                covered[word] |= bit;
+            }
+        }
+        covered_area += quad_size.x as u64 * quad_size.y as u64;
+        let depth = r[16];
+        // the sizes of the depths are halved as in the subdivision
+        while sizes.len() <= depth as usize {
+            sizes.push(sizes.last().unwrap().half().0);
+        }
+        let quad = Quad {
+            depth,
+            color: Some(Rgba(r[17..21].try_into().unwrap())),
+            size: (quad_size != sizes[depth as usize]).then_some(quad_size),
+        };
+        if map.insert(pos, quad).is_some() {
+            return Err(invalid(ERR_NOT_TILED));
+        }
+    }
+    // without overlaps, leaves as big as the image leave no gaps
+    if covered_area != area {
+        return Err(invalid(ERR_NOT_TILED));
+    }
+    Ok(QuadStructure { map, sizes })
+}
+
+/// the size of the image and its leaves, as exported to JSON
+#[derive(Serialize)]
+struct JsonExport {
+    width: u32,
+    height: u32,
+    leaves: Vec<JsonLeaf>,
+}
+
+#[derive(Serialize)]
+struct JsonLeaf {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    depth: u8,
+    color: Option<[u8; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    neighbors: Option<JsonNeighbors>,
+}
+
+/// positions of the leaves touching each side
+#[derive(Serialize)]
+struct JsonNeighbors {
+    up: Vec<[u32; 2]>,
+    down: Vec<[u32; 2]>,
+    left: Vec<[u32; 2]>,
+    right: Vec<[u32; 2]>,
+}
+
+/// the size of the image and the leaves in Z-order as a JSON object, on a single line.
+/// Only the leaf containing the pixel `at` is kept if given, none if it's outside the image
+pub(crate) fn to_json(structure: &QuadStructure, at: Option<&Vec2>, neighbors: bool) -> String {
+    let size = structure.sizes[0];
+    let index = (at.is_some() || neighbors).then(|| structure.index());
+    let kept = at.map(|at| index.as_ref().unwrap().leaf_at(at));
+    let leaves = LinearQuadTree::from(structure)
+        .iter()
+        .filter(|(pos, _)| kept.is_none_or(|leaf| leaf == Some(*pos)))
+        .map(|(pos, quad)| {
+            let quad_size = structure.size_of(quad);
+            let neighbors = index.as_ref().filter(|_| neighbors).map(|index| {
+                let side = |direction| {
+                    let found = index.neighbors(&pos, &quad_size, direction);
+                    found.iter().map(|p| [p.x, p.y]).collect()
+                };
+                JsonNeighbors {
+                    up: side(Direction::Up),
+                    down: side(Direction::Down),
+                    left: side(Direction::Left),
+                    right: side(Direction::Right),
+                }
+            });
+            JsonLeaf {
+                x: pos.x,
+                y: pos.y,
+                width: quad_size.x,
+                height: quad_size.y,
+                depth: quad.depth,
+                color: quad.color.map(|c| c.0),
+                neighbors,
+            }
+        })
+        .collect();
+    let export = JsonExport {
+        width: size.x,
+        height: size.y,
+        leaves,
+    };
+    serde_json::to_string(&export).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quad::{calc_quads, Aggregation, Guides};
+    use image::RgbaImage;
+    pub use test_case::test_case;
+
+    /// a 2x2 image cut vertically, the right half cut once more
+    fn structure() -> QuadStructure {
+        let quad = |depth, color, x, y| Quad {
+            depth,
+            color: Some(Rgba(color)),
+            size: Some(Vec2 { x, y }),
+        };
+        QuadStructure {
+            map: QuadMap::from([
+                (Vec2 { x: 0, y: 0 }, quad(1, [255, 0, 0, 255], 1, 2)),
+                (Vec2 { x: 1, y: 0 }, quad(2, [0, 0, 0, 255], 1, 1)),
+                (Vec2 { x: 1, y: 1 }, quad(2, [9, 9, 9, 255], 1, 1)),
+            ]),
+            sizes: vec![Vec2 { x: 2, y: 2 }, Vec2 { x: 1, y: 1 }, Vec2::ZERO],
+        }
+    }
+
+    #[test_case(true; "compressed")]
+    #[test_case(false; "uncompressed")]
+    fn decodes_encoded(compress: bool) {
+        // odd sizes leave quads of sizes other than the halved ones
+        let img = RgbaImage::from_fn(9, 13, |x, y| Rgba([(x * 28) as u8, (y * 19) as u8, 0, 255]));
+        let structure = QuadStructure::from(calc_quads(
+            &img,
+            &Vec2 { x: 1, y: 1 },
+            0,
+            &Rgba([0, 0, 0, 0]),
+            true,
+            Aggregation::Mean,
+            &Guides::default(),
+        ));
+        let decoded = decode(&encode(&structure, compress)).unwrap();
+        assert_eq!(decoded.sizes[0], structure.sizes[0]);
+        assert_eq!(decoded.map, structure.map);
+    }
+
+    #[test]
+    fn encodes_uncompressed() {
+        let buf = encode(&structure(), false);
+        assert_eq!(buf.len(), HEADER_LEN + 3 * RECORD_LEN);
+        assert_eq!(&buf[..6], b"QOMQ\x01\x00");
+        // the first leaf in Z-order is the one at the origin
+        assert_eq!(&buf[HEADER_LEN..HEADER_LEN + 8], &[0; 8]);
+    }
+
+    #[test_case(b"QOMQ" => ERR_NOT_ENCODED; "short")]
+    #[test_case(b"\x89PNG\x0d\x0a\x1a\x0a00000000000000" => ERR_NOT_ENCODED; "png")]
+    #[test_case(b"QOMQ\x02\x00000000000000" => ERR_VERSION; "version")]
+    #[test_case(b"QOMQ\x01\x00\x01\0\0\0\x01\0\0\0\x01\0\0\0" => ERR_TRUNCATED; "truncated")]
+    fn decodes_err(buf: &[u8]) -> String {
+        decode(buf).err().unwrap().to_string()
+    }
+
+    /// an encoded header of an image of the given size
+    fn header(w: u32, h: u32, count: u32, flags: u8) -> Vec<u8> {
+        let mut buf = b"QOMQ\x01".to_vec();
+        buf.push(flags);
+        for v in [w, h, count] {
+            buf.extend(v.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test_case(header(65535, 65535, 1, 0) => ERR_TOO_LARGE; "too-large")]
+    #[test_case(header(2, 2, 0, 0) => ERR_NO_LEAVES; "no-leaves")]
+    #[test_case(header(0, 2, 1, 0) => ERR_NO_LEAVES; "empty-image")]
+    #[test_case(header(1, 1, 2, 0) => ERR_NOT_TILED; "more-leaves-than-pixels")]
+    fn decodes_header_err(buf: Vec<u8>) -> String {
+        decode(&buf).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn decodes_not_tiled() {
+        let mut buf = encode(&structure(), false);
+        // the last leaf is left out
+        buf[14] = 2;
+        buf.truncate(HEADER_LEN + 2 * RECORD_LEN);
+        assert_eq!(decode(&buf).err().unwrap().to_string(), ERR_NOT_TILED);
+        // the last leaf overlaps the first one
+        let mut buf = encode(&structure(), false);
+        buf[HEADER_LEN + 2 * RECORD_LEN] = 0;
+        buf[HEADER_LEN + 2 * RECORD_LEN + 4] = 0;
+        assert_eq!(decode(&buf).err().unwrap().to_string(), ERR_NOT_TILED);
+        // as big as the image, but (1,0) is covered twice and (1,1) never
+        let mut buf = header(2, 2, 3, 0);
+        for (x, y, w, h) in [(0, 0, 2, 1), (1, 0, 1, 1), (0, 1, 1, 1)] {
+            let mut record = vec![0; RECORD_LEN];
+            for (at, v) in [(0, x), (4, y), (8, w), (12, h)] {
+                record[at..at + 4].copy_from_slice(&u32::to_le_bytes(v));
+            }
+            buf.extend(record);
+        }
+        assert_eq!(decode(&buf).err().unwrap().to_string(), ERR_NOT_TILED);
+    }
+
+    #[test]
+    fn decodes_limited_zlib() {
+        // a stream much longer than the records of the header, only those are inflated
+        let mut buf = header(1, 1, 1, FLAG_COMPRESSED);
+        let mut z = ZlibEncoder::new(&mut buf, Compression::best());
+        let mut record = vec![0; RECORD_LEN];
+        record[8] = 1;
+        record[12] = 1;
+        z.write_all(&record).unwrap();
+        z.write_all(&vec![0xff; 1 << 24]).unwrap();
+        z.finish().unwrap();
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.map.len(), 1);
+    }
+
+    #[test]
+    fn decodes_out_of_bounds() {
+        let mut buf = encode(&structure(), false);
+        // a 1x4 image of as many pixels
+        buf[6] = 1;
+        buf[10] = 4;
+        assert_eq!(decode(&buf).err().unwrap().to_string(), ERR_OUT_OF_BOUNDS);
+    }
+
+    #[test]
+    fn exports_json() {
+        assert_eq!(
+            to_json(&structure(), None, false),
+            concat!(
+                r#"{"width":2,"height":2,"leaves":["#,
+                r#"{"x":0,"y":0,"width":1,"height":2,"depth":1,"color":[255,0,0,255]},"#,
+                r#"{"x":1,"y":0,"width":1,"height":1,"depth":2,"color":[0,0,0,255]},"#,
+                r#"{"x":1,"y":1,"width":1,"height":1,"depth":2,"color":[9,9,9,255]}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test_case(Vec2 { x: 1, y: 1 }, r#"[{"x":1,"y":1,"width":1,"height":1,"depth":2,"color":[9,9,9,255]}]"#; "leaf")]
+    #[test_case(Vec2 { x: 2, y: 0 }, "[]"; "outside")]
+    fn exports_json_at(at: Vec2, leaves: &str) {
+        assert_eq!(
+            to_json(&structure(), Some(&at), false),
+            format!(r#"{{"width":2,"height":2,"leaves":{}}}"#, leaves) + "\n"
+        );
+    }
+
+    #[test]
+    fn exports_json_neighbors() {
+        assert_eq!(
+            to_json(&structure(), Some(&Vec2 { x: 1, y: 0 }), true),
+            concat!(
+                r#"{"width":2,"height":2,"leaves":["#,
+                r#"{"x":1,"y":0,"width":1,"height":1,"depth":2,"color":[0,0,0,255],"#,
+                r#""neighbors":{"up":[],"down":[[1,1]],"left":[[0,0]],"right":[]}}]}"#,
+                "\n"
+            )
+        );
+    }
+}
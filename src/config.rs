@@ -0,0 +1,456 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, Parser, ValueEnum};
+use toml::{Table, Value};
+
+use crate::args::CliArgs;
+
+const ARG_CONFIG: &str = "config";
+const COMMAND_RENDER: &str = "render";
+/// prefix of the arguments turning off a flag set by the config file or the preset
+const UNSET_PREFIX: &str = "--no-";
+/// arguments showing the help or version instead of running a command
+const HELP_ARGS: [&str; 5] = ["help", "-h", "--help", "-V", "--version"];
+const ARG_PRESET: &str = "preset";
+
+const ERR_CONFIG_FORMAT: &str = "config file must be TOML or YAML";
+#[cfg(not(feature = "yaml"))]
+const ERR_YAML_FEATURE: &str = "YAML config files require the `yaml` feature";
+
+/// named sets of options, overridden by the config file and the command line
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum Preset {
+    /// Black outlines of the quads on a white background
+    Outline,
+    /// Quads filled with a palette of 8 colors
+    Poster,
+    /// Small quads filled with their color and outlined in black
+    Mosaic,
+    /// White outlines on black down to the deepest quads, with stats and debug logs
+    DebugDepth,
+}
+
+impl Preset {
+    /// options of the preset, written as a TOML config file
+    fn options(&self) -> &'static str {
+        match self {
+            Preset::Outline => {
+                r#"
+                no-drawover = true
+                background = "white"
+                color = "black"
+                "#
+            }
+            Preset::Poster => {
+                r##"
+                fill = true
+                palette-size = 8
+                threshold = "#202020"
+                "##
+            }
+            Preset::Mosaic => {
+                r##"
+                fill = true
+                color = "black"
+                min-quad-size = "8,8"
+                threshold = "#101010"
+                "##
+            }
+            Preset::DebugDepth => {
+                r#"
+                no-drawover = true
+                background = "black"
+                color = "white"
+                min-depth = 0
+                stats = "human"
+                verbose = 2
+                "#
+            }
+        }
+    }
+}
+
+/// parses the command line over the options of the config file, which are over the ones of the preset
+pub(super) fn parse_args() -> CliArgs {
+    layered_args(std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+fn layered_args<I, T>(args: I) -> Result<CliArgs, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut cmd = CliArgs::command();
+    cmd.build();
+    // the media are rendered when no command is given, as before there were commands
+    let first = args
+        .get(command_index(&cmd, &args))
+        .and_then(|a| a.to_str());
+    if !first.is_some_and(|a| cmd.find_subcommand(a).is_some() || HELP_ARGS.contains(&a)) {
+        args.insert(args.len().min(1), COMMAND_RENDER.into());
+    }
+    let command = args
+        .get(command_index(&cmd, &args))
+        .and_then(|a| a.to_str());
+    let unset = match command.map(str::to_owned) {
+        Some(command) => take_unset(&cmd, &command, &mut args),
+        None => Vec::new(),
+    };
+
+    // the other options can be missing from the command line, they are checked afterwards
+    let cli = cmd
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+    let Some((command, cli)) = cli.subcommand() else {
+        return CliArgs::try_parse_from(args);
+    };
+    let invalid = |e: String| cmd.clone().error(ErrorKind::InvalidValue, e);
+
+    let config = match cli.get_one::<PathBuf>(ARG_CONFIG) {
+        Some(path) => read_config(path).map_err(invalid)?,
+        None => Table::new(),
+    };
+    let preset = match (cli.get_one::<Preset>(ARG_PRESET), config.get(ARG_PRESET)) {
+        (Some(p), _) => Some(*p),
+        (None, Some(Value::String(s))) => Some(Preset::from_str(s, true).map_err(invalid)?),
+        _ => None,
+    };
+    let mut options = match preset {
+        Some(p) => parse_config(p.options(), "toml").map_err(invalid)?,
+        None => Table::new(),
+    };
+    options.extend(config);
+
+    for id in unset {
+        options.remove(&id);
+    }
+    let layered = to_args(&cmd, command, &options, cli).map_err(invalid)?;
+    args.extend(layered);
+    CliArgs::try_parse_from(args)
+}
+
+/// removes the `--no-<flag>` arguments turning off the flags of the command, returning their ids
+fn take_unset(cmd: &Command, command: &str, args: &mut Vec<OsString>) -> Vec<String> {
+    let Some(sub) = cmd.find_subcommand(command) else {
+        return Vec::new();
+    };
+    let flag = |arg: &OsString| {
+        let long = arg.to_str()?.strip_prefix(UNSET_PREFIX)?;
+        sub.get_arguments()
+            .find(|a| a.get_long() == Some(long) && matches!(a.get_action(), ArgAction::SetTrue))
+            .map(|a| a.get_id().to_string())
+    };
+    let unset = args.iter().filter_map(flag).collect();
+    args.retain(|a| flag(a).is_none());
+    unset
+}
+
+/// index of the command, after the options common to all of them
+fn command_index(cmd: &Command, args: &[OsString]) -> usize {
+    let mut i = 1;
+    while let Some(arg) = args.get(i).and_then(|a| a.to_str()) {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (arg, false),
+        };
+        let global = cmd.get_arguments().find(|a| {
+            let long = name
+                .strip_prefix("--")
+                .is_some_and(|l| a.get_long() == Some(l));
+            // repeated short flags, e.g.: `-vvv`
+            let short = name
+                .strip_prefix('-')
+                .is_some_and(|s| !s.is_empty() && s.chars().all(|c| a.get_short() == Some(c)));
+            a.is_global_set() && (long || short)
+        });
+        match global {
+            Some(a) if a.get_action().takes_values() && !value => i += 2,
+            Some(_) => i += 1,
+            None => break,
+        }
+    }
+    i
+}
+
+/// reads the options of a TOML or YAML config file
+fn read_config(path: &Path) -> Result<Table, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read config file '{}': {e}", path.display()))?;
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    parse_config(&text, &ext)
+}
+
+/// parses the options of a config file with the given extension, keys are the ids of the arguments
+fn parse_config(text: &str, ext: &str) -> Result<Table, String> {
+    let table: Table = match ext {
+        "toml" => toml::from_str(text).map_err(|e| e.to_string())?,
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => serde_norway::from_str(text).map_err(|e| e.to_string())?,
+        #[cfg(not(feature = "yaml"))]
+        "yaml" | "yml" => return Err(ERR_YAML_FEATURE.to_owned()),
+        _ => return Err(ERR_CONFIG_FORMAT.to_owned()),
+    };
+    Ok(table
+        .into_iter()
+        .map(|(k, v)| (k.replace('-', "_"), v))
+        .collect())
+}
+
+/// turns the options of the command into arguments, leaving out the ones given in the command line.
+/// Options of the other commands are ignored
+fn to_args(
+    cmd: &Command,
+    command: &str,
+    options: &Table,
+    cli: &ArgMatches,
+) -> Result<Vec<OsString>, String> {
+    let long_of = |c: &Command, id: &str| {
+        c.get_arguments()
+            .filter(|a| a.get_id() == id && id != ARG_CONFIG)
+            .find_map(|a| a.get_long().map(|l| (a.get_action().clone(), l.to_owned())))
+    };
+    let mut args = Vec::new();
+    for (id, value) in options {
+        let Some((action, long)) = cmd.find_subcommand(command).and_then(|c| long_of(c, id)) else {
+            match cmd.get_subcommands().any(|c| long_of(c, id).is_some()) {
+                true => continue,
+                false => return Err(format!("unknown option `{id}` in config file")),
+            }
+        };
+        if given(cmd.find_subcommand(command).unwrap(), id, cli) {
+            continue;
+        }
+        let invalid = || format!("invalid value for `{long}` in config file: {value}");
+        match (action, value) {
+            (ArgAction::SetTrue, Value::Boolean(set)) => {
+                args.extend(set.then(|| format!("--{long}").into()))
+            }
+            (ArgAction::Count, Value::Integer(n)) if *n >= 0 => {
+                args.extend((0..*n).map(|_| format!("--{long}").into()))
+            }
+            (ArgAction::Set | ArgAction::Append, v) => args.extend(
+                values(v)
+                    .ok_or_else(invalid)?
+                    .into_iter()
+                    .map(|v| format!("--{long}={v}").into()),
+            ),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(args)
+}
+
+/// if the argument, or one it can't be used with, is given in the command line
+fn given(cmd: &Command, id: &str, cli: &ArgMatches) -> bool {
+    let on_command_line = |id: &str| cli.value_source(id) == Some(ValueSource::CommandLine);
+    let Some(arg) = cmd.get_arguments().find(|a| a.get_id() == id) else {
+        return false;
+    };
+    let conflicts = cmd.get_arg_conflicts_with(arg).into_iter().chain(
+        cmd.get_arguments()
+            .filter(|other| cmd.get_arg_conflicts_with(other).contains(&arg)),
+    );
+    // only one argument of a group that isn't multiple can be given
+    let exclusive = cmd.get_groups().filter(|g| {
+        let mut group = (*g).clone();
+        !group.is_multiple() && g.get_args().any(|a| a == arg.get_id())
+    });
+    on_command_line(id)
+        || conflicts
+            .map(|a| a.get_id().as_str())
+            .chain(exclusive.flat_map(|g| g.get_args().map(|a| a.as_str())))
+            .any(on_command_line)
+}
+
+/// values of an option as given in the command line, arrays of numbers are joined by commas
+fn values(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Some(vec![value.to_string()]),
+        Value::Array(a) if a.iter().all(Value::is_str) => Some(
+            a.iter()
+                .filter_map(|v| v.as_str().map(str::to_owned))
+                .collect(),
+        ),
+        Value::Array(a) if a.iter().all(|v| v.is_integer() || v.is_float()) => Some(vec![a
+            .iter()
+            .map(Value::to_string)
+            .collect::<Vec<_>>()
+            .join(",")]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Commands;
+    pub use test_case::test_case;
+
+    const IO: [&str; 5] = ["qom", "-i", "in.png", "-o", "out.png"];
+    const RENDER: [&str; 6] = ["qom", "render", "-i", "in.png", "-o", "out.png"];
+
+    #[test_case("min-depth = 2\nfill = true", "toml"; "toml")]
+    #[cfg_attr(feature = "yaml", test_case("min-depth: 2\nfill: true", "yaml"; "yaml"))]
+    #[cfg_attr(feature = "yaml", test_case("min_depth: 2\nfill: true", "yml"; "snake-case"))]
+    #[test_case("min_depth = 2\nfill = true", "toml"; "snake-case-toml")]
+    fn parses_config(text: &str, ext: &str) {
+        let options = parse_config(text, ext).unwrap();
+        assert_eq!(options["min_depth"], Value::Integer(2));
+        assert_eq!(options["fill"], Value::Boolean(true));
+    }
+
+    #[test]
+    fn parses_config_err() {
+        assert_eq!(parse_config("", "json").unwrap_err(), ERR_CONFIG_FORMAT);
+    }
+
+    #[cfg(not(feature = "yaml"))]
+    #[test]
+    fn parses_yaml_err() {
+        assert_eq!(
+            parse_config("fill: true", "yaml").unwrap_err(),
+            ERR_YAML_FEATURE
+        );
+    }
+
+    /// the arguments of the options of the config file for the command line
+    fn convert(text: &str, args: &[&str]) -> Result<Vec<OsString>, String> {
+        let mut cmd = CliArgs::command();
+        cmd.build();
+        let cli = cmd.clone().ignore_errors(true).get_matches_from(args);
+        let (command, cli) = cli.subcommand().unwrap();
+        to_args(&cmd, command, &parse_config(text, "toml").unwrap(), cli)
+    }
+
+    #[test_case("threshold = \"#101010\"" => vec!["--threshold=#101010"]; "string")]
+    #[test_case("min-depth = 3" => vec!["--min-depth=3"]; "integer")]
+    #[test_case("fill = true\nindexed = false" => vec!["--fill"]; "flags")]
+    #[test_case("verbose = 2" => vec!["--verbose", "--verbose"]; "count")]
+    #[test_case("min-quad-size = [4, 6]" => vec!["--min-quad-size=4,6"]; "numbers")]
+    #[test_case("palette = [\"red\", \"blue\"]" => vec!["--palette=red", "--palette=blue"]; "strings")]
+    fn converts_options(text: &str) -> Vec<OsString> {
+        convert(text, &RENDER).unwrap()
+    }
+
+    #[test_case("nope = 1"; "unknown")]
+    #[test_case("config = \"a.toml\""; "nested")]
+    #[test_case("fill = 1"; "not-flag")]
+    #[test_case("color = { r = 1 }"; "table")]
+    fn converts_options_err(text: &str) {
+        assert!(convert(text, &RENDER).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let args = [&RENDER[..], &["--min-depth", "1"]].concat();
+        let options = "min-depth = 3\nfill = true";
+        assert_eq!(convert(options, &args).unwrap(), vec!["--fill"]);
+    }
+
+    #[test_case("palette-size = 8\nfill = true", &["--palette", "red"]; "conflict")]
+    #[test_case("palette = [\"red\"]\nfill = true", &["--palette-size", "8"]; "conflicted")]
+    #[test_case("target-leaves = 8\nfill = true", &["--target-size", "800"]; "group")]
+    fn command_line_conflicts(options: &str, given: &[&str]) {
+        let args = [&RENDER[..], given].concat();
+        assert_eq!(convert(options, &args).unwrap(), vec!["--fill"]);
+    }
+
+    #[test]
+    fn unsets_flags() {
+        let args = IO.iter().chain(&["--preset", "mosaic", "--no-fill"]);
+        let Commands::Render(render) = layered_args(args).unwrap().command else {
+            panic!("not rendering");
+        };
+        assert!(!render.image.fill);
+        assert_eq!(render.image.color, Some(image::Rgba([0, 0, 0, 255])));
+    }
+
+    #[test]
+    fn ignores_other_commands() {
+        let args = ["qom", "stats", "-i", "in.png"];
+        let options = "min-depth = 3\nfill = true\ncolor = \"red\"";
+        assert_eq!(convert(options, &args).unwrap(), vec!["--min-depth=3"]);
+    }
+
+    #[test_case(&["-i", "in.png", "-o", "out.png"] => "render"; "render")]
+    #[test_case(&["render", "-i", "in.png", "-o", "out.png"] => "render"; "named-render")]
+    #[test_case(&["stats", "-i", "in.png"] => "stats"; "stats")]
+    #[test_case(&["-vv", "--preset", "poster", "stats", "-i", "in.png"] => "stats"; "after-globals")]
+    #[test_case(&["-v", "-i", "in.png", "-o", "out.png"] => "render"; "render-after-globals")]
+    #[test_case(&["-v", "-i", "stats", "-o", "out.png"] => "render"; "input-named-as-command")]
+    fn finds_command(args: &[&str]) -> &'static str {
+        match layered_args(["qom"].iter().chain(args)).unwrap().command {
+            Commands::Render(_) => "render",
+            Commands::Stats(_) => "stats",
+            _ => "other",
+        }
+    }
+
+    #[test]
+    fn finds_command_after_config() {
+        let path = std::env::temp_dir().join(format!(
+            "qom-{}-finds_command_after_config.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "min-depth = 3
+fill = true",
+        )
+        .unwrap();
+        let args = [
+            "qom",
+            "--config",
+            path.to_str().unwrap(),
+            "stats",
+            "-i",
+            "in.png",
+        ];
+        let cli = layered_args(args);
+        std::fs::remove_file(&path).unwrap();
+        let Commands::Stats(stats) = cli.unwrap().command else {
+            panic!("not measuring");
+        };
+        assert_eq!(stats.calc.min_depth, 3);
+    }
+
+    #[test]
+    fn applies_presets() {
+        for preset in Preset::value_variants() {
+            let value = preset.to_possible_value().unwrap();
+            assert!(layered_args(IO.iter().chain(&["--preset", value.get_name()])).is_ok());
+        }
+        let args = IO
+            .iter()
+            .chain(&["--preset", "poster", "--palette-size", "4"]);
+        let Commands::Render(render) = layered_args(args).unwrap().command else {
+            panic!("not rendering");
+        };
+        assert!(render.image.fill);
+        assert_eq!(render.image.palette_size, Some(4));
+    }
+}
@@ -14,27 +14,30 @@
  */
 use std::collections::HashMap;
 
+use crate::linear::LinearQuadTree;
 use crate::quad::*;
+use crate::shapes::{Shape, ShapeStructure};
 use crate::utils::*;
 use image::*;
+use num_traits::{NumCast, ToPrimitive};
 
 pub type ImageCache = HashMap<Vec2, DynamicImage>;
 
 //TODO unit test
 /// create a copy of the original and draw quads outlines on it
-pub fn draw_quads_squares(
-    original: &DynamicImage,
-    quads: &QuadStructure,
-    color: &Option<Rgba<u8>>,
-) -> DynamicImage {
+pub fn draw_quads_squares<I, P>(original: &I, quads: &QuadStructure<P>, color: &Option<P>) -> I
+where
+    I: GenericImage<Pixel = P> + Clone,
+    P: RgbaPixel,
+{
     let mut copy_img = original.clone();
 
-    for (pos, info) in quads.map.iter() {
+    for (pos, info) in LinearQuadTree::from(quads).iter() {
         draw_square(
             &mut copy_img,
-            pos,
-            &quads.sizes[info.depth as usize],
-            &color.unwrap_or(info.color.unwrap_or(DEFAULT_COLOR)),
+            &pos,
+            &quads.size_of(info),
+            &color.unwrap_or(info.color.unwrap_or(scale_color(&DEFAULT_COLOR))),
             &None,
         );
     }
@@ -43,33 +46,29 @@ pub fn draw_quads_squares(
 
 //TODO unit test
 /// Draws quads based on the specified image and with the given args only if the color satisfies the filter
-pub fn draw_quads(
-    structure: &QuadStructure,
-    border_color: &Option<Rgba<u8>>,
-    background_color: &Option<Rgba<u8>>,
+pub fn draw_quads<P: RgbaPixel>(
+    structure: &QuadStructure<P>,
+    border_color: &Option<P>,
+    background_color: &Option<P>,
     multiply: bool,
     quad_img: &Option<DynamicImage>,
     cache: &mut ImageCache,
-) -> DynamicImage {
+) -> PixelBuffer<P> {
     let img_size = structure.sizes[0];
 
-    let mut img_out = DynamicImage::ImageRgba8(match background_color {
-        Some(bgrc) => RgbaImage::from_pixel(img_size.x, img_size.y, *bgrc),
-        None => RgbaImage::new(img_size.x, img_size.y), //transparent bg
-    });
-    for (pos, info) in structure.map.iter() {
-        let size_adj = adjust_quad_size(
-            pos,
-            &structure.sizes[info.depth as usize],
-            &structure.map,
-            &img_size,
-        );
+    let mut img_out = match background_color {
+        Some(bgrc) => ImageBuffer::from_pixel(img_size.x, img_size.y, *bgrc),
+        None => ImageBuffer::new(img_size.x, img_size.y), //transparent bg
+    };
+    for (pos, info) in LinearQuadTree::from(structure).iter() {
+        // the leaves tile the image exactly
+        let size_adj = structure.size_of(info);
 
         match quad_img {
             Some(qimg) => draw_image(
                 &mut img_out,
                 qimg,
-                pos,
+                &pos,
                 &size_adj,
                 border_color,
                 match multiply {
@@ -81,9 +80,9 @@ pub fn draw_quads(
             None => {
                 draw_square(
                     &mut img_out,
-                    pos,
+                    &pos,
                     &size_adj,
-                    &border_color.unwrap_or(info.color.unwrap_or(DEFAULT_COLOR)),
+                    &border_color.unwrap_or(info.color.unwrap_or(scale_color(&DEFAULT_COLOR))),
                     &info.color,
                 );
             }
@@ -92,15 +91,39 @@ pub fn draw_quads(
     img_out
 }
 
+/// draws the outlines of the shapes on the image, in the border color or their own,
+/// and fills them with their color if asked to
+pub fn draw_shapes<S: Shape, P: RgbaPixel>(
+    img: &mut PixelBuffer<P>,
+    structure: &ShapeStructure<S, P>,
+    border_color: &Option<P>,
+    fill: bool,
+) {
+    for cell in structure.cells.iter() {
+        let border = border_color.unwrap_or(cell.color.unwrap_or(scale_color(&DEFAULT_COLOR)));
+        let outside = |x: i64, y: i64| !cell.shape.contains((x as f64 + 0.5, y as f64 + 0.5));
+        for (x, y) in cell.shape.pixels(&structure.size) {
+            let (xi, yi) = (x as i64, y as i64);
+            let on_border = outside(xi - 1, yi)
+                || outside(xi + 1, yi)
+                || outside(xi, yi - 1)
+                || outside(xi, yi + 1);
+            if on_border {
+                img.put_pixel(x, y, border);
+            } else if let Some(c) = cell.color.filter(|_| fill) {
+                img.put_pixel(x, y, c);
+            }
+        }
+    }
+}
+
 //TODO unit test
 /// draw a square outline on the image
-fn draw_square(
-    img: &mut DynamicImage,
-    pos: &Vec2,
-    size: &Vec2,
-    border_color: &Rgba<u8>,
-    fill_color: &Option<Rgba<u8>>,
-) {
+fn draw_square<I, P>(img: &mut I, pos: &Vec2, size: &Vec2, border_color: &P, fill_color: &Option<P>)
+where
+    I: GenericImage<Pixel = P>,
+    P: RgbaPixel,
+{
     unsafe {
         if fill_color.is_some() {
             for y in 1..size.y {
@@ -121,25 +144,27 @@ fn draw_square(
 
 //TODO unit test
 /// overlap an image on the specified position
-fn draw_image(
-    img: &mut DynamicImage,
+fn draw_image<P: RgbaPixel>(
+    img: &mut PixelBuffer<P>,
     img_todraw: &DynamicImage,
     pos: &Vec2,
     size: &Vec2,
-    border_color: &Option<Rgba<u8>>,
-    multiply_color: &Option<Rgba<u8>>,
+    border_color: &Option<P>,
+    multiply_color: &Option<P>,
     cache: &mut ImageCache,
 ) {
-    let draw = match cache.get(size) {
-        Some(di) => di,
-        None => {
-            cache.insert(
-                *size,
-                img_todraw.resize_exact(size.x, size.y, image::imageops::FilterType::Gaussian),
-            );
-            cache.get(size).unwrap()
-        }
-    };
+    // a cached image of another pixel type can be left by a previous image
+    if cache.get(size).and_then(P::as_buffer).is_none() {
+        cache.insert(
+            *size,
+            P::into_dynamic(P::to_buffer(&img_todraw.resize_exact(
+                size.x,
+                size.y,
+                image::imageops::FilterType::Gaussian,
+            ))),
+        );
+    }
+    let draw = P::as_buffer(&cache[size]).unwrap();
     match multiply_color {
         Some(c) => img.copy_from(&multiply_image_by(draw, c), pos.x, pos.y),
         None => img.copy_from(draw, pos.x, pos.y),
@@ -150,17 +175,12 @@ fn draw_image(
     }
 }
 
-fn multiply_image_by(src: &DynamicImage, by: &Rgba<u8>) -> DynamicImage {
-    DynamicImage::ImageRgba8(
-        RgbaImage::from_raw(
-            src.width(),
-            src.height(),
-            src.pixels()
-                .flat_map(|(_, _, p)| multiply_pixels(&p, by))
-                .collect(),
-        )
-        .unwrap(),
-    )
+fn multiply_image_by<P: RgbaPixel>(src: &PixelBuffer<P>, by: &P) -> PixelBuffer<P> {
+    let mut out = src.clone();
+    for p in out.pixels_mut() {
+        *p = *P::from_slice(&multiply_pixels(p, by));
+    }
+    out
 }
 
 pub(super) fn apply_background_color(src: &DynamicImage, color: &Rgba<u8>) -> DynamicImage {
@@ -180,51 +200,16 @@ pub(super) fn apply_background_color(src: &DynamicImage, color: &Rgba<u8>) -> Dy
     )
 }
 
-fn multiply_pixels(a: &Rgba<u8>, b: &Rgba<u8>) -> [u8; 4] {
-    [
-        ((a[0] as u16) * (b[0] as u16) / 255) as u8,
-        ((a[1] as u16) * (b[1] as u16) / 255) as u8,
-        ((a[2] as u16) * (b[2] as u16) / 255) as u8,
-        ((a[3] as u16) * (b[3] as u16) / 255) as u8,
-    ]
-}
-
-/// Increase a quads' size by 1 if there's not a quad next to it;
-/// this check avoids empty line artifacts caused by the modulo
-/// while halfing odd numbers in the quad size
-fn adjust_quad_size(pos: &Vec2, size: &Vec2, quadinf_map: &QuadMap, bounds: &Vec2) -> Vec2 {
-    Vec2 {
-        // find right
-        x: if (pos.x + size.x) < bounds.x {
-            match quadinf_map.get(&Vec2 {
-                x: pos.x + size.x,
-                y: pos.y,
-            }) {
-                Some(_) => size.x,
-                None => size.x + 1,
-            }
-        } else {
-            size.x
-        },
-        // find bottom
-        y: if (pos.y + size.y) < bounds.y {
-            match quadinf_map.get(&Vec2 {
-                x: pos.x,
-                y: pos.y + size.y,
-            }) {
-                Some(_) => size.y,
-                None => size.y + 1,
-            }
-        } else {
-            size.y
-        },
-    }
+fn multiply_pixels<P: RgbaPixel>(a: &P, b: &P) -> [P::Subpixel; 4] {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    let (a, b) = (a.channels(), b.channels());
+    [0, 1, 2, 3]
+        .map(|i| NumCast::from(a[i].to_f64().unwrap() * b[i].to_f64().unwrap() / max).unwrap())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use once_cell::sync::Lazy;
     pub use test_case::test_case;
 
     #[test_case([96,96,96,96],[96,96,96,96],[36,36,36,36];"6666x6666")]
@@ -236,36 +221,27 @@ mod tests {
         assert_eq!(multiply_pixels(&Rgba(a), &Rgba(b)), expects)
     }
 
-    /* 0,0|AB e\|  X=(2,2)
-     *    |CD f\|__ missing pixel
-     *    |gh x\|
-     *    |\\ \Z|
-     */
-    static TEST_QUADTREE: Lazy<QuadMap> = Lazy::new(|| {
-        QuadMap::from([
-            (Vec2 { x: 0, y: 0 }, Quad::new(1)), //A
-            (Vec2 { x: 2, y: 0 }, Quad::new(1)), //B
-            (Vec2 { x: 0, y: 2 }, Quad::new(1)), //C
-            (Vec2 { x: 2, y: 2 }, Quad::new(1)), //D
-            (Vec2 { x: 5, y: 0 }, Quad::new(1)), //e
-            (Vec2 { x: 5, y: 2 }, Quad::new(1)), //f
-            (Vec2 { x: 0, y: 5 }, Quad::new(1)), //g
-            (Vec2 { x: 2, y: 5 }, Quad::new(1)), //h
-            (Vec2 { x: 5, y: 5 }, Quad::new(1)), //x
-            (Vec2 { x: 7, y: 7 }, Quad::new(1)), //Z
-        ])
-    });
-    const TEST_QUAD_SIZE: Vec2 = Vec2 { x: 2, y: 2 };
-
-    #[test_case(Vec2{x:0,y:0},TEST_QUAD_SIZE; "none-rb")]
-    #[test_case(Vec2{x:2,y:0},Vec2 { x: 3, y: 2 }; "expand-x")]
-    #[test_case(Vec2{x:0,y:2},Vec2 { x: 2, y: 3 }; "expand-y")]
-    #[test_case(Vec2{x:2,y:2},Vec2 { x: 3, y: 3 }; "expand-both")]
-    #[test_case(Vec2{x:7,y:7},TEST_QUAD_SIZE; "at-bounds")]
-    fn adjusts_size(pos: Vec2, expect_size: Vec2) {
-        assert_eq!(
-            adjust_quad_size(&pos, &TEST_QUAD_SIZE, &TEST_QUADTREE, &Vec2 { x: 9, y: 9 }),
-            expect_size
-        )
+    #[test]
+    fn fills_odd_sizes() {
+        // halving 9 and 13 leaves a pixel of remainder at every depth
+        let img = RgbaImage::from_fn(9, 13, |x, y| Rgba([(x * 28) as u8, (y * 19) as u8, 0, 255]));
+        let structure = QuadStructure::from(calc_quads(
+            &img,
+            &Vec2 { x: 1, y: 1 },
+            0,
+            &Rgba([0, 0, 0, 0]),
+            true,
+            Aggregation::Mean,
+            &Guides::default(),
+        ));
+        let out = draw_quads(
+            &structure,
+            &None,
+            &None,
+            false,
+            &None,
+            &mut ImageCache::new(),
+        );
+        assert!(out.pixels().all(|p| p[3] == 255));
     }
 }
@@ -0,0 +1,135 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::GenericImageView;
+
+use crate::utils::{luma, RgbaPixel, Vec2};
+
+/// gradient filter used to detect the edges
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum EdgeOperator {
+    /// 3x3 Sobel kernel
+    Sobel,
+    /// 3x3 Scharr kernel, more accurate on diagonal edges
+    Scharr,
+}
+
+impl EdgeOperator {
+    /// weights of the smoothing part of the kernel, the other being a difference
+    fn weights(&self) -> [f64; 3] {
+        match self {
+            EdgeOperator::Sobel => [1.0, 2.0, 1.0],
+            EdgeOperator::Scharr => [3.0, 10.0, 3.0],
+        }
+    }
+}
+
+/// gradient magnitude of the image, as a summed area table to get the density of any region
+pub struct EdgeMap {
+    width: usize,
+    /// (width + 1) * (height + 1) sums, with a leading row and column of zeros
+    integral: Vec<f64>,
+}
+
+impl EdgeMap {
+    /// computes the gradient of the luma, normalized between 0 and 1
+    pub fn new<I, P>(img: &I, operator: EdgeOperator) -> Self
+    where
+        I: GenericImageView<Pixel = P>,
+        P: RgbaPixel,
+    {
+        let (w, h) = img.dimensions();
+        let mut lumas = vec![0.0; (w * h) as usize];
+        for (x, y, p) in img.pixels() {
+            lumas[(y * w + x) as usize] = luma(&p);
+        }
+        // pixels outside the image repeat the border
+        let at = |x: i64, y: i64| {
+            let x = x.clamp(0, w as i64 - 1) as u32;
+            let y = y.clamp(0, h as i64 - 1) as u32;
+            lumas[(y * w + x) as usize]
+        };
+
+        let k = operator.weights();
+        // the highest magnitude, of a black to white step, on both directions
+        let norm = (k.iter().sum::<f64>()) * std::f64::consts::SQRT_2;
+        let width = w as usize + 1;
+        let mut integral = vec![0.0; width * (h as usize + 1)];
+        for y in 0..h as i64 {
+            for x in 0..w as i64 {
+                let (mut gx, mut gy) = (0.0, 0.0);
+                for (i, k) in (-1..=1).zip(k) {
+                    gx += k * (at(x + 1, y + i) - at(x - 1, y + i));
+                    gy += k * (at(x + i, y + 1) - at(x + i, y - 1));
+                }
+                let magnitude = (gx * gx + gy * gy).sqrt() / norm;
+                let (x, y) = (x as usize + 1, y as usize + 1);
+                integral[y * width + x] =
+                    magnitude + integral[(y - 1) * width + x] + integral[y * width + x - 1]
+                        - integral[(y - 1) * width + x - 1];
+            }
+        }
+        Self { width, integral }
+    }
+
+    /// mean gradient magnitude of the region, from 0 to 1
+    pub fn density(&self, pos: &Vec2, size: &Vec2) -> f64 {
+        let at = |x: u32, y: u32| self.integral[y as usize * self.width + x as usize];
+        let (x1, y1) = (pos.x + size.x, pos.y + size.y);
+        let sum = at(x1, y1) + at(pos.x, pos.y) - at(x1, pos.y) - at(pos.x, y1);
+        sum / (size.x as f64 * size.y as f64).max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    pub use test_case::test_case;
+
+    /// black with a vertical white line in the column 5
+    fn line() -> RgbaImage {
+        RgbaImage::from_fn(16, 8, |x, _| match x {
+            5 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 255]),
+        })
+    }
+
+    #[test_case(EdgeOperator::Sobel; "sobel")]
+    #[test_case(EdgeOperator::Scharr; "scharr")]
+    fn finds_edges(operator: EdgeOperator) {
+        let edges = EdgeMap::new(&line(), operator);
+        let size = Vec2 { x: 4, y: 8 };
+        assert!(edges.density(&Vec2 { x: 8, y: 0 }, &size) < 1e-9);
+        assert!(edges.density(&Vec2 { x: 4, y: 0 }, &size) > 0.1);
+    }
+
+    #[test]
+    fn normalizes_magnitude() {
+        // a black to white step has the highest gradient along x
+        let img = RgbaImage::from_fn(2, 1, |x, _| {
+            Rgba([x as u8 * 255, x as u8 * 255, x as u8 * 255, 255])
+        });
+        let edges = EdgeMap::new(&img, EdgeOperator::Sobel);
+        let d = edges.density(&Vec2::ZERO, &Vec2 { x: 1, y: 1 });
+        assert!((d - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn flat_has_no_edges() {
+        let img = RgbaImage::from_pixel(8, 8, Rgba([100, 50, 25, 255]));
+        let edges = EdgeMap::new(&img, EdgeOperator::Scharr);
+        assert_eq!(edges.density(&Vec2::ZERO, &Vec2 { x: 8, y: 8 }), 0.0);
+    }
+}
@@ -0,0 +1,170 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::GenericImageView;
+
+use crate::utils::{luma, RgbaPixel, Vec2};
+
+/// number of bins of the luma histogram
+pub(crate) const ENTROPY_BINS: usize = 256;
+/// side of the tiles the histograms are summed over
+const TILE: u32 = 16;
+
+type Histogram = [u32; ENTROPY_BINS];
+
+/// luma histograms of the image, as a summed area table over tiles to get the entropy of any region
+/// without scanning more than its edges
+pub struct EntropyMap {
+    width: u32,
+    /// histogram bin of each pixel
+    bins: Vec<u8>,
+    /// tiles per row + 1
+    tiles: usize,
+    /// (tiles per row + 1) * (tiles per column + 1) histograms, with a leading row and column of zeros.
+    /// The sums wrap around, their differences are still exact
+    integral: Vec<Histogram>,
+}
+
+impl EntropyMap {
+    pub fn new<I, P>(img: &I) -> Self
+    where
+        I: GenericImageView<Pixel = P>,
+        P: RgbaPixel,
+    {
+        let (w, h) = img.dimensions();
+        let mut bins = vec![0; (w * h) as usize];
+        for (x, y, p) in img.pixels() {
+            let bin = (luma(&p) * ENTROPY_BINS as f64) as usize;
+            bins[(y * w + x) as usize] = bin.min(ENTROPY_BINS - 1) as u8;
+        }
+
+        let tiles = (w / TILE) as usize + 1;
+        let mut map = Self {
+            width: w,
+            bins,
+            tiles,
+            integral: vec![[0; ENTROPY_BINS]; tiles * ((h / TILE) as usize + 1)],
+        };
+        for ty in 1..=(h / TILE) as usize {
+            for tx in 1..tiles {
+                let mut sum = [0; ENTROPY_BINS];
+                let (x, y) = (tx as u32 * TILE, ty as u32 * TILE);
+                map.add_pixels(&mut sum, (x - TILE, y - TILE), (x, y));
+                let (up, left, diag) = (
+                    &map.integral[(ty - 1) * tiles + tx],
+                    &map.integral[ty * tiles + tx - 1],
+                    &map.integral[(ty - 1) * tiles + tx - 1],
+                );
+                for k in 0..ENTROPY_BINS {
+                    sum[k] = sum[k]
+                        .wrapping_add(up[k])
+                        .wrapping_add(left[k])
+                        .wrapping_sub(diag[k]);
+                }
+                map.integral[ty * tiles + tx] = sum;
+            }
+        }
+        map
+    }
+
+    /// Shannon entropy in bits of the luma histogram of the region
+    pub fn entropy(&self, pos: &Vec2, size: &Vec2) -> f64 {
+        let histogram = self.histogram(pos, size);
+        let total = (size.x as f64 * size.y as f64).max(1.0);
+        histogram
+            .iter()
+            .filter(|&&n| n > 0)
+            .map(|&n| {
+                let p = n as f64 / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// the whole tiles inside the region are summed from the table, the pixels around them counted
+    fn histogram(&self, pos: &Vec2, size: &Vec2) -> Histogram {
+        let (x0, y0, x1, y1) = (pos.x, pos.y, pos.x + size.x, pos.y + size.y);
+        let (tx0, ty0, tx1, ty1) = (x0.div_ceil(TILE), y0.div_ceil(TILE), x1 / TILE, y1 / TILE);
+        let mut histogram = [0; ENTROPY_BINS];
+        if tx0 >= tx1 || ty0 >= ty1 {
+            self.add_pixels(&mut histogram, (x0, y0), (x1, y1));
+            return histogram;
+        }
+
+        let at = |tx: u32, ty: u32| &self.integral[ty as usize * self.tiles + tx as usize];
+        let (a, b, c, d) = (at(tx1, ty1), at(tx0, ty0), at(tx1, ty0), at(tx0, ty1));
+        for k in 0..ENTROPY_BINS {
+            histogram[k] = a[k]
+                .wrapping_add(b[k])
+                .wrapping_sub(c[k])
+                .wrapping_sub(d[k]);
+        }
+        // the rows above and below the tiles, then the pixels at their sides
+        let (ix0, iy0, ix1, iy1) = (tx0 * TILE, ty0 * TILE, tx1 * TILE, ty1 * TILE);
+        self.add_pixels(&mut histogram, (x0, y0), (x1, iy0));
+        self.add_pixels(&mut histogram, (x0, iy1), (x1, y1));
+        self.add_pixels(&mut histogram, (x0, iy0), (ix0, iy1));
+        self.add_pixels(&mut histogram, (ix1, iy0), (x1, iy1));
+        histogram
+    }
+
+    /// counts the pixels from the first corner included to the second excluded
+    fn add_pixels(&self, histogram: &mut Histogram, from: (u32, u32), to: (u32, u32)) {
+        for y in from.1..to.1 {
+            let row = (y * self.width) as usize;
+            for &bin in &self.bins[row + from.0 as usize..row + to.0 as usize] {
+                histogram[bin as usize] += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    pub use test_case::test_case;
+
+    #[test_case(|_, _| 0 => 0.0; "flat")]
+    #[test_case(|x, _| if x < 4 { 0 } else { 255 } => 1.0; "halves")]
+    #[test_case(|x, y| ((x * 4 + y) % 16) as u8 * 16 => 4.0; "ramp")]
+    fn calcs_entropy(f: fn(u32, u32) -> u8) -> f64 {
+        let img = RgbaImage::from_fn(8, 4, |x, y| {
+            let v = f(x, y);
+            Rgba([v, v, v, 255])
+        });
+        EntropyMap::new(&img).entropy(&Vec2::ZERO, &Vec2 { x: 8, y: 4 })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn sums_tiles(
+            x in 0u32..70,
+            y in 0u32..50,
+            w in 0u32..70,
+            h in 0u32..50,
+        ) {
+            let img = RgbaImage::from_fn(70, 50, |x, y| {
+                let v = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) as u8;
+                Rgba([v, v, v, 255])
+            });
+            let map = EntropyMap::new(&img);
+            let (w, h) = (w.min(70 - x), h.min(50 - y));
+            let mut scanned = [0; ENTROPY_BINS];
+            map.add_pixels(&mut scanned, (x, y), (x + w, y + h));
+            let histogram = map.histogram(&Vec2 { x, y }, &Vec2 { x: w, y: h });
+            proptest::prop_assert_eq!(histogram, scanned);
+        }
+    }
+}
@@ -1,23 +1,70 @@
 use crate::args::*;
+use crate::cms::to_working_space;
 use crate::drawing::apply_background_color;
+use crate::mask::RegionMask;
+use crate::meta::*;
+use crate::quad::DEFAULT_TRESHOLD;
 use image::{codecs::*, *};
-use log::{debug, info, trace};
-use std::{fs::File, path::PathBuf};
+use log::{debug, info, trace, warn};
+use moxcms::ColorProfile;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
 
-pub(crate) fn load_image(source: &PathBuf) -> ImageResult<DynamicImage> {
+/// path used to read from stdin or write to stdout
+pub(crate) const STDIO_PATH: &str = "-";
+
+/// if the path refers to stdin/stdout instead of a file
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
+}
+
+/// loads the image applying its EXIF orientation, the returned EXIF has the orientation reset
+pub(crate) fn load_image_with_metadata(
+    source: &PathBuf,
+) -> ImageResult<(DynamicImage, ImageMetadata)> {
     let strpath = source.to_str().unwrap();
-    info!("loading image '{strpath}'");
-    let imres = image::ImageReader::open(source)
-        .expect("error while opening image")
-        .with_guessed_format()?
-        .decode();
+    let buf = if is_stdio(source) {
+        info!("loading image from stdin");
+        let mut buf = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+        buf
+    } else {
+        info!("loading image '{strpath}'");
+        std::fs::read(source).expect("error while opening image")
+    };
+
+    // format is guessed from the magic bytes
+    let reader = ImageReader::new(Cursor::new(&buf[..])).with_guessed_format()?;
+    let mut decoder = reader.into_decoder()?;
+    let mut meta = read_metadata(&mut decoder)?;
+    // the orientation is reset in the EXIF as it gets applied here
+    let orientation = match meta.exif {
+        Some(ref mut exif) => metadata::Orientation::remove_from_exif_chunk(exif)
+            .unwrap_or(metadata::Orientation::NoTransforms),
+        None => decoder.orientation()?,
+    };
+
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    if orientation != metadata::Orientation::NoTransforms {
+        debug!("applying orientation {orientation:?}");
+        img.apply_orientation(orientation);
+    }
     debug!("loaded image '{strpath}'");
-    imres
+    Ok((img, meta))
 }
 
-pub(crate) fn load_filler(drawarg: &DrawingArgs) -> Result<Option<DynamicImage>, ImageError> {
+pub(crate) fn load_filler(
+    drawarg: &DrawingArgs,
+    working_space: &Option<ColorProfile>,
+) -> Result<Option<DynamicImage>, ImageError> {
     if let Some(ref path) = drawarg.fill_with {
-        let img = load_image(path)?;
+        let (img, meta) = load_image_with_metadata(path)?;
+        let img = to_working_space(img, &meta.icc, working_space);
         Ok(Some(if let Some(bg) = drawarg.background {
             debug!("applying color to filler image");
             apply_background_color(&img, &bg)
@@ -29,44 +76,748 @@ pub(crate) fn load_filler(drawarg: &DrawingArgs) -> Result<Option<DynamicImage>,
     }
 }
 
+/// loads the region of interest mask, stretched to the given size
+pub(crate) fn load_mask(calc: &QuadArgs, size: (u32, u32)) -> ImageResult<Option<RegionMask>> {
+    let Some(ref path) = calc.mask else {
+        return Ok(None);
+    };
+    let (img, _) = load_image_with_metadata(path)?;
+    let mut mask = img.to_luma8();
+    if mask.dimensions() != size {
+        debug!("resizing mask from {:?} to {size:?}", mask.dimensions());
+        mask = imageops::resize(&mask, size.0, size.1, imageops::FilterType::Triangle);
+    }
+    Ok(Some(RegionMask::new(mask, calc.mask_mode)))
+}
+
+/// builds the name of an output file from the template, see `IOArgs::output_name`
+pub(crate) fn output_file_name(
+    template: &str,
+    input: &Path,
+    format: &Option<ImageFormat>,
+    calc: &QuadArgs,
+) -> String {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = match format {
+        Some(f) => f.extensions_str()[0].into(),
+        None => input.extension().unwrap_or_default().to_string_lossy(),
+    };
+    let t = calc.threshold.unwrap_or(DEFAULT_TRESHOLD);
+    template
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext)
+        .replace("{depth}", &calc.min_depth.to_string())
+        .replace(
+            "{size}",
+            &format!("{}x{}", calc.min_quad_size.x, calc.min_quad_size.y),
+        )
+        .replace(
+            "{threshold}",
+            &format!("{:02x}{:02x}{:02x}{:02x}", t[0], t[1], t[2], t[3]),
+        )
+}
+
 pub(crate) fn save_image(
     img: &DynamicImage,
-    path: &PathBuf,
-    compression: &ImgCompression,
+    path: &Path,
+    format: &Option<ImageFormat>,
+    encoding: &EncodingArgs,
+    meta: &ImageMetadata,
 ) -> ImageResult<()> {
-    info!("saving image to '{}'", path.to_str().unwrap());
+    let buf = encode_image(img, output_format(path, format)?, encoding, meta)?;
+    Ok(write_output(path, &buf)?)
+}
+
+/// reads the whole file, or stdin
+pub(crate) fn read_input(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if is_stdio(path) {
+        info!("reading from stdin");
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+    } else {
+        info!("reading '{}'", path.to_string_lossy());
+        File::open(path)?.read_to_end(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// writes the buffer to the file, or stdout
+pub(crate) fn write_output(path: &Path, buf: &[u8]) -> std::io::Result<()> {
+    if is_stdio(path) {
+        info!("writing to stdout");
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(buf)?;
+        stdout.flush()
+    } else {
+        info!("saving to '{}'", path.to_string_lossy());
+        File::create(path)?.write_all(buf)
+    }
+}
+
+/// size in bytes of the image as it would be saved
+pub(crate) fn encoded_size(
+    img: &DynamicImage,
+    path: &Path,
+    format: &Option<ImageFormat>,
+    encoding: &EncodingArgs,
+    meta: &ImageMetadata,
+) -> ImageResult<usize> {
+    Ok(encode_image(img, output_format(path, format)?, encoding, meta)?.len())
+}
+
+/// the given format, or the one of the path extension
+fn output_format(path: &Path, format: &Option<ImageFormat>) -> ImageResult<ImageFormat> {
+    // when writing to stdout the format can't be guessed and has to be given
+    match format {
+        Some(f) => Ok(*f),
+        None => ImageFormat::from_path(path),
+    }
+}
 
-    match ImageFormat::from_path(path)? {
+/// converts the image to a color type supported by the encoder of the format,
+/// keeping as much bit depth as the format allows
+fn to_encodable(img: &DynamicImage, format: ImageFormat) -> Cow<'_, DynamicImage> {
+    let color = img.color();
+    let alpha = color.has_alpha();
+    let depth = color.bytes_per_pixel() / color.channel_count();
+    let converted = match format {
+        ImageFormat::Jpeg if alpha || depth > 1 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ImageFormat::Hdr if color != ColorType::Rgb32F => {
+            DynamicImage::ImageRgb32F(img.to_rgb32f())
+        }
+        ImageFormat::OpenExr if depth != 4 => match alpha {
+            true => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+            false => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        },
+        // alpha is dropped to write a plain PPM, which is more widely supported than PAM
+        ImageFormat::Pnm if alpha || depth > 2 => match depth {
+            1 => DynamicImage::ImageRgb8(img.to_rgb8()),
+            _ => DynamicImage::ImageRgb16(img.to_rgb16()),
+        },
+        ImageFormat::Png | ImageFormat::Tiff | ImageFormat::Avif if depth > 2 => match alpha {
+            true => DynamicImage::ImageRgba16(img.to_rgba16()),
+            false => DynamicImage::ImageRgb16(img.to_rgb16()),
+        },
+        ImageFormat::Jpeg
+        | ImageFormat::Hdr
+        | ImageFormat::OpenExr
+        | ImageFormat::Png
+        | ImageFormat::Tiff
+        | ImageFormat::Pnm
+        | ImageFormat::Avif => return Cow::Borrowed(img),
+        _ if depth > 1 => match alpha {
+            true => DynamicImage::ImageRgba8(img.to_rgba8()),
+            false => DynamicImage::ImageRgb8(img.to_rgb8()),
+        },
+        _ => return Cow::Borrowed(img),
+    };
+    debug!(
+        "converting {color:?} image to {:?} for {format:?}",
+        converted.color()
+    );
+    Cow::Owned(converted)
+}
+
+/// encodes the image in the given format, embedding the metadata if supported
+fn encode_image(
+    img: &DynamicImage,
+    format: ImageFormat,
+    encoding: &EncodingArgs,
+    meta: &ImageMetadata,
+) -> ImageResult<Vec<u8>> {
+    if !meta.is_empty()
+        && !matches!(
+            format,
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP
+        )
+    {
+        warn!("metadata is not supported for {format:?} and will be discarded");
+    }
+    let compression = encoding.compression;
+    let img = &*to_encodable(img, format);
+    let mut w = Cursor::new(Vec::new());
+    match format {
         ImageFormat::Png => {
             trace!("saving as .png image");
-            img.write_with_encoder(png::PngEncoder::new_with_quality(
-                File::create(path)?,
-                match compression {
-                    ImgCompression::Max => png::CompressionType::Best,
-                    ImgCompression::High => png::CompressionType::Best,
-                    ImgCompression::Default => png::CompressionType::Default,
-                    ImgCompression::Low => png::CompressionType::Fast,
-                    ImgCompression::No => png::CompressionType::Fast,
-                },
-                png::FilterType::Adaptive,
-            ))
+            encode_png(img, &mut w, encoding)
         }
         ImageFormat::Jpeg => {
             trace!("saving as .jpeg image");
-            img.write_with_encoder(jpeg::JpegEncoder::new_with_quality(
-                File::create(path)?,
-                match compression {
-                    ImgCompression::Max => 10,
-                    ImgCompression::High => 40,
-                    ImgCompression::Default => 70,
-                    ImgCompression::Low => 82,
-                    ImgCompression::No => 100,
-                },
+            let quality = encoding.quality.unwrap_or(match compression {
+                ImgCompression::Max => 10,
+                ImgCompression::High => 40,
+                ImgCompression::Default => 70,
+                ImgCompression::Low => 82,
+                ImgCompression::No => 100,
+            });
+            encode_jpeg(img, &mut w, quality, encoding.chroma_subsampling)
+        }
+        ImageFormat::WebP => {
+            trace!("saving as .webp image");
+            encode_webp(img, &mut w, encoding.quality)
+        }
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => {
+            trace!("saving as .avif image");
+            // speed goes from 1 (slowest) to 10, quality from 1 to 100
+            let (speed, quality) = match compression {
+                ImgCompression::Max => (1, 30),
+                ImgCompression::High => (3, 55),
+                ImgCompression::Default => (4, 75),
+                ImgCompression::Low => (6, 90),
+                ImgCompression::No => (8, 100),
+            };
+            img.write_with_encoder(avif::AvifEncoder::new_with_speed_quality(
+                &mut w,
+                speed,
+                encoding.quality.unwrap_or(quality),
             ))
         }
+        #[cfg(feature = "tga")]
+        ImageFormat::Tga => {
+            trace!("saving as .tga image");
+            let encoder = tga::TgaEncoder::new(&mut w);
+            img.write_with_encoder(match compression {
+                ImgCompression::No => encoder.disable_rle(),
+                _ => encoder,
+            })
+        }
         _ => {
             trace!("saving as generic image");
-            img.save(path)
+            img.write_to(&mut w, format)
+        }
+    }?;
+    Ok(embed_metadata(w.into_inner(), format, meta))
+}
+
+/// all the filters tried by `ImgCompression::Max`, along with a palette
+const PNG_FILTERS: [png::FilterType; 6] = [
+    png::FilterType::NoFilter,
+    png::FilterType::Sub,
+    png::FilterType::Up,
+    png::FilterType::Avg,
+    png::FilterType::Paeth,
+    png::FilterType::Adaptive,
+];
+
+fn encode_png(
+    img: &DynamicImage,
+    w: &mut Cursor<Vec<u8>>,
+    encoding: &EncodingArgs,
+) -> ImageResult<()> {
+    let compression = encoding.compression;
+    let color = img.color();
+    let palette = match (encoding.indexed, compression) {
+        (true, _) => Some(quantize(&img.to_rgba8())),
+        // tried only if lossless
+        (false, ImgCompression::Max) if color.bytes_per_pixel() == color.channel_count() => {
+            exact_palette(&img.to_rgba8())
+        }
+        _ => None,
+    };
+
+    // keeps the smallest output
+    let mut best: Option<Vec<u8>> = None;
+    if let Some((palette, indices)) = palette {
+        let level = match compression {
+            ImgCompression::Max | ImgCompression::High => ::png::Compression::Best,
+            ImgCompression::Default => ::png::Compression::Default,
+            ImgCompression::Low | ImgCompression::No => ::png::Compression::Fast,
+        };
+        let mut buf = Vec::new();
+        write_indexed_png(&mut buf, img.dimensions(), &palette, &indices, level)?;
+        trace!(
+            "palette of {} colors produced {} bytes",
+            palette.len(),
+            buf.len()
+        );
+        if encoding.indexed {
+            return Ok(w.write_all(&buf)?);
+        }
+        best = Some(buf);
+    }
+
+    let (level, filters): (_, &[_]) = match compression {
+        ImgCompression::Max => (png::CompressionType::Best, &PNG_FILTERS),
+        ImgCompression::High => (png::CompressionType::Best, &[png::FilterType::Adaptive]),
+        ImgCompression::Default => (png::CompressionType::Default, &[png::FilterType::Adaptive]),
+        ImgCompression::Low => (png::CompressionType::Fast, &[png::FilterType::Adaptive]),
+        ImgCompression::No => (png::CompressionType::Fast, &[png::FilterType::NoFilter]),
+    };
+    for &filter in filters {
+        let mut buf = Vec::new();
+        img.write_with_encoder(png::PngEncoder::new_with_quality(&mut buf, level, filter))?;
+        trace!("filter {filter:?} produced {} bytes", buf.len());
+        if best.as_ref().is_none_or(|b| buf.len() < b.len()) {
+            best = Some(buf);
+        }
+    }
+    w.write_all(&best.unwrap())?;
+    Ok(())
+}
+
+/// palette and per pixel indices of the image, if it has at most 256 colors
+fn exact_palette(img: &RgbaImage) -> Option<(Vec<Rgba<u8>>, Vec<u8>)> {
+    let mut palette = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut indices = Vec::with_capacity(img.len() / 4);
+    for px in img.pixels() {
+        let i = match lookup.get(px) {
+            Some(&i) => i,
+            None if palette.len() == 256 => return None,
+            None => {
+                let i = palette.len() as u8;
+                palette.push(*px);
+                lookup.insert(*px, i);
+                i
+            }
+        };
+        indices.push(i);
+    }
+    Some(sort_palette(palette, indices))
+}
+
+/// reduces the image to a palette of at most 256 colors
+fn quantize(img: &RgbaImage) -> (Vec<Rgba<u8>>, Vec<u8>) {
+    if let Some(exact) = exact_palette(img) {
+        return exact;
+    }
+    debug!("quantizing image to 256 colors");
+    let nq = color_quant::NeuQuant::new(10, 256, img.as_raw());
+    let palette = nq
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| Rgba([c[0], c[1], c[2], c[3]]))
+        .collect();
+    let indices = img.pixels().map(|px| nq.index_of(&px.0) as u8).collect();
+    sort_palette(palette, indices)
+}
+
+/// moves the translucent colors at the start of the palette to keep the tRNS chunk short
+fn sort_palette(palette: Vec<Rgba<u8>>, indices: Vec<u8>) -> (Vec<Rgba<u8>>, Vec<u8>) {
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by_key(|&i| palette[i][3] == u8::MAX);
+    let mut remap = [0; 256];
+    for (new, &old) in order.iter().enumerate() {
+        remap[old] = new as u8;
+    }
+    (
+        order.iter().map(|&i| palette[i]).collect(),
+        indices.into_iter().map(|i| remap[i as usize]).collect(),
+    )
+}
+
+/// writes an indexed PNG with the smallest bit depth that fits the palette
+fn write_indexed_png(
+    w: &mut impl Write,
+    (width, height): (u32, u32),
+    palette: &[Rgba<u8>],
+    indices: &[u8],
+    level: ::png::Compression,
+) -> ImageResult<()> {
+    let bits: u8 = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let mut encoder = ::png::Encoder::new(w, width, height);
+    encoder.set_color(::png::ColorType::Indexed);
+    encoder.set_depth(::png::BitDepth::from_u8(bits).unwrap());
+    encoder.set_compression(level);
+    // filters rarely help with palettes
+    encoder.set_filter(::png::FilterType::NoFilter);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>(),
+    );
+    let trns: Vec<u8> = palette
+        .iter()
+        .map(|c| c[3])
+        .take_while(|&a| a != u8::MAX)
+        .collect();
+    if !trns.is_empty() {
+        encoder.set_trns(trns);
+    }
+
+    // packs the indices of each row in bytes, from the most significant bit
+    let per_byte = (8 / bits) as usize;
+    let data: Vec<u8> = indices
+        .chunks_exact(width as usize)
+        .flat_map(|row| {
+            row.chunks(per_byte).map(|c| {
+                c.iter()
+                    .enumerate()
+                    .fold(0, |b, (i, &v)| b | v << (8 - bits as usize * (i + 1)))
+            })
+        })
+        .collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|e| ImageError::IoError(e.into()))
+}
+
+fn encode_jpeg(
+    img: &DynamicImage,
+    w: &mut impl Write,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+) -> ImageResult<()> {
+    let (width, height) = img.dimensions();
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(ImageError::Limits(error::LimitError::from_kind(
+            error::LimitErrorKind::DimensionError,
+        )));
+    };
+    let mut encoder = jpeg_encoder::Encoder::new(w, quality);
+    encoder.set_sampling_factor(match subsampling {
+        ChromaSubsampling::S444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        ChromaSubsampling::S422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::S420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    });
+    match img {
+        DynamicImage::ImageLuma8(luma) => {
+            encoder.encode(luma, width, height, jpeg_encoder::ColorType::Luma)
         }
+        _ => encoder.encode(&img.to_rgb8(), width, height, jpeg_encoder::ColorType::Rgb),
+    }
+    .map_err(|e| match e {
+        jpeg_encoder::EncodingError::IoError(e) => ImageError::IoError(e),
+        e => ImageError::Encoding(error::EncodingError::new(ImageFormat::Jpeg.into(), e)),
+    })
+}
+
+/// encodes a lossless WebP, or a lossy one if a quality is given
+fn encode_webp(img: &DynamicImage, w: &mut impl Write, quality: Option<u8>) -> ImageResult<()> {
+    let (width, height) = img.dimensions();
+    let alpha = img.color().has_alpha();
+    let data = match alpha {
+        true => img.to_rgba8().into_raw(),
+        false => img.to_rgb8().into_raw(),
+    };
+    match quality {
+        #[cfg(feature = "webp-lossy")]
+        Some(q) => {
+            let encoder = match alpha {
+                true => ::webp::Encoder::from_rgba(&data, width, height),
+                false => ::webp::Encoder::from_rgb(&data, width, height),
+            };
+            w.write_all(&encoder.encode(q as f32))?;
+            return Ok(());
+        }
+        #[cfg(not(feature = "webp-lossy"))]
+        Some(_) => warn!("lossy WebP is not supported by this build, saving as lossless"),
+        None => {}
+    }
+    let color = match alpha {
+        true => image_webp::ColorType::Rgba8,
+        false => image_webp::ColorType::Rgb8,
+    };
+    image_webp::WebPEncoder::new(w)
+        .encode(&data, width, height, color)
+        .map_err(|e| match e {
+            image_webp::EncodingError::IoError(e) => ImageError::IoError(e),
+            e => ImageError::Encoding(error::EncodingError::new(ImageFormat::WebP.into(), e)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edges::EdgeOperator;
+    use crate::mask::MaskMode;
+    use crate::partition::{CellEdges, Partition};
+    use crate::quad::*;
+    use crate::utils::{LumaStandard, Vec2};
+    pub use test_case::test_case;
+
+    const TEST_CALC: QuadArgs = QuadArgs {
+        min_depth: DEFAULT_MIN_DEPTH,
+        min_quad_size: DEFAULT_MIN_SIZE,
+        threshold: None,
+        aggregation: Aggregation::Mean,
+        criterion: Criterion::Color,
+        partition: Partition::Quad,
+        cell_size: None,
+        cell_edges: CellEdges::Crop,
+        edge_threshold: DEFAULT_EDGE_THRESHOLD,
+        entropy_threshold: DEFAULT_ENTROPY_THRESHOLD,
+        distance: Distance::Channels,
+        distance_threshold: DEFAULT_DISTANCE_THRESHOLD,
+        luma_standard: LumaStandard::Rec709,
+        channel_weights: [1.0; 4],
+        ignore_alpha: false,
+        edge_operator: EdgeOperator::Sobel,
+        mask: None,
+        mask_mode: MaskMode::Modulate,
+    };
+
+    const TEST_ENCODING: EncodingArgs = EncodingArgs {
+        compression: ImgCompression::Default,
+        quality: None,
+        chroma_subsampling: ChromaSubsampling::S444,
+        indexed: false,
+    };
+
+    /// an image with a gradient and many colors
+    fn gradient(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(w, h, |x, y| {
+            Rgba([(x * 7) as u8, (y * 5) as u8, (x ^ y) as u8, 255])
+        }))
+    }
+
+    #[test_case(DEFAULT_OUTPUT_NAME, "dir/a.jpg", None => "a.jpg"; "default")]
+    #[test_case(DEFAULT_OUTPUT_NAME, "dir/a.jpg", Some(ImageFormat::Png) => "a.png"; "default-format")]
+    #[test_case("{stem}-quads.{ext}", "a.b.jpg", None => "a.b-quads.jpg"; "stem-dots")]
+    #[test_case("{stem}.png", "a.jpg", Some(ImageFormat::WebP) => "a.png"; "fixed-ext")]
+    #[test_case("{stem}.{ext}", "noext", None => "noext."; "no-ext")]
+    #[test_case("{stem}-d{depth}-s{size}-t{threshold}", "a.png", None => "a-d4-s4x4-t08080808"; "params")]
+    #[test_case("{nope}", "a.png", None => "{nope}"; "unknown")]
+    fn formats_output_name(template: &str, input: &str, format: Option<ImageFormat>) -> String {
+        output_file_name(template, &PathBuf::from(input), &format, &TEST_CALC)
+    }
+
+    #[test_case(ImageFormat::Jpeg; "jpeg")]
+    #[test_case(ImageFormat::Png; "png")]
+    #[test_case(ImageFormat::WebP; "webp")]
+    fn applies_orientation(format: ImageFormat) {
+        let meta = ImageMetadata {
+            exif: Some(crate::meta::tests::exif(false, 6)),
+            ..Default::default()
+        };
+        let buf =
+            encode_image(&DynamicImage::new_rgb8(4, 2), format, &TEST_ENCODING, &meta).unwrap();
+        // unique to the test and the process, as other runs can share the directory
+        let path = std::env::temp_dir().join(format!(
+            "qom-{}-applies_orientation.{}",
+            std::process::id(),
+            format.extensions_str()[0]
+        ));
+        std::fs::write(&path, buf).unwrap();
+
+        let (img, meta) = load_image_with_metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(img.dimensions(), (2, 4));
+        assert_eq!(
+            metadata::Orientation::from_exif_chunk(&meta.exif.unwrap()),
+            Some(metadata::Orientation::NoTransforms)
+        );
+    }
+
+    #[test_case(ColorType::Rgba16, ImageFormat::Png => ColorType::Rgba16; "png-16")]
+    #[test_case(ColorType::Rgba32F, ImageFormat::Png => ColorType::Rgba16; "png-f32")]
+    #[test_case(ColorType::Rgba32F, ImageFormat::OpenExr => ColorType::Rgba32F; "exr")]
+    #[test_case(ColorType::Rgba8, ImageFormat::OpenExr => ColorType::Rgba32F; "exr-8")]
+    #[test_case(ColorType::Rgba32F, ImageFormat::Avif => ColorType::Rgba16; "avif-f32")]
+    #[test_case(ColorType::Rgba32F, ImageFormat::Qoi => ColorType::Rgba8; "qoi-f32")]
+    #[test_case(ColorType::Rgba16, ImageFormat::Hdr => ColorType::Rgb32F; "hdr")]
+    #[test_case(ColorType::Rgba16, ImageFormat::Jpeg => ColorType::Rgb8; "jpeg-16")]
+    #[test_case(ColorType::Rgba8, ImageFormat::Jpeg => ColorType::Rgb8; "jpeg-alpha")]
+    #[test_case(ColorType::Rgba32F, ImageFormat::Bmp => ColorType::Rgba8; "bmp-f32")]
+    #[test_case(ColorType::Rgb8, ImageFormat::Bmp => ColorType::Rgb8; "bmp")]
+    fn converts_to_encodable(color: ColorType, format: ImageFormat) -> ColorType {
+        let img = match color {
+            ColorType::Rgba16 => DynamicImage::new_rgba16(2, 2),
+            ColorType::Rgba32F => DynamicImage::new_rgba32f(2, 2),
+            ColorType::Rgb8 => DynamicImage::new_rgb8(2, 2),
+            _ => DynamicImage::new_rgba8(2, 2),
+        };
+        to_encodable(&img, format).color()
+    }
+
+    #[test_case(ImageFormat::Png; "png")]
+    #[cfg_attr(feature = "exr", test_case(ImageFormat::OpenExr; "exr"))]
+    #[cfg_attr(feature = "tiff", test_case(ImageFormat::Tiff; "tiff"))]
+    #[test_case(ImageFormat::Hdr; "hdr")]
+    fn saves_without_quantization(format: ImageFormat) {
+        // a value between two 8 bit steps
+        let value = 1000;
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            2,
+            2,
+            Rgba([value, value, value, u16::MAX]),
+        ));
+        let buf = encode_image(&img, format, &TEST_ENCODING, &ImageMetadata::default()).unwrap();
+        let out = load_from_memory_with_format(&buf, format).unwrap();
+        let c = out.to_rgba16().get_pixel(0, 0)[0];
+        assert!(c.abs_diff(value) <= 8, "{c} != {value}");
+    }
+
+    #[cfg_attr(feature = "tga", test_case(ImageFormat::Tga, ImgCompression::Default; "tga"))]
+    #[cfg_attr(feature = "tga", test_case(ImageFormat::Tga, ImgCompression::No; "tga-raw"))]
+    #[cfg_attr(feature = "qoi", test_case(ImageFormat::Qoi, ImgCompression::Default; "qoi"))]
+    #[cfg_attr(feature = "pnm", test_case(ImageFormat::Pnm, ImgCompression::Default; "pnm"))]
+    #[cfg_attr(feature = "tiff", test_case(ImageFormat::Tiff, ImgCompression::Default; "tiff"))]
+    #[test_case(ImageFormat::Bmp, ImgCompression::Default; "bmp")]
+    fn roundtrips_format(format: ImageFormat, compression: ImgCompression) {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(4, 4, |x, y| {
+            Rgba([x as u8 * 60, y as u8 * 60, 100, 255])
+        }));
+        let encoding = EncodingArgs {
+            compression,
+            ..TEST_ENCODING
+        };
+        let buf = encode_image(&img, format, &encoding, &ImageMetadata::default()).unwrap();
+        let out = load_from_memory_with_format(&buf, format).unwrap();
+        assert_eq!(out.to_rgba8(), img.to_rgba8());
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn encodes_avif() {
+        let img = DynamicImage::new_rgba16(8, 8);
+        let buf = encode_image(
+            &img,
+            ImageFormat::Avif,
+            &TEST_ENCODING,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!(&buf[4..12], b"ftypavif");
+    }
+
+    #[test_case(2; "1-bit")]
+    #[test_case(3; "2-bit")]
+    #[test_case(16; "4-bit")]
+    #[test_case(200; "8-bit")]
+    fn roundtrips_indexed_png(colors: u32) {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(13, 7, |x, y| {
+            let i = ((x + y * 13) % colors) as u8;
+            Rgba([i, 255 - i, i / 2, if i == 1 { 128 } else { 255 }])
+        }));
+        let encoding = EncodingArgs {
+            indexed: true,
+            ..TEST_ENCODING
+        };
+        let buf =
+            encode_image(&img, ImageFormat::Png, &encoding, &ImageMetadata::default()).unwrap();
+        let out = load_from_memory_with_format(&buf, ImageFormat::Png).unwrap();
+        assert_eq!(out.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn quantizes_indexed_png() {
+        let img = gradient(64, 64);
+        let (palette, indices) = quantize(&img.to_rgba8());
+        assert!(palette.len() <= 256);
+        assert_eq!(indices.len(), 64 * 64);
+
+        let encoding = EncodingArgs {
+            indexed: true,
+            ..TEST_ENCODING
+        };
+        let buf =
+            encode_image(&img, ImageFormat::Png, &encoding, &ImageMetadata::default()).unwrap();
+        let out = load_from_memory_with_format(&buf, ImageFormat::Png).unwrap();
+        assert_eq!(out.dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn uses_palette_for_max_compression() {
+        // scattered colors, where filters can't help
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(64, 64, |x, y| {
+            let i = (x * 7 + y * 13).wrapping_mul(2654435761) >> 28;
+            Rgba([i as u8 * 16, 255 - i as u8 * 16, 0, 255])
+        }));
+        let encode = |compression| {
+            let encoding = EncodingArgs {
+                compression,
+                ..TEST_ENCODING
+            };
+            encode_image(&img, ImageFormat::Png, &encoding, &ImageMetadata::default()).unwrap()
+        };
+        let (max, default) = (encode(ImgCompression::Max), encode(ImgCompression::Default));
+        assert!(max.len() < default.len());
+        let out = load_from_memory_with_format(&max, ImageFormat::Png).unwrap();
+        assert_eq!(out.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn max_png_compression_without_palette() {
+        let img = gradient(64, 64);
+        let encode = |compression| {
+            let encoding = EncodingArgs {
+                compression,
+                ..TEST_ENCODING
+            };
+            encode_image(&img, ImageFormat::Png, &encoding, &ImageMetadata::default()).unwrap()
+        };
+        assert!(encode(ImgCompression::Max).len() <= encode(ImgCompression::High).len());
+    }
+
+    #[test_case(ChromaSubsampling::S444; "444")]
+    #[test_case(ChromaSubsampling::S422; "422")]
+    #[test_case(ChromaSubsampling::S420; "420")]
+    fn encodes_jpeg_subsampling(chroma_subsampling: ChromaSubsampling) {
+        let encoding = EncodingArgs {
+            chroma_subsampling,
+            ..TEST_ENCODING
+        };
+        let buf = encode_image(
+            &gradient(17, 9),
+            ImageFormat::Jpeg,
+            &encoding,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let out = load_from_memory_with_format(&buf, ImageFormat::Jpeg).unwrap();
+        assert_eq!(out.dimensions(), (17, 9));
+    }
+
+    #[test]
+    fn jpeg_quality_overrides_compression() {
+        let encode = |quality| {
+            let encoding = EncodingArgs {
+                quality,
+                ..TEST_ENCODING
+            };
+            encode_image(
+                &gradient(64, 64),
+                ImageFormat::Jpeg,
+                &encoding,
+                &ImageMetadata::default(),
+            )
+            .unwrap()
+        };
+        assert!(encode(Some(5)).len() < encode(None).len());
+        assert!(encode(Some(100)).len() > encode(None).len());
+    }
+
+    #[cfg(feature = "webp-lossy")]
+    #[test]
+    fn encodes_lossy_webp() {
+        let meta = ImageMetadata {
+            exif: Some(crate::meta::tests::exif(false, 1)),
+            ..Default::default()
+        };
+        let encode = |quality| {
+            let encoding = EncodingArgs {
+                quality,
+                ..TEST_ENCODING
+            };
+            encode_image(&gradient(64, 64), ImageFormat::WebP, &encoding, &meta).unwrap()
+        };
+        let (lossy, lossless) = (encode(Some(50)), encode(None));
+        assert!(lossy.len() < lossless.len());
+        let mut decoder = webp::WebPDecoder::new(Cursor::new(&lossy)).unwrap();
+        assert_eq!(read_metadata(&mut decoder).unwrap(), meta);
+        let out = load_from_memory_with_format(&lossy, ImageFormat::WebP).unwrap();
+        assert_eq!(out.dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn formats_output_name_threshold() {
+        let calc = QuadArgs {
+            threshold: Some(Rgba([255, 0, 16, 1])),
+            min_quad_size: Vec2 { x: 8, y: 16 },
+            ..TEST_CALC
+        };
+        assert_eq!(
+            output_file_name("{threshold}_{size}", &PathBuf::from("a"), &None, &calc),
+            "ff001001_8x16"
+        )
     }
 }
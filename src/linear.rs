@@ -0,0 +1,227 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::utils::{Quad, QuadStructure, Vec2};
+
+/// deepest level a code can reach, with two bits for each
+pub const MAX_CODE_DEPTH: u8 = 32;
+
+/// locational code: the path from the root to a quad, and its depth
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LocCode {
+    /// index of the child at each level, x then y bit, left aligned
+    /// so ancestors sort right before their descendants, in Z-order
+    pub path: u64,
+    pub depth: u8,
+}
+
+/// the leaves of a structure sorted by their locational codes
+pub struct LinearQuadTree<P> {
+    pub leaves: Vec<(LocCode, Quad<P>)>,
+    /// as in `QuadStructure`
+    pub sizes: Vec<Vec2>,
+}
+
+impl LocCode {
+    /// code of the quad at the position and depth, in an image of the given size
+    pub fn new(pos: &Vec2, depth: u8, bounds: &Vec2) -> Self {
+        debug_assert!(depth <= MAX_CODE_DEPTH);
+        let (mut corner, mut size) = (Vec2::ZERO, *bounds);
+        let mut path = 0;
+        for level in 1..=depth {
+            let (first, second) = size.split();
+            // the second ones are empty once the region is a pixel wide
+            let right = second.x > 0 && pos.x >= corner.x + first.x;
+            let bottom = second.y > 0 && pos.y >= corner.y + first.y;
+            let child = right as u64 | (bottom as u64) << 1;
+            (corner, size) = descend(corner, size, child);
+            path |= child << shift(level);
+        }
+        Self { path, depth }
+    }
+
+    /// code of a single pixel, deep enough to tell it from all the others
+    pub fn pixel(pos: &Vec2, bounds: &Vec2) -> Self {
+        let depth = 32 - bounds.x.max(bounds.y).saturating_sub(1).leading_zeros();
+        Self::new(pos, depth as u8, bounds)
+    }
+
+    /// position and size of the quad
+    pub fn rect(&self, bounds: &Vec2) -> (Vec2, Vec2) {
+        (1..=self.depth).fold((Vec2::ZERO, *bounds), |(corner, size), level| {
+            descend(corner, size, (self.path >> shift(level)) & 0b11)
+        })
+    }
+
+    /// position of the top left corner of the quad
+    pub fn pos(&self, bounds: &Vec2) -> Vec2 {
+        self.rect(bounds).0
+    }
+}
+
+/// region of the child, split as in the subdivision
+fn descend(corner: Vec2, size: Vec2, child: u64) -> (Vec2, Vec2) {
+    let (first, second) = size.split();
+    let (right, bottom) = (child & 0b01 != 0, child & 0b10 != 0);
+    (
+        Vec2 {
+            x: corner.x + right as u32 * first.x,
+            y: corner.y + bottom as u32 * first.y,
+        },
+        Vec2 {
+            x: if right { second.x } else { first.x },
+            y: if bottom { second.y } else { first.y },
+        },
+    )
+}
+
+fn shift(level: u8) -> u32 {
+    2 * (MAX_CODE_DEPTH - level) as u32
+}
+
+impl<P> LinearQuadTree<P> {
+    /// positions and quads in Z-order
+    pub fn iter(&self) -> impl Iterator<Item = (Vec2, &Quad<P>)> {
+        self.leaves
+            .iter()
+            .map(|(code, quad)| (code.pos(&self.sizes[0]), quad))
+    }
+}
+
+impl<P: Copy> From<&QuadStructure<P>> for LinearQuadTree<P> {
+    fn from(structure: &QuadStructure<P>) -> Self {
+        let bounds = structure.sizes[0];
+        let mut leaves: Vec<_> = structure
+            .map
+            .iter()
+            .map(|(pos, quad)| {
+                let code = LocCode::new(pos, quad.depth.min(MAX_CODE_DEPTH), &bounds);
+                // the quads of the other partitions aren't on the path of halved ones
+                let rect = (*pos, structure.size_of(quad));
+                match code.rect(&bounds) == rect {
+                    true => (code, quad.clone()),
+                    false => (LocCode::pixel(pos, &bounds), quad.clone()),
+                }
+            })
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        Self {
+            leaves,
+            sizes: structure.sizes.clone(),
+        }
+    }
+}
+
+impl<P> From<LinearQuadTree<P>> for QuadStructure<P> {
+    fn from(linear: LinearQuadTree<P>) -> Self {
+        let bounds = linear.sizes[0];
+        Self {
+            map: linear
+                .leaves
+                .into_iter()
+                .map(|(code, quad)| (code.pos(&bounds), quad))
+                .collect(),
+            sizes: linear.sizes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::QuadMap;
+    use image::Rgba;
+    pub use test_case::test_case;
+
+    const BOUNDS: Vec2 = Vec2 { x: 5, y: 5 };
+
+    #[test_case(Vec2{x:0,y:0},0,0; "root")]
+    #[test_case(Vec2{x:3,y:0},1,0b01 << 62; "right")]
+    #[test_case(Vec2{x:0,y:3},1,0b10 << 62; "bottom")]
+    #[test_case(Vec2{x:4,y:3},2,0b11 << 62 | 0b01 << 60; "nested")]
+    fn encodes_path(pos: Vec2, depth: u8, path: u64) {
+        let code = LocCode::new(&pos, depth, &BOUNDS);
+        assert_eq!(code, LocCode { path, depth });
+        assert_eq!(code.pos(&BOUNDS), pos);
+    }
+
+    #[test_case(Vec2{x:5,y:5}; "odd")]
+    #[test_case(Vec2{x:16,y:3}; "wide")]
+    #[test_case(Vec2{x:1,y:1}; "pixel")]
+    fn decodes_every_pixel(bounds: Vec2) {
+        let mut codes = Vec::new();
+        for y in 0..bounds.y {
+            for x in 0..bounds.x {
+                let code = LocCode::pixel(&Vec2 { x, y }, &bounds);
+                assert_eq!(code.pos(&bounds), Vec2 { x, y });
+                codes.push(code);
+            }
+        }
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), (bounds.x * bounds.y) as usize);
+    }
+
+    /// a quad with its exact size, when it isn't the halved one
+    fn quad(depth: u8, size: Option<(u32, u32)>) -> Quad<Rgba<u8>> {
+        Quad {
+            depth,
+            color: None,
+            size: size.map(Vec2::from),
+        }
+    }
+
+    #[test]
+    fn sorts_in_z_order() {
+        // the first quads take the remainder of the odd sizes
+        let structure = QuadStructure {
+            map: QuadMap::from([
+                (Vec2 { x: 3, y: 3 }, quad(1, None)),
+                (Vec2 { x: 0, y: 3 }, quad(1, Some((3, 2)))),
+                (Vec2 { x: 4, y: 2 }, quad(2, None)),
+                (Vec2 { x: 3, y: 2 }, quad(2, None)),
+                (Vec2 { x: 4, y: 0 }, quad(2, Some((1, 2)))),
+                (Vec2 { x: 3, y: 0 }, quad(2, Some((1, 2)))),
+                (Vec2 { x: 0, y: 0 }, quad(1, Some((3, 3)))),
+            ]),
+            sizes: vec![BOUNDS, Vec2 { x: 2, y: 2 }, Vec2 { x: 1, y: 1 }],
+        };
+        let linear = LinearQuadTree::from(&structure);
+        assert!(linear.leaves.iter().all(|(c, q)| c.depth == q.depth));
+        let order: Vec<_> = linear.iter().map(|(pos, _)| (pos.x, pos.y)).collect();
+        assert_eq!(
+            order,
+            vec![(0, 0), (3, 0), (4, 0), (3, 2), (4, 2), (0, 3), (3, 3)]
+        );
+        assert_eq!(QuadStructure::from(linear).map, structure.map);
+    }
+
+    #[test]
+    fn keeps_own_sizes() {
+        let quad = |x, y, w, h| {
+            let mut q: Quad<Rgba<u8>> = Quad::new(1);
+            q.size = Some(Vec2 { x: w, y: h });
+            (Vec2 { x, y }, q)
+        };
+        // a binary partition of a 6x4 image
+        let structure = QuadStructure {
+            map: QuadMap::from([quad(3, 0, 3, 4), quad(0, 2, 3, 2), quad(0, 0, 3, 2)]),
+            sizes: vec![Vec2 { x: 6, y: 4 }],
+        };
+        let linear = LinearQuadTree::from(&structure);
+        let order: Vec<_> = linear.iter().map(|(pos, _)| (pos.x, pos.y)).collect();
+        assert_eq!(order, vec![(0, 0), (3, 0), (0, 2)]);
+        assert_eq!(QuadStructure::from(linear).map, structure.map);
+    }
+}
@@ -13,53 +13,235 @@
  * limitations under the License.
  */
 mod args;
+mod cms;
+mod codec;
+mod config;
 mod drawing;
+mod edges;
+mod entropy;
 mod io;
+mod linear;
+mod mask;
+mod meta;
+mod metrics;
+mod palette;
+mod partition;
 mod quad;
+mod query;
+mod search;
+mod shapes;
+mod tree;
 mod utils;
 
 use crate::args::*;
-use crate::drawing::{draw_quads, draw_quads_squares, ImageCache};
+use crate::cms::*;
+use crate::drawing::{draw_quads, draw_quads_squares, draw_shapes, ImageCache};
+use crate::edges::EdgeMap;
+use crate::entropy::EntropyMap;
 use crate::io::*;
+use crate::mask::RegionMask;
+use crate::meta::ImageMetadata;
+use crate::metrics::{mse, psnr, reconstruction, visible_leaves, ErrorMetrics, Stats};
+use crate::palette::{apply_palette, build_palette, ColorRegions};
+use crate::partition::{fitting_cell_side, padded_size, CellEdges, Partition};
 use crate::quad::*;
-use clap::Parser;
-use image::{DynamicImage, ImageError};
-use log::{debug, error, info};
+use crate::search::{describe, search, Target};
+use crate::shapes::{calc_shapes, Hexagon, Shape, Triangle};
+use crate::utils::{scale_color, PixelBuffer, QuadStructure, RgbaPixel, Vec2};
+use image::{
+    ColorType, DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageError, Rgba,
+};
+use log::{debug, error, info, warn};
 use simplelog::*;
-use std::io::{Error, ErrorKind};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // initialization
-    let cli = CliArgs::parse();
+    let cli = config::parse_args();
 
     // logging
-    SimpleLogger::init(
-        match cli.verbose {
-            0 => LevelFilter::Error,
-            1 => LevelFilter::Info,
-            2 => LevelFilter::Debug,
-            (3..=u8::MAX) => LevelFilter::Trace,
-        },
-        ConfigBuilder::default()
-            .set_time_level(LevelFilter::Off)
-            .set_level_padding(LevelPadding::Right)
-            .set_thread_level(LevelFilter::Off)
-            .set_target_level(LevelFilter::Off)
-            .set_location_level(LevelFilter::Off)
-            .build(),
-    )?;
+    let log_level = match cli.verbose {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        (3..=u8::MAX) => LevelFilter::Trace,
+    };
+    let log_config = ConfigBuilder::default()
+        .set_time_level(LevelFilter::Off)
+        .set_level_padding(LevelPadding::Right)
+        .set_thread_level(LevelFilter::Off)
+        .set_target_level(LevelFilter::Off)
+        .set_location_level(LevelFilter::Off)
+        .build();
+    if cli.command.writes_stdout() {
+        // stdout is reserved for the output
+        WriteLogger::init(log_level, log_config, std::io::stderr())?;
+    } else {
+        SimpleLogger::init(log_level, log_config)?;
+    }
+
+    match cli.command {
+        Commands::Render(render_args) => render(render_args)?,
+        Commands::Export(export_args) => export(&export_args)?,
+        Commands::Encode(encode_args) => encode(&encode_args)?,
+        Commands::Decode(decode_args) => decode(&decode_args)?,
+        Commands::Stats(stats_args) => stats(&stats_args)?,
+        Commands::Compare(compare_args) => compare(&compare_args)?,
+    }
+
+    info!("DONE \\[T]/");
+    Ok(())
+}
+
+fn render(mut cli: Box<RenderArgs>) -> Result<(), Box<dyn std::error::Error>> {
+    // colors are given in sRGB
+    if let Some(ref working) = cli.io.working_space {
+        let srgb = moxcms::ColorProfile::new_srgb();
+        for c in [&mut cli.image.color, &mut cli.image.background]
+            .into_iter()
+            .flatten()
+            .chain(cli.image.palette.iter_mut())
+        {
+            *c = convert_color(c, &srgb, working)?;
+        }
+    }
 
     if let 0 = check_rank(&cli.io)? {
         single_image(&cli)?
     } else {
         multiple_images(&cli)?
     }
-
-    info!("DONE \\[T]/");
     Ok(())
 }
 
+fn export(cli: &ExportArgs) -> Result<(), ImageError> {
+    if cli.format == ExportFormat::Binary && (cli.at.is_some() || cli.neighbors) {
+        error!("looking up leaves is only supported by JSON exports!");
+        return Err(Error::from(ErrorKind::Unsupported).into());
+    }
+    let structure = filled_quads(&cli.input, &cli.calc)?;
+    let buf = match cli.format {
+        ExportFormat::Json => {
+            codec::to_json(&structure, cli.at.as_ref(), cli.neighbors).into_bytes()
+        }
+        ExportFormat::Binary => codec::encode(&structure, false),
+    };
+    Ok(write_output(&cli.output, &buf)?)
+}
+
+fn encode(cli: &EncodeArgs) -> Result<(), ImageError> {
+    let structure = filled_quads(&cli.input, &cli.calc)?;
+    Ok(write_output(&cli.output, &codec::encode(&structure, true))?)
+}
+
+fn decode(cli: &DecodeArgs) -> Result<(), ImageError> {
+    let mut structure = codec::decode(&read_input(&cli.input)?)?;
+    reduce_colors(&mut structure, &cli.image);
+    let img_fill_with = load_filler(&cli.image, &None)?;
+
+    info!("generating output image");
+    let img = draw_quads(
+        &structure,
+        &cli.image.color,
+        &cli.image.background,
+        cli.image.fill,
+        &img_fill_with,
+        &mut ImageCache::new(),
+    );
+    save_image(
+        &DynamicImage::ImageRgba8(img),
+        &cli.output,
+        &cli.output_format,
+        &cli.encoding,
+        &ImageMetadata::default(),
+    )
+}
+
+fn stats(cli: &StatsArgs) -> Result<(), ImageError> {
+    quads_only(&cli.calc)?;
+    let (img_in, _) = load_image_with_metadata(&cli.input)?;
+    let mask = load_mask(&cli.calc, img_in.dimensions())?;
+    let Source { img, mask, size } = pad_to_cells(img_in, mask, &cli.calc);
+
+    // high bit depth media are measured without quantization
+    let stats = match img.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => stats_as(
+            &Rgba::<u16>::to_buffer(&img),
+            mask.as_ref(),
+            &size,
+            &cli.calc,
+        ),
+        ColorType::Rgb32F | ColorType::Rgba32F => stats_as(
+            &Rgba::<f32>::to_buffer(&img),
+            mask.as_ref(),
+            &size,
+            &cli.calc,
+        ),
+        _ => stats_as(&img, mask.as_ref(), &size, &cli.calc),
+    };
+    let report = stats.format(&cli.input.to_string_lossy(), cli.format);
+    Ok(std::io::stdout().write_all(report.as_bytes())?)
+}
+
+fn compare(cli: &CompareArgs) -> Result<(), ImageError> {
+    let (img_in, _) = load_image_with_metadata(&cli.input)?;
+    let (img_ref, _) = load_image_with_metadata(&cli.reference)?;
+    if img_in.dimensions() != img_ref.dimensions() {
+        error!("input and reference have different sizes!");
+        return Err(Error::from(ErrorKind::InvalidInput).into());
+    }
+    // 16 bits per channel keep 8 bit media exact
+    let metrics = ErrorMetrics::new(&img_in.to_rgba16(), &img_ref.to_rgba16());
+    let report = metrics.format(
+        &cli.input.to_string_lossy(),
+        &cli.reference.to_string_lossy(),
+        cli.format,
+    );
+    Ok(std::io::stdout().write_all(report.as_bytes())?)
+}
+
+/// the quads of the partitions of shapes can't be exported nor measured
+fn quads_only(calc: &QuadArgs) -> Result<(), Error> {
+    match calc.partition.rects() {
+        Some(_) => Ok(()),
+        None => {
+            error!(
+                "this command is not supported by the {:?} partition!",
+                calc.partition
+            );
+            Err(Error::from(ErrorKind::Unsupported))
+        }
+    }
+}
+
+/// the quads of the media, with their colors
+fn filled_quads(input: &PathBuf, calc: &QuadArgs) -> Result<QuadStructure, ImageError> {
+    quads_only(calc)?;
+    let (img_in, _) = load_image_with_metadata(input)?;
+    let mask = load_mask(calc, img_in.dimensions())?;
+    let Source { img, mask, .. } = pad_to_cells(img_in, mask, calc);
+    info!("calculating quads");
+    Ok(quad_structure(&img, mask.as_ref(), calc, true))
+}
+
+/// the statistics of the quads of the image of the given size, without drawing them
+fn stats_as<I, P>(source: &I, mask: Option<&RegionMask>, size: &Vec2, calc: &QuadArgs) -> Stats
+where
+    I: GenericImage<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    info!("calculating quads");
+    let now = Instant::now();
+    let structure = quad_structure(source, mask, calc, false);
+    let calc_time = now.elapsed();
+    let mut stats = Stats::new(source, &structure, size, calc.aggregation);
+    stats.timings.calc = calc_time;
+    stats
+}
+
 fn check_rank(io: &IOArgs) -> Result<u8, Error> {
     if io.input.is_dir() {
         // folder
@@ -74,15 +256,19 @@ fn check_rank(io: &IOArgs) -> Result<u8, Error> {
             error!("input is a file, but output is a directory!");
             return Err(Error::from(ErrorKind::InvalidInput));
         }
+        if is_stdio(&io.output) && io.output_format.is_none() {
+            error!("writing to stdout requires an output format!");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
         Ok(0)
     }
 }
 
-fn multiple_images(cli: &CliArgs) -> Result<(), ImageError> {
+fn multiple_images(cli: &RenderArgs) -> Result<(), ImageError> {
     let mut cache = ImageCache::new();
 
     // load additional image
-    let img_fill_with = load_filler(&cli.image)?;
+    let img_fill_with = load_filler(&cli.image, &cli.io.working_space)?;
 
     // load all images into iterator
     let inputs = cli
@@ -92,94 +278,514 @@ fn multiple_images(cli: &CliArgs) -> Result<(), ImageError> {
         .filter(|dres| dres.as_ref().unwrap().path().is_file())
         .flatten();
 
+    let mut written = HashSet::new();
     for entry in inputs {
         // load source image to process
-        let img_in = match load_image(&entry.path()) {
+        let (img_in, meta) = match load_image_with_metadata(&entry.path()) {
             Ok(img) => img,
             Err(error) => panic!("problem opening input image: {error:?}"),
         };
+        let img_in = to_working_space(img_in, &meta.icc, &cli.io.working_space);
+        let mask = load_mask(&cli.calc, img_in.dimensions())?;
+        let source = pad_to_cells(img_in, mask, &cli.calc);
+        let meta = kept_metadata(&cli.io, meta);
 
         // process
-        let img_out =
-            generate_quadtree_image(&img_in, &img_fill_with, &cli.calc, &cli.image, &mut cache);
+        let calc = searched_calc(
+            &source,
+            &img_fill_with,
+            &entry.path(),
+            cli,
+            &meta,
+            &mut cache,
+        );
+        let (img_out, stats) = generate_quadtree_image(
+            &source,
+            &img_fill_with,
+            &calc,
+            &cli.image,
+            cli.io.stats.is_some(),
+            &mut cache,
+        );
+        let img_out = to_output_space(source.unpadded(img_out), &cli.io);
 
         // save processed image
+        let path_out = cli.io.output.join(output_file_name(
+            &cli.io.output_name,
+            &entry.path(),
+            &cli.io.output_format,
+            &calc,
+        ));
+        if !written.insert(path_out.clone()) {
+            warn!(
+                "'{}' was already written in this batch and will be overwritten",
+                path_out.to_string_lossy()
+            );
+        }
+        let now = Instant::now();
         match save_image(
             &img_out,
-            &cli.io.output.join(entry.file_name()),
-            &cli.io.compression,
+            &path_out,
+            &cli.io.output_format,
+            &cli.io.encoding,
+            &meta,
         ) {
             Ok(_) => {}
             Err(error) => panic!("cannot save image: {error:?}"),
         }
+        report(stats, now, &entry.path(), &cli.io)?;
     }
 
     Ok(())
 }
 
-fn single_image(cli: &CliArgs) -> Result<(), ImageError> {
+fn single_image(cli: &RenderArgs) -> Result<(), ImageError> {
     // load source image to process
-    let img_in = load_image(&cli.io.input)?;
+    let (img_in, meta) = load_image_with_metadata(&cli.io.input)?;
+    let img_in = to_working_space(img_in, &meta.icc, &cli.io.working_space);
 
-    // load additional image
-    let img_fill_with = load_filler(&cli.image)?;
+    // load additional images
+    let img_fill_with = load_filler(&cli.image, &cli.io.working_space)?;
+    let mask = load_mask(&cli.calc, img_in.dimensions())?;
+    let source = pad_to_cells(img_in, mask, &cli.calc);
+    let meta = kept_metadata(&cli.io, meta);
 
     // process
-    let img_out = generate_quadtree_image(
-        &img_in,
+    let mut cache = ImageCache::new();
+    let calc = searched_calc(
+        &source,
         &img_fill_with,
-        &cli.calc,
+        &cli.io.input,
+        cli,
+        &meta,
+        &mut cache,
+    );
+    let (img_out, stats) = generate_quadtree_image(
+        &source,
+        &img_fill_with,
+        &calc,
         &cli.image,
-        &mut ImageCache::new(),
+        cli.io.stats.is_some(),
+        &mut cache,
     );
+    let img_out = to_output_space(source.unpadded(img_out), &cli.io);
 
     // save processed image
-    save_image(&img_out, &cli.io.output, &cli.io.compression)?;
+    let now = Instant::now();
+    save_image(
+        &img_out,
+        &cli.io.output,
+        &cli.io.output_format,
+        &cli.io.encoding,
+        &meta,
+    )?;
+    report(stats, now, &cli.io.input, &cli.io)?;
 
     Ok(())
 }
 
+/// prints the statistics of the image, saved since the given instant
+fn report(stats: Option<Stats>, saving: Instant, input: &Path, io: &IOArgs) -> Result<(), Error> {
+    let (Some(mut stats), Some(format)) = (stats, io.stats) else {
+        return Ok(());
+    };
+    stats.timings.save = saving.elapsed();
+    let report = stats.format(&input.to_string_lossy(), format);
+    match is_stdio(&io.output) {
+        // stdout is reserved for the output image
+        true => std::io::stderr().write_all(report.as_bytes()),
+        false => std::io::stdout().write_all(report.as_bytes()),
+    }
+}
+
+/// the arguments of the calculation, with the threshold searched for the target if any
+fn searched_calc(
+    source: &Source,
+    img_fill_with: &Option<DynamicImage>,
+    input: &Path,
+    cli: &RenderArgs,
+    meta: &ImageMetadata,
+    cache: &mut ImageCache,
+) -> QuadArgs {
+    let Some(target) = cli.search.target() else {
+        return cli.calc.clone();
+    };
+    let shapes = matches!(cli.calc.partition, Partition::Triangle | Partition::Hexagon);
+    if shapes && !matches!(target, Target::FileSize(_)) {
+        warn!("searching the threshold for {target:?} is not supported by this partition");
+        return cli.calc.clone();
+    }
+    info!("searching the threshold for {target:?}");
+    // the extension of the output doesn't depend on the searched arguments
+    let path_out = match cli.io.output.is_dir() {
+        true => cli.io.output.join(output_file_name(
+            &cli.io.output_name,
+            input,
+            &cli.io.output_format,
+            &cli.calc,
+        )),
+        false => cli.io.output.clone(),
+    };
+    let (w, h) = source.img.dimensions();
+    let calc = search(
+        &cli.calc,
+        target,
+        cli.search.search_min_size,
+        w.max(h),
+        |calc| match target {
+            // neither needs the image to be drawn
+            Target::Leaves(_) | Target::Psnr(_) => measured(source, calc, &cli.image, target),
+            Target::FileSize(_) => {
+                let (img, _) =
+                    generate_quadtree_image(source, img_fill_with, calc, &cli.image, false, cache);
+                let img = to_output_space(source.unpadded(img), &cli.io);
+                let size = encoded_size(
+                    &img,
+                    &path_out,
+                    &cli.io.output_format,
+                    &cli.io.encoding,
+                    meta,
+                );
+                size.map_or_else(
+                    |error| {
+                        error!("cannot encode image: {error:?}");
+                        f64::INFINITY
+                    },
+                    |size| size as f64,
+                )
+            }
+        },
+    );
+    // printed even without -v, it's the result of the search; stdout is for the stats
+    eprintln!(
+        "searched for '{}': {}",
+        input.to_string_lossy(),
+        describe(&calc)
+    );
+    calc
+}
+
+/// the leaves or the PSNR of the quads of the source, as searched for
+fn measured(source: &Source, calc: &QuadArgs, draw: &DrawingArgs, target: Target) -> f64 {
+    let (mask, size) = (source.mask.as_ref(), &source.size);
+    match source.img.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            let img = Rgba::<u16>::to_buffer(&source.img);
+            measured_as(&img, mask, size, calc, draw, target)
+        }
+        ColorType::Rgb32F | ColorType::Rgba32F => {
+            let img = Rgba::<f32>::to_buffer(&source.img);
+            measured_as(&img, mask, size, calc, draw, target)
+        }
+        _ => measured_as(&source.img, mask, size, calc, draw, target),
+    }
+}
+
+/// as `measured`, of the image of the given size at the top left of the source
+fn measured_as<I, P>(
+    source: &I,
+    mask: Option<&RegionMask>,
+    size: &Vec2,
+    calc: &QuadArgs,
+    draw: &DrawingArgs,
+    target: Target,
+) -> f64
+where
+    I: GenericImage<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    let mut structure = quad_structure(source, mask, calc, draw.fill);
+    if let Target::Leaves(_) = target {
+        return visible_leaves(&structure, size).count() as f64;
+    }
+    // as the statistics measure it, without the SSIM
+    reduce_colors(&mut structure, draw);
+    let rebuilt = reconstruction(source, &structure, calc.aggregation);
+    psnr(mse(
+        &*source.view(0, 0, size.x, size.y),
+        &*rebuilt.view(0, 0, size.x, size.y),
+    ))
+}
+
+/// the metadata to write in the output, empty unless asked to keep it.
+/// Color managed outputs are always tagged with their profile
+fn kept_metadata(io: &IOArgs, meta: ImageMetadata) -> ImageMetadata {
+    let mut kept = match io.keep_metadata {
+        true => meta,
+        false => ImageMetadata::default(),
+    };
+    if let Some(icc) = output_icc(io) {
+        kept.icc = Some(icc);
+    }
+    kept
+}
+
+/// the media to draw and its mask, padded to whole square cells if asked to
+struct Source {
+    img: DynamicImage,
+    mask: Option<RegionMask>,
+    /// size before padding
+    size: Vec2,
+}
+
+impl Source {
+    /// crops the output drawn from the padded media back to the size of the media
+    fn unpadded(&self, img: DynamicImage) -> DynamicImage {
+        match Vec2::from(img.dimensions()) == self.size {
+            true => img,
+            false => img.crop_imm(0, 0, self.size.x, self.size.y),
+        }
+    }
+}
+
+/// pads the image and the mask to whole square cells, if asked to
+fn pad_to_cells(img: DynamicImage, mask: Option<RegionMask>, calc: &QuadArgs) -> Source {
+    let size = Vec2::from(img.dimensions());
+    if calc.partition != Partition::Square || calc.cell_edges != CellEdges::Pad {
+        return Source { img, mask, size };
+    }
+    let padded = padded_size(&size, fitting_cell_side(&size, calc.cell_size));
+    if padded == size {
+        return Source { img, mask, size };
+    }
+    debug!("padding image from {size} to {padded}");
+    let mut out = DynamicImage::new(padded.x, padded.y, img.color());
+    out.copy_from(&img, 0, 0).unwrap();
+    Source {
+        img: out,
+        mask: mask.map(|m| m.padded(&padded)),
+        size,
+    }
+}
+
+/// the output image and its statistics, if asked for
 fn generate_quadtree_image(
-    source: &DynamicImage,
+    source: &Source,
     img_fill_with: &Option<DynamicImage>,
     calc: &QuadArgs,
     draw: &DrawingArgs,
+    stats: bool,
     cache: &mut ImageCache,
-) -> DynamicImage {
-    info!("calculating quads");
-    let now = Instant::now();
+) -> (DynamicImage, Option<Stats>) {
+    // high bit depth media are processed without quantization,
+    // everything else keeps being drawn over a copy of itself
+    match source.img.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            generate_quadtree_image_as(
+                &Rgba::<u16>::to_buffer(&source.img),
+                source,
+                img_fill_with,
+                calc,
+                draw,
+                stats,
+                cache,
+            )
+        }
+        ColorType::Rgb32F | ColorType::Rgba32F => generate_quadtree_image_as(
+            &Rgba::<f32>::to_buffer(&source.img),
+            source,
+            img_fill_with,
+            calc,
+            draw,
+            stats,
+            cache,
+        ),
+        _ => {
+            generate_quadtree_image_as(&source.img, source, img_fill_with, calc, draw, stats, cache)
+        }
+    }
+}
 
-    let structure = calc_quads(
+/// what guides the subdivision of the image
+fn guides<'a, I, P>(source: &I, mask: Option<&'a RegionMask>, calc: &QuadArgs) -> Guides<'a>
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    Guides {
+        mask,
+        // the shapes don't read it
+        partition: calc.partition.rects().unwrap_or_default(),
+        cell_size: calc.cell_size,
+        split: match calc.criterion {
+            Criterion::Color => SplitBy::Color(ColorDistance {
+                distance: calc.distance,
+                luma: calc.luma_standard,
+                weights: calc.channel_weights,
+                ignore_alpha: calc.ignore_alpha,
+                threshold: calc.distance_threshold,
+            }),
+            Criterion::Edges => SplitBy::Edges(
+                EdgeMap::new(source, calc.edge_operator),
+                calc.edge_threshold,
+            ),
+            Criterion::Entropy => SplitBy::Entropy(EntropyMap::new(source), calc.entropy_threshold),
+        },
+    }
+}
+
+/// reduces the fill colors to the generated or given palette
+fn reduce_colors<P: RgbaPixel>(structure: &mut impl ColorRegions<P>, draw: &DrawingArgs) {
+    let palette: Vec<P> = match draw.palette_size {
+        Some(size) => build_palette(structure, size as usize, draw.quantizer),
+        None => draw.palette.iter().map(scale_color).collect(),
+    };
+    if !palette.is_empty() {
+        debug!("reducing the fill colors to {} colors", palette.len());
+        apply_palette(structure, &palette);
+    }
+}
+
+/// subdivides the image into quads, calculating the colors of all of them if asked to
+fn quad_structure<I, P>(
+    source: &I,
+    mask: Option<&RegionMask>,
+    calc: &QuadArgs,
+    colors: bool,
+) -> QuadStructure<P>
+where
+    I: GenericImage<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    let now = Instant::now();
+    let tree = calc_quads(
         source,
         &calc.min_quad_size,
         calc.min_depth,
-        &calc.threshold.unwrap_or(DEFAULT_TRESHOLD),
-        draw.fill,
+        &scale_color(&calc.threshold.unwrap_or(DEFAULT_TRESHOLD)),
+        colors,
+        calc.aggregation,
+        &guides(source, mask, calc),
+    );
+    debug!(
+        "built a tree of {} nodes from {} roots",
+        tree.len(),
+        tree.roots().len()
     );
+    let structure = QuadStructure::from(tree);
 
     debug!(
         "subdivided image into {} quads over {} recursions in {:.3?}",
         structure.map.len(),
-        structure.sizes.len() - 1,
+        structure.depth(),
+        now.elapsed()
+    );
+    structure
+}
+
+fn generate_shapes_image<S, I, P>(
+    source: &I,
+    mask: Option<&RegionMask>,
+    calc: &QuadArgs,
+    draw: &DrawingArgs,
+) -> DynamicImage
+where
+    S: Shape,
+    I: GenericImage<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    let now = Instant::now();
+    let mut structure = calc_shapes::<S, _, _>(
+        source,
+        &calc.min_quad_size,
+        calc.min_depth,
+        &scale_color(&calc.threshold.unwrap_or(DEFAULT_TRESHOLD)),
+        draw.fill,
+        calc.aggregation,
+        &guides(source, mask, calc),
+    );
+    debug!(
+        "subdivided image into {} shapes over {} recursions in {:.3?}",
+        structure.cells.len(),
+        structure.depth(),
         now.elapsed()
     );
+    reduce_colors(&mut structure, draw);
+
+    info!("generating output image");
+    if draw.fill_with.is_some() {
+        warn!("filling with an image is not supported by this partition");
+    }
+    let (w, h) = source.dimensions();
+    let mut img: PixelBuffer<P> = if draw.no_drawover || draw.fill || draw.fill_with.is_some() {
+        match draw.background {
+            Some(c) => ImageBuffer::from_pixel(w, h, scale_color(&c)),
+            None => ImageBuffer::new(w, h),
+        }
+    } else {
+        ImageBuffer::from_fn(w, h, |x, y| source.get_pixel(x, y))
+    };
+    draw_shapes(
+        &mut img,
+        &structure,
+        &draw.color.map(|c| scale_color(&c)),
+        draw.fill,
+    );
+    debug!("image generated in {:.3?} total", now.elapsed());
+    P::into_dynamic(img)
+}
+
+/// as `generate_quadtree_image`, over the source converted to `img`
+fn generate_quadtree_image_as<I, P>(
+    img: &I,
+    source: &Source,
+    img_fill_with: &Option<DynamicImage>,
+    calc: &QuadArgs,
+    draw: &DrawingArgs,
+    stats: bool,
+    cache: &mut ImageCache,
+) -> (DynamicImage, Option<Stats>)
+where
+    I: GenericImage<Pixel = P> + Clone + Sync + Into<DynamicImage>,
+    P: RgbaPixel,
+{
+    let mask = source.mask.as_ref();
+    info!("calculating quads");
+    let now = Instant::now();
+
+    if stats && matches!(calc.partition, Partition::Triangle | Partition::Hexagon) {
+        warn!("statistics are not supported by this partition");
+    }
+    match calc.partition {
+        Partition::Triangle => {
+            let img = generate_shapes_image::<Triangle, _, _>(img, mask, calc, draw);
+            return (img, None);
+        }
+        Partition::Hexagon => {
+            let img = generate_shapes_image::<Hexagon, _, _>(img, mask, calc, draw);
+            return (img, None);
+        }
+        _ => {}
+    }
+    let mut structure = quad_structure(img, mask, calc, draw.fill);
+    reduce_colors(&mut structure, draw);
+    let calc_time = now.elapsed();
+    let color = draw.color.map(|c| scale_color(&c));
     // if a new image has to be generated, recoloring needs to be applied or
     // if the filler image is not None, use the full version of the
     // drawing fn, otherwise simplify
     info!("generating output image");
-    let img = if draw.no_drawover || draw.fill || draw.fill_with.is_some() {
-        draw_quads(
+    let drawn = if draw.no_drawover || draw.fill || draw.fill_with.is_some() {
+        P::into_dynamic(draw_quads(
             &structure,
-            &draw.color,
-            &draw.background,
+            &color,
+            &draw.background.map(|c| scale_color(&c)),
             draw.fill,
             img_fill_with,
             cache,
-        )
+        ))
     } else {
-        draw_quads_squares(source, &structure, &draw.color)
+        draw_quads_squares(img, &structure, &color).into()
     };
 
     debug!("image generated in {:.3?} total", now.elapsed());
-    img
+    let draw_time = now.elapsed() - calc_time;
+
+    let stats = stats.then(|| {
+        let mut stats = Stats::new(img, &structure, &source.size, calc.aggregation);
+        stats.timings.calc = calc_time;
+        stats.timings.draw = draw_time;
+        stats
+    });
+    (drawn, stats)
 }
@@ -0,0 +1,142 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::{GenericImage, GenericImageView, GrayImage};
+
+use crate::utils::Vec2;
+
+/// how much larger the minimum quad size gets in fully black regions
+const MIN_SIZE_FACTOR: f64 = 4.0;
+/// mask values below this are masked out when excluding
+const EXCLUDE_BELOW: u8 = 128;
+
+/// how the region of interest mask affects the subdivision
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum MaskMode {
+    /// Darker regions get a higher threshold, minimum size and a lower minimum depth
+    Modulate,
+    /// Quads entirely in dark regions are never split
+    Exclude,
+}
+
+/// grayscale mask of the regions of interest
+pub struct RegionMask {
+    mode: MaskMode,
+    mask: GrayImage,
+}
+
+impl RegionMask {
+    /// the mask has to be as big as the image it's used with
+    pub fn new(mask: GrayImage, mode: MaskMode) -> Self {
+        Self { mode, mask }
+    }
+
+    /// grows the mask to the given size, the new regions are not of interest
+    pub fn padded(self, size: &Vec2) -> Self {
+        let mut mask = GrayImage::new(size.x, size.y);
+        mask.copy_from(&self.mask, 0, 0).unwrap();
+        Self { mask, ..self }
+    }
+
+    pub fn mode(&self) -> MaskMode {
+        self.mode
+    }
+
+    /// highest intensity of the region from 0 to 1, as any region of interest in it
+    /// has to be subdivided
+    pub fn weight(&self, pos: &Vec2, size: &Vec2) -> f64 {
+        self.max(pos, size) as f64 / u8::MAX as f64
+    }
+
+    /// if the region is entirely masked out and must not be split at all
+    pub fn excludes(&self, pos: &Vec2, size: &Vec2) -> bool {
+        self.mode == MaskMode::Exclude && self.max(pos, size) < EXCLUDE_BELOW
+    }
+
+    fn max(&self, pos: &Vec2, size: &Vec2) -> u8 {
+        let view = self.mask.view(pos.x, pos.y, size.x, size.y);
+        view.pixels().map(|(_, _, p)| p[0]).max().unwrap_or(0)
+    }
+
+    /// minimum quad size for a region of the given weight
+    pub fn min_size(&self, min_size: &Vec2, weight: f64) -> Vec2 {
+        let factor = 1.0 + (MIN_SIZE_FACTOR - 1.0) * (1.0 - weight);
+        Vec2 {
+            x: (min_size.x as f64 * factor).round() as u32,
+            y: (min_size.y as f64 * factor).round() as u32,
+        }
+    }
+
+    /// minimum depth for a region of the given weight
+    pub fn min_depth(&self, min_depth: u8, weight: f64) -> u8 {
+        (min_depth as f64 * weight).round() as u8
+    }
+
+    /// threshold channel for a region of the given weight, black regions never split by color
+    pub fn threshold(&self, threshold: f64, max: f64, weight: f64) -> f64 {
+        threshold + (max - threshold) * (1.0 - weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+    pub use test_case::test_case;
+
+    /// left half black, right half white
+    fn halves(mode: MaskMode) -> RegionMask {
+        let img = GrayImage::from_fn(8, 4, |x, _| Luma([if x < 4 { 0 } else { 255 }]));
+        RegionMask::new(img, mode)
+    }
+
+    #[test_case(Vec2{x:0,y:0}, Vec2{x:8,y:4} => 1.0; "whole")]
+    #[test_case(Vec2{x:0,y:0}, Vec2{x:4,y:4} => 0.0; "black")]
+    #[test_case(Vec2{x:4,y:2}, Vec2{x:4,y:2} => 1.0; "white")]
+    #[test_case(Vec2{x:2,y:1}, Vec2{x:2,y:2} => 0.0; "black-inner")]
+    #[test_case(Vec2{x:3,y:1}, Vec2{x:2,y:2} => 1.0; "straddling")]
+    fn weighs_region(pos: Vec2, size: Vec2) -> f64 {
+        halves(MaskMode::Modulate).weight(&pos, &size)
+    }
+
+    #[test_case(Vec2{x:0,y:0}, Vec2{x:4,y:4} => true; "black")]
+    #[test_case(Vec2{x:3,y:0}, Vec2{x:2,y:4} => false; "straddling")]
+    fn excludes_region(pos: Vec2, size: Vec2) -> bool {
+        halves(MaskMode::Exclude).excludes(&pos, &size)
+    }
+
+    #[test]
+    fn modulates_never_excludes() {
+        assert!(!halves(MaskMode::Modulate).excludes(&Vec2::ZERO, &Vec2 { x: 4, y: 4 }))
+    }
+
+    #[test_case(1.0 => (Vec2{x:4,y:2}, 4, 8.0); "white")]
+    #[test_case(0.5 => (Vec2{x:10,y:5}, 2, 131.5); "gray")]
+    #[test_case(0.0 => (Vec2{x:16,y:8}, 0, 255.0); "black")]
+    fn modulates_args(weight: f64) -> (Vec2, u8, f64) {
+        let mask = halves(MaskMode::Modulate);
+        (
+            mask.min_size(&Vec2 { x: 4, y: 2 }, weight),
+            mask.min_depth(4, weight),
+            mask.threshold(8.0, 255.0, weight),
+        )
+    }
+
+    #[test]
+    fn pads_with_no_interest() {
+        let mask = halves(MaskMode::Exclude).padded(&Vec2 { x: 16, y: 8 });
+        assert!(!mask.excludes(&Vec2::ZERO, &Vec2 { x: 8, y: 4 }));
+        assert!(mask.excludes(&Vec2 { x: 8, y: 0 }, &Vec2 { x: 8, y: 8 }));
+    }
+}
@@ -0,0 +1,369 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::io::Write;
+
+use flate2::{write::ZlibEncoder, Compression};
+use image::{ImageDecoder, ImageFormat, ImageResult};
+use log::warn;
+
+/* data structures */
+
+/// metadata carried from the input media to the output media
+#[derive(Clone, Default, Eq, PartialEq, Debug)]
+pub struct ImageMetadata {
+    /// ICC color profile
+    pub icc: Option<Vec<u8>>,
+    /// raw EXIF data, starting with the TIFF header
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl ImageMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.icc.is_none() && self.exif.is_none() && self.xmp.is_none()
+    }
+}
+
+/* reading */
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER_JPEG: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_KEYWORD_PNG: &[u8] = b"XML:com.adobe.xmp";
+const ICC_HEADER_JPEG: &[u8] = b"ICC_PROFILE\0";
+const ICC_NAME_PNG: &[u8] = b"ICC Profile";
+const PNG_SIGNATURE_LEN: usize = 8;
+const JPEG_MAX_SEGMENT: usize = u16::MAX as usize - 2;
+
+/// reads the ICC profile, EXIF and XMP through the decoder
+pub(crate) fn read_metadata(decoder: &mut impl ImageDecoder) -> ImageResult<ImageMetadata> {
+    Ok(ImageMetadata {
+        icc: decoder.icc_profile()?,
+        // some WebP writers keep the JPEG APP1 header in the chunk
+        exif: decoder
+            .exif_metadata()?
+            .map(|exif| match exif.strip_prefix(EXIF_HEADER) {
+                Some(tiff) => tiff.to_vec(),
+                None => exif,
+            }),
+        xmp: decoder.xmp_metadata()?,
+    })
+}
+
+/// iterates over the segments of a JPEG until the start of scan
+fn jpeg_segments(buf: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = 2; // SOI
+    std::iter::from_fn(move || {
+        if pos + 4 > buf.len() || buf[pos] != 0xFF || buf[pos + 1] == 0xDA {
+            return None;
+        }
+        let marker = buf[pos + 1];
+        let len = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
+        let data = buf.get(pos + 4..pos + 2 + len)?;
+        pos += 2 + len;
+        Some((marker, data))
+    })
+}
+
+/// iterates over the chunks of a RIFF (WebP) container as fourcc and data
+fn riff_chunks(buf: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 12; // RIFF size WEBP
+    std::iter::from_fn(move || {
+        let kind = buf.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(buf.get(pos + 4..pos + 8)?.try_into().unwrap()) as usize;
+        let data = buf.get(pos + 8..pos + 8 + len)?;
+        pos += 8 + len + (len & 1);
+        Some((kind, data))
+    })
+}
+
+/* writing */
+
+/// adds the metadata to an already encoded PNG or JPEG
+pub(crate) fn embed_metadata(buf: Vec<u8>, format: ImageFormat, meta: &ImageMetadata) -> Vec<u8> {
+    if meta.is_empty() {
+        return buf;
+    }
+    match format {
+        ImageFormat::Png => embed_png(buf, meta),
+        ImageFormat::Jpeg => embed_jpeg(buf, meta),
+        ImageFormat::WebP => embed_webp(buf, meta),
+        _ => buf,
+    }
+}
+
+fn embed_png(buf: Vec<u8>, meta: &ImageMetadata) -> Vec<u8> {
+    // right after IHDR, as iCCP has to precede PLTE and IDAT
+    let at = PNG_SIGNATURE_LEN + 25;
+    let mut chunks = Vec::new();
+    if let Some(ref icc) = meta.icc {
+        let mut data = [ICC_NAME_PNG, b"\0\0"].concat();
+        let mut z = ZlibEncoder::new(&mut data, Compression::default());
+        z.write_all(icc).unwrap();
+        z.finish().unwrap();
+        write_png_chunk(&mut chunks, b"iCCP", &data);
+    }
+    if let Some(ref exif) = meta.exif {
+        write_png_chunk(&mut chunks, b"eXIf", exif);
+    }
+    if let Some(ref xmp) = meta.xmp {
+        // keyword, uncompressed, no language tag nor translated keyword
+        let data = [XMP_KEYWORD_PNG, b"\0\0\0\0\0", xmp].concat();
+        write_png_chunk(&mut chunks, b"iTXt", &data);
+    }
+    [&buf[..at], &chunks, &buf[at..]].concat()
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn embed_jpeg(buf: Vec<u8>, meta: &ImageMetadata) -> Vec<u8> {
+    // after SOI and the JFIF header
+    let at = match jpeg_segments(&buf).next() {
+        Some((0xE0, data)) => 4 + data.len() + 2,
+        _ => 2,
+    };
+    let mut segments = Vec::new();
+    if let Some(ref exif) = meta.exif {
+        if !write_jpeg_segment(&mut segments, 0xE1, &[EXIF_HEADER, exif].concat()) {
+            warn!("EXIF metadata is too large for JPEG and will be discarded");
+        }
+    }
+    if let Some(ref xmp) = meta.xmp {
+        if !write_jpeg_segment(&mut segments, 0xE1, &[XMP_HEADER_JPEG, xmp].concat()) {
+            warn!("XMP metadata is too large for JPEG and will be discarded");
+        }
+    }
+    if let Some(ref icc) = meta.icc {
+        // split over multiple numbered segments
+        let chunks: Vec<&[u8]> = icc
+            .chunks(JPEG_MAX_SEGMENT - ICC_HEADER_JPEG.len() - 2)
+            .collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let seq = [i as u8 + 1, chunks.len() as u8];
+            write_jpeg_segment(
+                &mut segments,
+                0xE2,
+                &[ICC_HEADER_JPEG, &seq, chunk].concat(),
+            );
+        }
+    }
+    [&buf[..at], &segments, &buf[at..]].concat()
+}
+
+/// writes an APPn segment, returns false if the data doesn't fit
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) -> bool {
+    if data.len() > JPEG_MAX_SEGMENT {
+        return false;
+    }
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(data);
+    true
+}
+
+fn embed_webp(buf: Vec<u8>, meta: &ImageMetadata) -> Vec<u8> {
+    // metadata requires the extended format, with a VP8X chunk first
+    let mut flags = 0;
+    let mut canvas = None;
+    let mut image = Vec::new();
+    for (kind, data) in riff_chunks(&buf) {
+        match kind {
+            b"VP8X" if data.len() >= 10 => {
+                flags = data[0];
+                canvas = Some(data[4..10].try_into().unwrap());
+            }
+            b"ICCP" | b"EXIF" | b"XMP " | b"VP8X" => {}
+            _ => {
+                canvas = canvas.or_else(|| webp_canvas(kind, data));
+                if kind == b"ALPH" || (kind == b"VP8L" && data.len() > 4 && data[4] & 0x10 != 0) {
+                    flags |= WEBP_FLAG_ALPHA;
+                }
+                write_riff_chunk(&mut image, kind.try_into().unwrap(), data);
+            }
+        }
+    }
+    let Some(canvas): Option<[u8; 6]> = canvas else {
+        warn!("cannot read WebP dimensions, metadata will be discarded");
+        return buf;
+    };
+
+    let mut chunks = Vec::new();
+    if meta.icc.is_some() {
+        flags |= WEBP_FLAG_ICC;
+    }
+    if meta.exif.is_some() {
+        flags |= WEBP_FLAG_EXIF;
+    }
+    if meta.xmp.is_some() {
+        flags |= WEBP_FLAG_XMP;
+    }
+    write_riff_chunk(
+        &mut chunks,
+        b"VP8X",
+        &[&[flags, 0, 0, 0], &canvas[..]].concat(),
+    );
+    if let Some(ref icc) = meta.icc {
+        write_riff_chunk(&mut chunks, b"ICCP", icc);
+    }
+    chunks.extend_from_slice(&image);
+    if let Some(ref exif) = meta.exif {
+        write_riff_chunk(&mut chunks, b"EXIF", exif);
+    }
+    if let Some(ref xmp) = meta.xmp {
+        write_riff_chunk(&mut chunks, b"XMP ", xmp);
+    }
+    let size = (chunks.len() as u32 + 4).to_le_bytes();
+    [b"RIFF", &size[..], b"WEBP", &chunks].concat()
+}
+
+const WEBP_FLAG_ICC: u8 = 0x20;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_EXIF: u8 = 0x08;
+const WEBP_FLAG_XMP: u8 = 0x04;
+
+/// canvas width and height minus one as 24 bits each, as stored in VP8X
+fn webp_canvas(kind: &[u8], data: &[u8]) -> Option<[u8; 6]> {
+    let (w, h) = match kind {
+        // lossy frame tag and start code, then 14 bits each
+        b"VP8 " if data.len() >= 10 => (
+            u16::from_le_bytes([data[6], data[7]]) as u32 & 0x3FFF,
+            u16::from_le_bytes([data[8], data[9]]) as u32 & 0x3FFF,
+        ),
+        // lossless signature, then 14 bits each minus one
+        b"VP8L" if data.len() >= 5 => {
+            let bits = u32::from_le_bytes(data[1..5].try_into().unwrap());
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)
+        }
+        _ => return None,
+    };
+    let (w, h) = (
+        w.checked_sub(1)?.to_le_bytes(),
+        h.checked_sub(1)?.to_le_bytes(),
+    );
+    Some([w[0], w[1], w[2], h[0], h[1], h[2]])
+}
+
+fn write_riff_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{codecs::*, DynamicImage, ImageReader};
+    use std::io::Cursor;
+    pub use test_case::test_case;
+
+    /// minimal EXIF with only the orientation tag
+    pub(crate) fn exif(be: bool, orientation: u16) -> Vec<u8> {
+        let mut v = Vec::new();
+        if be {
+            v.extend_from_slice(&[0x4D, 0x4D, 0, 42, 0, 0, 0, 8, 0, 1]);
+            v.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+            v.extend_from_slice(&orientation.to_be_bytes());
+        } else {
+            v.extend_from_slice(&[0x49, 0x49, 42, 0, 8, 0, 0, 0, 1, 0]);
+            v.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0]);
+            v.extend_from_slice(&orientation.to_le_bytes());
+        }
+        v.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        v
+    }
+
+    fn test_meta() -> ImageMetadata {
+        ImageMetadata {
+            icc: Some(vec![1, 2, 3, 4]),
+            exif: Some(exif(false, 6)),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+        }
+    }
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let img = DynamicImage::new_rgb8(4, 4);
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, format).unwrap();
+        buf.into_inner()
+    }
+
+    #[test_case(ImageFormat::Png; "png")]
+    #[test_case(ImageFormat::Jpeg; "jpeg")]
+    #[test_case(ImageFormat::WebP; "webp")]
+    fn roundtrips(format: ImageFormat) {
+        let buf = embed_metadata(encoded(format), format, &test_meta());
+        let mut decoder = ImageReader::with_format(Cursor::new(&buf), format)
+            .into_decoder()
+            .unwrap();
+        assert_eq!(decoder.dimensions(), (4, 4));
+        assert_eq!(read_metadata(&mut decoder).unwrap(), test_meta());
+        DynamicImage::from_decoder(decoder).unwrap();
+    }
+
+    #[test]
+    fn strips_exif_header() {
+        let meta = ImageMetadata {
+            exif: Some([EXIF_HEADER, &exif(false, 6)].concat()),
+            ..Default::default()
+        };
+        let buf = embed_metadata(encoded(ImageFormat::WebP), ImageFormat::WebP, &meta);
+        let mut decoder = webp::WebPDecoder::new(Cursor::new(&buf)).unwrap();
+        assert_eq!(
+            read_metadata(&mut decoder).unwrap().exif,
+            Some(exif(false, 6))
+        );
+    }
+
+    #[test]
+    fn reads_truncated_xmp() {
+        let buf = encoded(ImageFormat::Png);
+        let mut chunk = Vec::new();
+        write_png_chunk(&mut chunk, b"iTXt", &[XMP_KEYWORD_PNG, b"\0\0"].concat());
+        let at = PNG_SIGNATURE_LEN + 25;
+        let buf = [&buf[..at], &chunk, &buf[at..]].concat();
+        let mut decoder = png::PngDecoder::new(Cursor::new(&buf)).unwrap();
+        assert_eq!(read_metadata(&mut decoder).unwrap().xmp, None);
+    }
+
+    #[test_case(b"VP8 ", &[0; 10] => None; "lossy-zero")]
+    #[test_case(b"VP8 ", &[0, 0, 0, 0x9D, 0x01, 0x2A, 4, 0, 2, 0] => Some([3, 0, 0, 1, 0, 0]); "lossy")]
+    #[test_case(b"VP8L", &[0x2F, 3, 0x40, 0, 0] => Some([3, 0, 0, 1, 0, 0]); "lossless")]
+    #[test_case(b"ALPH", &[0; 10] => None; "other")]
+    fn reads_webp_canvas(kind: &[u8], data: &[u8]) -> Option<[u8; 6]> {
+        webp_canvas(kind, data)
+    }
+
+    #[test]
+    fn ignores_empty() {
+        let buf = encoded(ImageFormat::Png);
+        assert_eq!(
+            embed_metadata(buf.clone(), ImageFormat::Png, &ImageMetadata::default()),
+            buf
+        );
+        let mut decoder = png::PngDecoder::new(Cursor::new(&buf)).unwrap();
+        assert!(read_metadata(&mut decoder).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,614 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::fmt::Write;
+use std::time::Duration;
+
+use image::GenericImageView;
+use serde::{Serialize, Serializer};
+
+use crate::drawing::{draw_quads, ImageCache};
+use crate::quad::{aggregate_colors, Aggregation};
+use crate::utils::{luma, normalized, PixelBuffer, Quad, QuadStructure, RgbaPixel, Vec2};
+
+/// side of the windows the structural similarity is averaged over
+const SSIM_WINDOW: u32 = 8;
+/// stabilize the division of the structural similarity with weak denominators
+const SSIM_C1: f64 = 0.01 * 0.01;
+const SSIM_C2: f64 = 0.03 * 0.03;
+
+/// how the statistics are printed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum StatsFormat {
+    /// Aligned text for people
+    Human,
+    /// A JSON object per image, on a single line
+    Json,
+}
+
+/// leaves of a depth
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DepthStats {
+    pub depth: u8,
+    pub leaves: usize,
+    /// fraction of the image area they cover
+    pub coverage: f64,
+}
+
+/// time taken by each step of the processing, serialized in seconds
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Timings {
+    #[serde(serialize_with = "secs")]
+    pub calc: Duration,
+    #[serde(serialize_with = "secs")]
+    pub draw: Duration,
+    #[serde(serialize_with = "secs")]
+    pub save: Duration,
+}
+
+/// difference of the quads filled with their color from the input,
+/// over the channels normalized between 0 and 1.
+/// JSON has no infinity, an infinite PSNR is serialized as null
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ErrorMetrics {
+    /// mean squared error
+    pub mse: f64,
+    /// mean absolute error
+    pub mae: f64,
+    /// peak signal to noise ratio in decibels, infinite for identical images
+    pub psnr: f64,
+    /// structural similarity of the luma, 1 for identical images
+    pub ssim: f64,
+}
+
+/// report of a processed image
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub leaves: usize,
+    pub depths: Vec<DepthStats>,
+    /// sizes of the leaves with the smallest and biggest area
+    pub min_size: Vec2,
+    pub max_size: Vec2,
+    /// mean area of the leaves in pixels
+    pub mean_area: f64,
+    pub timings: Timings,
+    pub error: ErrorMetrics,
+}
+
+/// the statistics of an image as reported in JSON
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    input: &'a str,
+    leaves: usize,
+    /// only the depths with leaves
+    depths: Vec<&'a DepthStats>,
+    leaf_size: LeafSizeReport,
+    time: &'a Timings,
+    error: &'a ErrorMetrics,
+}
+
+#[derive(Serialize)]
+struct LeafSizeReport {
+    min: [u32; 2],
+    max: [u32; 2],
+    mean_area: f64,
+}
+
+/// the comparison of two images as reported in JSON
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    input: &'a str,
+    reference: &'a str,
+    #[serde(flatten)]
+    error: &'a ErrorMetrics,
+}
+
+impl Stats {
+    /// statistics of the leaves and the reconstruction error of the structure,
+    /// quads without a color are filled with the aggregation of their pixels.
+    /// Only the `image` is measured of a source padded beyond it
+    pub fn new<I, P>(
+        source: &I,
+        structure: &QuadStructure<P>,
+        image: &Vec2,
+        aggregation: Aggregation,
+    ) -> Self
+    where
+        I: GenericImageView<Pixel = P>,
+        P: RgbaPixel,
+    {
+        let image = *image;
+        let image_area = area(&image).max(1.0);
+        let mut depths: Vec<DepthStats> = (0..=structure.depth())
+            .map(|depth| DepthStats {
+                depth,
+                leaves: 0,
+                coverage: 0.0,
+            })
+            .collect();
+        let (mut min_size, mut max_size) = (image, Vec2::ZERO);
+        let (mut leaves, mut total_area) = (0, 0.0);
+        for (quad, size) in visible_leaves(structure, &image) {
+            leaves += 1;
+            let d = &mut depths[quad.depth as usize];
+            d.leaves += 1;
+            d.coverage += area(&size) / image_area;
+            total_area += area(&size);
+            if area(&size) < area(&min_size) {
+                min_size = size;
+            }
+            if area(&size) > area(&max_size) {
+                max_size = size;
+            }
+        }
+        if leaves == 0 {
+            min_size = Vec2::ZERO;
+        }
+
+        let rebuilt = reconstruction(source, structure, aggregation);
+        Self {
+            leaves,
+            depths,
+            min_size,
+            max_size,
+            mean_area: total_area / leaves.max(1) as f64,
+            timings: Timings::default(),
+            error: ErrorMetrics::new(
+                &*source.view(0, 0, image.x, image.y),
+                &*rebuilt.view(0, 0, image.x, image.y),
+            ),
+        }
+    }
+
+    /// the report in the given format, ending with a new line
+    pub fn format(&self, input: &str, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Human => self.human(input),
+            StatsFormat::Json => self.json(input),
+        }
+    }
+
+    fn human(&self, input: &str) -> String {
+        let mut s = String::new();
+        let t = &self.timings;
+        writeln!(s, "statistics of {input}").unwrap();
+        writeln!(s, "  leaves     {}", self.leaves).unwrap();
+        writeln!(s, "  depth     leaves   coverage").unwrap();
+        for d in self.depths.iter().filter(|d| d.leaves > 0) {
+            let coverage = d.coverage * 100.0;
+            writeln!(s, "  {:5} {:10} {:9.2}%", d.depth, d.leaves, coverage).unwrap();
+        }
+        writeln!(
+            s,
+            "  leaf size  min {}x{}, max {}x{}, mean {:.2} px",
+            self.min_size.x, self.min_size.y, self.max_size.x, self.max_size.y, self.mean_area
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "  time       calc {:.3?}, draw {:.3?}, save {:.3?}",
+            t.calc, t.draw, t.save
+        )
+        .unwrap();
+        let e = &self.error;
+        writeln!(
+            s,
+            "  error      mse {:.6}, mae {:.6}, psnr {:.2} dB, ssim {:.4}",
+            e.mse, e.mae, e.psnr, e.ssim
+        )
+        .unwrap();
+        s
+    }
+
+    fn json(&self, input: &str) -> String {
+        let report = StatsReport {
+            input,
+            leaves: self.leaves,
+            depths: self.depths.iter().filter(|d| d.leaves > 0).collect(),
+            leaf_size: LeafSizeReport {
+                min: [self.min_size.x, self.min_size.y],
+                max: [self.max_size.x, self.max_size.y],
+                mean_area: self.mean_area,
+            },
+            time: &self.timings,
+            error: &self.error,
+        };
+        serde_json::to_string(&report).unwrap() + "\n"
+    }
+}
+
+impl ErrorMetrics {
+    /// the comparison of the input with the reference in the given format, ending with a new line
+    pub fn format(&self, input: &str, reference: &str, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Human => format!(
+                "error of {input} against {reference}\n  mse {:.6}, mae {:.6}, psnr {:.2} dB, ssim {:.4}\n",
+                self.mse, self.mae, self.psnr, self.ssim
+            ),
+            StatsFormat::Json => {
+                let report = ErrorReport {
+                    input,
+                    reference,
+                    error: self,
+                };
+                serde_json::to_string(&report).unwrap() + "\n"
+            }
+        }
+    }
+
+    /// compares two images of the same size
+    pub fn new<A, B, P>(a: &A, b: &B) -> Self
+    where
+        A: GenericImageView<Pixel = P>,
+        B: GenericImageView<Pixel = P>,
+        P: RgbaPixel,
+    {
+        let (mut squared, mut absolute) = (0.0, 0.0);
+        for ((_, _, pa), (_, _, pb)) in a.pixels().zip(b.pixels()) {
+            let (ca, cb) = (normalized(pa.channels()), normalized(pb.channels()));
+            for (x, y) in ca.iter().zip(cb) {
+                squared += (x - y).powi(2);
+                absolute += (x - y).abs();
+            }
+        }
+        let (w, h) = a.dimensions();
+        let samples = (w as f64 * h as f64 * 4.0).max(1.0);
+        let mse = squared / samples;
+        Self {
+            mse,
+            mae: absolute / samples,
+            psnr: psnr(mse),
+            ssim: ssim(a, b),
+        }
+    }
+}
+
+/// mean squared error of the normalized channels of two images of the same size
+pub fn mse<A, B, P>(a: &A, b: &B) -> f64
+where
+    A: GenericImageView<Pixel = P>,
+    B: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let mut squared = 0.0;
+    for ((_, _, pa), (_, _, pb)) in a.pixels().zip(b.pixels()) {
+        let (ca, cb) = (normalized(pa.channels()), normalized(pb.channels()));
+        squared += ca.iter().zip(cb).map(|(x, y)| (x - y).powi(2)).sum::<f64>();
+    }
+    let (w, h) = a.dimensions();
+    squared / (w as f64 * h as f64 * 4.0).max(1.0)
+}
+
+/// peak signal to noise ratio in decibels of the mean squared error of normalized channels
+pub fn psnr(mse: f64) -> f64 {
+    match mse > 0.0 {
+        true => 10.0 * (1.0 / mse).log10(),
+        false => f64::INFINITY,
+    }
+}
+
+/// mean structural similarity of the luma of two images of the same size,
+/// over every window of 8x8 pixels, or the whole image if smaller
+pub fn ssim<A, B, P>(a: &A, b: &B) -> f64
+where
+    A: GenericImageView<Pixel = P>,
+    B: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let (w, h) = a.dimensions();
+    if w == 0 || h == 0 {
+        return 1.0;
+    }
+    // sums of x, y, x², y² and xy
+    let table = SummedArea::new(
+        w,
+        h,
+        a.pixels().zip(b.pixels()).map(|(pa, pb)| {
+            let (x, y) = (luma(&pa.2), luma(&pb.2));
+            [x, y, x * x, y * y, x * y]
+        }),
+    );
+
+    let (ww, wh) = (SSIM_WINDOW.min(w), SSIM_WINDOW.min(h));
+    let n = (ww * wh) as f64;
+    let mut total = 0.0;
+    for y in 0..=h - wh {
+        for x in 0..=w - ww {
+            let [sx, sy, sxx, syy, sxy] = table.sum(x, y, ww, wh);
+            let (mx, my) = (sx / n, sy / n);
+            let (vx, vy) = (sxx / n - mx * mx, syy / n - my * my);
+            let cov = sxy / n - mx * my;
+            total += ((2.0 * mx * my + SSIM_C1) * (2.0 * cov + SSIM_C2))
+                / ((mx * mx + my * my + SSIM_C1) * (vx + vy + SSIM_C2));
+        }
+    }
+    total / ((w - ww + 1) as f64 * (h - wh + 1) as f64)
+}
+
+/// sums of values over any rectangle of an image
+struct SummedArea<const N: usize> {
+    width: usize,
+    /// (width + 1) * (height + 1) sums, with a leading row and column of zeros
+    sums: Vec<[f64; N]>,
+}
+
+impl<const N: usize> SummedArea<N> {
+    /// from the values of the pixels by rows
+    fn new(w: u32, h: u32, values: impl Iterator<Item = [f64; N]>) -> Self {
+        let width = w as usize + 1;
+        let mut sums = vec![[0.0; N]; width * (h as usize + 1)];
+        for (i, v) in values.enumerate() {
+            let (x, y) = (i % w as usize + 1, i / w as usize + 1);
+            for k in 0..N {
+                sums[y * width + x][k] =
+                    v[k] + sums[(y - 1) * width + x][k] + sums[y * width + x - 1][k]
+                        - sums[(y - 1) * width + x - 1][k];
+            }
+        }
+        Self { width, sums }
+    }
+
+    fn sum(&self, x: u32, y: u32, w: u32, h: u32) -> [f64; N] {
+        let at = |x: u32, y: u32| self.sums[y as usize * self.width + x as usize];
+        let (a, b, c, d) = (at(x + w, y + h), at(x, y), at(x + w, y), at(x, y + h));
+        std::array::from_fn(|k| a[k] + b[k] - c[k] - d[k])
+    }
+}
+
+/// the leaves of a structure starting inside the image, with the size of what they cover of it
+pub fn visible_leaves<'a, P>(
+    structure: &'a QuadStructure<P>,
+    image: &'a Vec2,
+) -> impl Iterator<Item = (&'a Quad<P>, Vec2)> {
+    let inside = |pos: &&Vec2| pos.x < image.x && pos.y < image.y;
+    structure
+        .map
+        .iter()
+        .filter(move |(pos, _)| inside(pos))
+        .map(|(pos, quad)| {
+            let size = structure.size_of(quad);
+            let x = size.x.min(image.x - pos.x);
+            let y = size.y.min(image.y - pos.y);
+            (quad, Vec2 { x, y })
+        })
+}
+
+/// the quads filled with their color as drawn by `--fill`,
+/// those without one with the aggregation of their pixels
+pub fn reconstruction<I, P>(
+    source: &I,
+    structure: &QuadStructure<P>,
+    aggregation: Aggregation,
+) -> PixelBuffer<P>
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let mut colored = QuadStructure {
+        map: structure.map.clone(),
+        sizes: structure.sizes.clone(),
+    };
+    for (pos, quad) in colored.map.iter_mut() {
+        if quad.color.is_none() {
+            let size = structure.size_of(quad);
+            let color = aggregate_colors(source, pos, &size, aggregation);
+            quad.color = Some(*P::from_slice(&color));
+        }
+    }
+    draw_quads(&colored, &None, &None, false, &None, &mut ImageCache::new())
+}
+
+fn area(size: &Vec2) -> f64 {
+    size.x as f64 * size.y as f64
+}
+
+fn secs<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{Quad, QuadMap};
+    use image::{Rgba, RgbaImage};
+    pub use test_case::test_case;
+
+    /// a 4x4 image, the left half split once more
+    fn structure() -> QuadStructure {
+        let quad = |depth, color| Quad {
+            depth,
+            color: Some(Rgba(color)),
+            size: None,
+        };
+        QuadStructure {
+            map: QuadMap::from([
+                (Vec2 { x: 0, y: 0 }, quad(2, [0, 0, 0, 255])),
+                (Vec2 { x: 1, y: 0 }, quad(2, [0, 0, 0, 255])),
+                (Vec2 { x: 0, y: 1 }, quad(2, [0, 0, 0, 255])),
+                (Vec2 { x: 1, y: 1 }, quad(2, [0, 0, 0, 255])),
+                (Vec2 { x: 2, y: 0 }, quad(1, [255, 255, 255, 255])),
+                (Vec2 { x: 0, y: 2 }, quad(1, [0, 0, 0, 255])),
+                (Vec2 { x: 2, y: 2 }, quad(1, [0, 0, 0, 255])),
+            ]),
+            sizes: vec![
+                Vec2 { x: 4, y: 4 },
+                Vec2 { x: 2, y: 2 },
+                Vec2 { x: 1, y: 1 },
+            ],
+        }
+    }
+
+    #[test]
+    fn counts_leaves() {
+        let img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        let stats = Stats::new(&img, &structure(), &Vec2 { x: 4, y: 4 }, Aggregation::Mean);
+        assert_eq!(stats.leaves, 7);
+        assert_eq!(
+            stats.depths[1..],
+            [
+                DepthStats {
+                    depth: 1,
+                    leaves: 3,
+                    coverage: 0.75
+                },
+                DepthStats {
+                    depth: 2,
+                    leaves: 4,
+                    coverage: 0.25
+                }
+            ]
+        );
+        assert_eq!(stats.min_size, Vec2 { x: 1, y: 1 });
+        assert_eq!(stats.max_size, Vec2 { x: 2, y: 2 });
+        assert!((stats.mean_area - 16.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn measures_unpadded() {
+        // the image is the black top left quarter
+        let img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        let stats = Stats::new(&img, &structure(), &Vec2 { x: 2, y: 2 }, Aggregation::Mean);
+        assert_eq!(stats.leaves, 4);
+        assert_eq!(stats.depths[1].leaves, 0);
+        assert_eq!(stats.depths[2].coverage, 1.0);
+        assert_eq!(stats.max_size, Vec2 { x: 1, y: 1 });
+        assert_eq!(stats.error.mse, 0.0);
+    }
+
+    #[test]
+    fn clips_visible_leaves() {
+        let structure = structure();
+        let mut visible: Vec<_> = visible_leaves(&structure, &Vec2 { x: 3, y: 3 })
+            .map(|(_, size)| (size.x, size.y))
+            .collect();
+        visible.sort();
+        assert_eq!(
+            visible,
+            [(1, 1), (1, 1), (1, 1), (1, 1), (1, 1), (1, 2), (2, 1)]
+        );
+    }
+
+    #[test]
+    fn measures_error() {
+        // the white quad covers black pixels
+        let img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        let error = Stats::new(&img, &structure(), &Vec2 { x: 4, y: 4 }, Aggregation::Mean).error;
+        // 4 of 16 pixels differ by 1 in 3 of 4 channels
+        assert!((error.mse - 0.25 * 0.75).abs() < 1e-9);
+        assert!((error.mae - 0.25 * 0.75).abs() < 1e-9);
+        let rebuilt = reconstruction(&img, &structure(), Aggregation::Mean);
+        assert!((mse(&img, &rebuilt) - error.mse).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reconstructs_missing_colors() {
+        let img = RgbaImage::from_fn(2, 1, |x, _| Rgba([x as u8 * 100, 0, 0, 255]));
+        let s = QuadStructure {
+            map: QuadMap::from([(Vec2::ZERO, Quad::new(0))]),
+            sizes: vec![Vec2 { x: 2, y: 1 }],
+        };
+        assert_eq!(
+            reconstruction(&img, &s, Aggregation::Mean).get_pixel(1, 0),
+            &Rgba([50, 0, 0, 255])
+        );
+    }
+
+    #[test_case(0.01 => 20.0; "hundredth")]
+    #[test_case(1.0 => 0.0; "opposite")]
+    #[test_case(0.0 => f64::INFINITY; "identical")]
+    fn measures_psnr(mse: f64) -> f64 {
+        psnr(mse)
+    }
+
+    /// a gradient with a bright square, noisy if asked to
+    fn scene(noise: u32) -> RgbaImage {
+        RgbaImage::from_fn(24, 20, |x, y| {
+            let v = match (6..14).contains(&x) && (4..12).contains(&y) {
+                true => 220,
+                false => x * 4 + y * 3,
+            };
+            let n = (x * 31 + y * 17).wrapping_mul(2654435761) >> 29;
+            let v = (v + n * noise).min(255) as u8;
+            Rgba([v, v, v, 255])
+        })
+    }
+
+    #[test]
+    fn measures_ssim() {
+        let img = scene(0);
+        assert!((ssim(&img, &img) - 1.0).abs() < 1e-9);
+        let noisy = ssim(&img, &scene(2));
+        let noisier = ssim(&img, &scene(8));
+        assert!(1.0 > noisy && noisy > noisier);
+        // symmetric
+        assert!((ssim(&scene(8), &img) - noisier).abs() < 1e-9);
+    }
+
+    #[test]
+    fn measures_small_ssim() {
+        // a single window smaller than 8x8
+        let a = RgbaImage::from_fn(3, 2, |x, _| Rgba([x as u8 * 100, 0, 0, 255]));
+        let b = RgbaImage::from_fn(3, 2, |x, _| Rgba([200 - x as u8 * 100, 0, 0, 255]));
+        assert!((ssim(&a, &a) - 1.0).abs() < 1e-9);
+        assert!(ssim(&a, &b) < 0.0);
+    }
+
+    #[test_case(StatsFormat::Human, "statistics of in.png\n"; "human")]
+    #[test_case(StatsFormat::Json, r#"{"input":"in.png","leaves":7,"depths":[{"depth":1"#; "json")]
+    fn formats(format: StatsFormat, starts: &str) {
+        let img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        let report = Stats::new(&img, &structure(), &Vec2 { x: 4, y: 4 }, Aggregation::Mean)
+            .format("in.png", format);
+        assert!(report.starts_with(starts));
+        assert!(report.ends_with('\n'));
+    }
+
+    #[test]
+    fn formats_json_non_finite() {
+        let mut stats = Stats::new(
+            &RgbaImage::new(1, 1),
+            &QuadStructure {
+                map: QuadMap::from([(Vec2::ZERO, Quad::new(0))]),
+                sizes: vec![Vec2 { x: 1, y: 1 }],
+            },
+            &Vec2 { x: 1, y: 1 },
+            Aggregation::Mean,
+        );
+        stats.mean_area = f64::NAN;
+        let report = stats.format("in.png", StatsFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(value["error"]["psnr"], serde_json::Value::Null);
+        assert_eq!(value["leaf_size"]["mean_area"], serde_json::Value::Null);
+        assert_eq!(value["leaf_size"]["min"], serde_json::json!([1, 1]));
+    }
+
+    #[test_case(StatsFormat::Human => "error of a.png against b.png\n  mse 0.000000, mae 0.000000, psnr inf dB, ssim 1.0000\n"; "human")]
+    #[test_case(StatsFormat::Json => concat!(
+        r#"{"input":"a.png","reference":"b.png","mse":0.0,"mae":0.0,"psnr":null,"ssim":1.0}"#, "\n"
+    ); "json")]
+    fn formats_error(format: StatsFormat) -> String {
+        let img = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        ErrorMetrics::new(&img, &img).format("a.png", "b.png", format)
+    }
+
+    #[test_case("a.png" => r#""a.png""#; "plain")]
+    #[test_case(r#"C:\a "b".png"# => r#""C:\\a \"b\".png""#; "escaped")]
+    #[test_case("a\nb" => r#""a\nb""#; "control")]
+    fn quotes_json(input: &str) -> String {
+        let img = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 255]));
+        let report = ErrorMetrics::new(&img, &img).format(input, "", StatsFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&report).unwrap();
+        value["input"].to_string()
+    }
+}
@@ -0,0 +1,335 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::Primitive;
+use log::trace;
+use num_traits::{NumCast, ToPrimitive};
+
+use crate::utils::*;
+use std::cmp::Ordering;
+
+/// maximum number of k-means iterations
+const KMEANS_MAX_ITERATIONS: usize = 32;
+
+/// a color with the channels as floats in the range of its pixel type
+type Color = [f64; 4];
+
+/// a color and how much area it covers
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    color: Color,
+    weight: f64,
+}
+
+/// algorithm used to build a palette from the colors of the quads
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum Quantizer {
+    /// Split the colors along the widest channel at the weighted median
+    MedianCut,
+    /// Refine the median cut palette with k-means clustering
+    Kmeans,
+}
+
+/// colored regions of an image, a palette can be built from and applied to
+pub(crate) trait ColorRegions<P: RgbaPixel> {
+    /// the colors of the regions and their area
+    fn colors(&self) -> impl Iterator<Item = (P, f64)>;
+    fn colors_mut(&mut self) -> impl Iterator<Item = &mut P>;
+}
+
+impl<P: RgbaPixel> ColorRegions<P> for QuadStructure<P> {
+    fn colors(&self) -> impl Iterator<Item = (P, f64)> {
+        self.map.values().filter_map(|q| {
+            let size = self.size_of(q);
+            q.color.map(|c| (c, size.x as f64 * size.y as f64))
+        })
+    }
+
+    fn colors_mut(&mut self) -> impl Iterator<Item = &mut P> {
+        self.map.values_mut().filter_map(|q| q.color.as_mut())
+    }
+}
+
+/// builds a palette of at most `size` colors from the quad colors, weighted by their area
+pub(crate) fn build_palette<P: RgbaPixel>(
+    structure: &impl ColorRegions<P>,
+    size: usize,
+    quantizer: Quantizer,
+) -> Vec<P> {
+    let samples = samples(structure);
+    let palette = median_cut(&samples, size);
+    let palette = match quantizer {
+        Quantizer::MedianCut => palette,
+        Quantizer::Kmeans => kmeans(&samples, palette),
+    };
+    trace!("built palette of {} colors", palette.len());
+    palette.iter().map(from_color).collect()
+}
+
+/// replaces the color of every quad with the nearest one of the palette
+pub(crate) fn apply_palette<P: RgbaPixel>(structure: &mut impl ColorRegions<P>, palette: &[P]) {
+    let colors: Vec<Color> = palette.iter().map(to_color).collect();
+    for c in structure.colors_mut() {
+        *c = palette[nearest(&to_color(c), &colors)];
+    }
+}
+
+/// colors of the quads and their area, identical colors are merged
+fn samples<P: RgbaPixel>(structure: &impl ColorRegions<P>) -> Vec<Sample> {
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut quads: Vec<_> = structure
+        .colors()
+        .map(|(c, weight)| (to_color(&c), weight))
+        .collect();
+    quads.sort_by(|a, b| {
+        a.0.iter()
+            .zip(&b.0)
+            .map(|(x, y)| x.total_cmp(y))
+            .fold(Ordering::Equal, Ordering::then)
+    });
+    for (color, weight) in quads {
+        match samples.last_mut() {
+            Some(s) if s.color == color => s.weight += weight,
+            _ => samples.push(Sample { color, weight }),
+        }
+    }
+    samples
+}
+
+fn median_cut(samples: &[Sample], size: usize) -> Vec<Color> {
+    let mut boxes = vec![samples.to_vec()];
+    while boxes.len() < size {
+        // the box with the widest channel is split next
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (channel, range) = (0..4)
+                    .map(|c| (c, range(b, c)))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap();
+                (i, channel, range)
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2));
+        let Some((i, channel, _)) = widest else {
+            break;
+        };
+
+        let mut b = boxes.swap_remove(i);
+        b.sort_by(|x, y| x.color[channel].total_cmp(&y.color[channel]));
+        let half = b.iter().map(|s| s.weight).sum::<f64>() / 2.0;
+        let mut acc = 0.0;
+        let split = b
+            .iter()
+            .position(|s| {
+                acc += s.weight;
+                acc >= half
+            })
+            .unwrap_or(0)
+            .clamp(0, b.len() - 2)
+            + 1;
+        let other = b.split_off(split);
+        boxes.push(b);
+        boxes.push(other);
+    }
+    boxes.iter().filter_map(|b| mean(b.iter())).collect()
+}
+
+fn kmeans(samples: &[Sample], mut centers: Vec<Color>) -> Vec<Color> {
+    let mut assigned = vec![usize::MAX; samples.len()];
+    for iteration in 0..KMEANS_MAX_ITERATIONS {
+        let mut changed = false;
+        for (s, a) in samples.iter().zip(assigned.iter_mut()) {
+            let n = nearest(&s.color, &centers);
+            changed |= *a != n;
+            *a = n;
+        }
+        if !changed {
+            trace!("k-means converged after {iteration} iterations");
+            break;
+        }
+        for (i, center) in centers.iter_mut().enumerate() {
+            let cluster = samples.iter().zip(&assigned).filter(|(_, &a)| a == i);
+            // empty clusters keep their center
+            if let Some(m) = mean(cluster.map(|(s, _)| s)) {
+                *center = m;
+            }
+        }
+    }
+    centers
+}
+
+fn range(samples: &[Sample], channel: usize) -> f64 {
+    let values = samples.iter().map(|s| s.color[channel]);
+    values.clone().fold(f64::MIN, f64::max) - values.fold(f64::MAX, f64::min)
+}
+
+/// weighted mean of the colors, none if there is no weight
+fn mean<'a>(samples: impl Iterator<Item = &'a Sample>) -> Option<Color> {
+    let (sum, weight) = samples.fold(([0.0; 4], 0.0), |(mut sum, weight), s| {
+        for (acc, v) in sum.iter_mut().zip(s.color) {
+            *acc += v * s.weight;
+        }
+        (sum, weight + s.weight)
+    });
+    (weight > 0.0).then(|| sum.map(|v| v / weight))
+}
+
+/// index of the nearest color by euclidean distance
+fn nearest(color: &Color, palette: &[Color]) -> usize {
+    let distance = |p: &Color| (0..4).map(|i| (color[i] - p[i]).powi(2)).sum::<f64>();
+    (0..palette.len())
+        .min_by(|&a, &b| distance(&palette[a]).total_cmp(&distance(&palette[b])))
+        .unwrap()
+}
+
+fn to_color<P: RgbaPixel>(p: &P) -> Color {
+    let c = p.channels();
+    [0, 1, 2, 3].map(|i| c[i].to_f64().unwrap())
+}
+
+fn from_color<P: RgbaPixel>(c: &Color) -> P {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    // integer channels are rounded, not truncated
+    let round = |v: f64| if max > 1.0 { v.round() } else { v };
+    *P::from_slice(&c.map(|v| NumCast::from(round(v.clamp(0.0, max))).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    pub use test_case::test_case;
+
+    /// quads of the given colors, each of the given depth in a 16x16 image
+    fn structure(quads: &[(Rgba<u8>, u8)]) -> QuadStructure {
+        QuadStructure {
+            map: quads
+                .iter()
+                .enumerate()
+                .map(|(i, &(c, depth))| {
+                    let quad = Quad {
+                        depth,
+                        color: Some(c),
+                        size: None,
+                    };
+                    (Vec2 { x: i as u32, y: 0 }, quad)
+                })
+                .collect(),
+            sizes: vec![
+                Vec2 { x: 16, y: 16 },
+                Vec2 { x: 8, y: 8 },
+                Vec2 { x: 4, y: 4 },
+            ],
+        }
+    }
+
+    fn colors(structure: &QuadStructure) -> Vec<Rgba<u8>> {
+        let mut colors: Vec<_> = structure.map.values().map(|q| q.color.unwrap()).collect();
+        colors.sort_by_key(|c| c.0);
+        colors.dedup();
+        colors
+    }
+
+    #[test_case(Quantizer::MedianCut; "median-cut")]
+    #[test_case(Quantizer::Kmeans; "kmeans")]
+    fn clusters_colors(quantizer: Quantizer) {
+        let mut s = structure(&[
+            (Rgba([0, 0, 0, 255]), 1),
+            (Rgba([10, 10, 10, 255]), 1),
+            (Rgba([200, 200, 200, 255]), 1),
+            (Rgba([210, 210, 210, 255]), 1),
+        ]);
+        let palette = build_palette(&s, 2, quantizer);
+        apply_palette(&mut s, &palette);
+        assert_eq!(
+            colors(&s),
+            vec![Rgba([5, 5, 5, 255]), Rgba([205, 205, 205, 255])]
+        );
+    }
+
+    #[test_case(Quantizer::MedianCut; "median-cut")]
+    #[test_case(Quantizer::Kmeans; "kmeans")]
+    fn weights_by_area(quantizer: Quantizer) {
+        // the first quad is four times as big
+        let s = structure(&[(Rgba([0, 0, 0, 255]), 1), (Rgba([100, 0, 0, 255]), 2)]);
+        assert_eq!(build_palette(&s, 1, quantizer), vec![Rgba([20, 0, 0, 255])]);
+    }
+
+    #[test]
+    fn keeps_fewer_colors() {
+        let s = structure(&[(Rgba([1, 2, 3, 4]), 1), (Rgba([1, 2, 3, 4]), 2)]);
+        assert_eq!(
+            build_palette(&s, 16, Quantizer::MedianCut),
+            vec![Rgba([1, 2, 3, 4])]
+        );
+    }
+
+    #[test]
+    fn snaps_to_palette() {
+        let mut s = structure(&[
+            (Rgba([30, 20, 20, 255]), 1),
+            (Rgba([250, 240, 230, 255]), 1),
+            (Rgba([200, 10, 0, 255]), 2),
+        ]);
+        apply_palette(
+            &mut s,
+            &[
+                Rgba([0, 0, 0, 255]),
+                Rgba([255, 255, 255, 255]),
+                Rgba([255, 0, 0, 255]),
+            ],
+        );
+        assert_eq!(
+            colors(&s),
+            vec![
+                Rgba([0, 0, 0, 255]),
+                Rgba([255, 0, 0, 255]),
+                Rgba([255, 255, 255, 255])
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_palette_f32() {
+        let s = QuadStructure {
+            map: QuadMap::from([
+                (Vec2::ZERO, Quad::from(Rgba([0.1f32, 0.2, 0.3, 1.0]))),
+                (
+                    Vec2 { x: 1, y: 0 },
+                    Quad::from(Rgba([0.3f32, 0.2, 0.1, 1.0])),
+                ),
+            ]),
+            sizes: vec![Vec2 { x: 2, y: 1 }],
+        };
+        let palette = build_palette(&s, 1, Quantizer::MedianCut);
+        assert!((palette[0][0] - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn builds_palette_nan() {
+        let s = QuadStructure {
+            map: QuadMap::from([
+                (Vec2::ZERO, Quad::from(Rgba([f32::NAN, 0.2, 0.3, 1.0]))),
+                (
+                    Vec2 { x: 1, y: 0 },
+                    Quad::from(Rgba([0.3f32, 0.2, 0.1, 1.0])),
+                ),
+            ]),
+            sizes: vec![Vec2 { x: 2, y: 1 }],
+        };
+        assert_eq!(build_palette(&s, 2, Quantizer::MedianCut).len(), 2);
+    }
+}
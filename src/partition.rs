@@ -0,0 +1,563 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::GenericImageView;
+use log::trace;
+use rayon::prelude::*;
+
+use crate::quad::{aggregate_colors, Aggregation, Guides};
+use crate::tree::{NodeId, QuadTree};
+use crate::utils::{luma, Quad, RgbaPixel, Vec2, VecQuad};
+
+/// how a quad is split
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub(crate) enum Partition {
+    /// Four quadrants, halving both sides
+    #[default]
+    Quad,
+    /// Two halves, cutting the longer side
+    Binary,
+    /// Two rectangles, cutting where the luma of the two sides varies the least
+    Kd,
+    /// Four square quadrants, inside a grid of square cells tiling the image
+    Square,
+    /// Two right isosceles triangles, bisecting the right angle
+    Triangle,
+    /// Four hexagons half as big, the central one and three on the edges
+    Hexagon,
+}
+
+/// the partitions tiling the image with rectangles, built as a tree by `calc_quads`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum RectPartition {
+    #[default]
+    Quad,
+    Binary,
+    Kd,
+    Square,
+}
+
+impl Partition {
+    /// the partition as rectangles, None for the shapes
+    pub(crate) fn rects(self) -> Option<RectPartition> {
+        match self {
+            Partition::Quad => Some(RectPartition::Quad),
+            Partition::Binary => Some(RectPartition::Binary),
+            Partition::Kd => Some(RectPartition::Kd),
+            Partition::Square => Some(RectPartition::Square),
+            Partition::Triangle | Partition::Hexagon => None,
+        }
+    }
+}
+
+/// how the cells on the right and bottom edges fit the image
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub(crate) enum CellEdges {
+    /// The cells are cut at the edges of the image
+    #[default]
+    Crop,
+    /// The image is padded with transparent pixels to whole cells, the output is cropped back
+    Pad,
+}
+
+/// side of the square cells tiling an image, the largest power of two fitting in it
+pub(crate) fn cell_side(size: &Vec2) -> u32 {
+    1 << size.x.min(size.y).max(1).ilog2()
+}
+
+/// the given side of the square cells, if it fits in the image
+pub(crate) fn fitting_cell_side(size: &Vec2, cell_size: Option<u32>) -> u32 {
+    let largest = cell_side(size);
+    cell_size.map_or(largest, |side| side.min(largest))
+}
+
+/// size of the image grown to whole cells
+pub(crate) fn padded_size(size: &Vec2, side: u32) -> Vec2 {
+    Vec2 {
+        x: size.x.div_ceil(side) * side,
+        y: size.y.div_ceil(side) * side,
+    }
+}
+
+/// a rectangle of the image
+type Rect = (Vec2, Vec2);
+
+/// splits the image in rectangles, each cut in two by the given partition
+pub(crate) fn calc_rects<I, P>(
+    img: &I,
+    min_quad_size: &Vec2,
+    min_depth: u8,
+    treshold: &P,
+    do_calc_color: bool,
+    aggregation: Aggregation,
+    guides: &Guides,
+) -> QuadTree<P>
+where
+    I: GenericImageView<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    // each cut halves the area, twice as many as the quads
+    let max_depth = ((img.width() * img.height()) as f64).log2() as u8;
+    trace!("Max iterations: {max_depth}");
+
+    let mut quads = QuadTree::new(Vec2::from(img.dimensions()));
+    let root = quads.add_root(Vec2::ZERO, quads.sizes[0], Quad::new(0));
+    let mut rects_in: Vec<(NodeId, Rect)> = vec![(root, (Vec2::ZERO, quads.sizes[0]))];
+    let mut curr_depth: u8 = 1;
+
+    while curr_depth < max_depth && !rects_in.is_empty() {
+        trace!("Iteration: {}, rects {}", curr_depth, rects_in.len());
+        let rects_out = Vec::from_par_iter(
+            rects_in
+                .par_iter()
+                .map(|(id, (pos, size))| -> Option<(NodeId, [VecQuad<P>; 2])> {
+                    let limits = guides.limits(pos, size, min_quad_size, min_depth, treshold)?;
+                    let cut = cut(img, pos, size, &limits.min_size, guides.partition)?;
+                    let mut subs = cut.map(|(p, s)| {
+                        let mut quad = Quad::new(curr_depth);
+                        quad.size = Some(s);
+                        VecQuad(p, quad)
+                    });
+                    if curr_depth > limits.min_depth || do_calc_color {
+                        // colors are needed only to be compared or kept
+                        let averages = (guides.split.by_color() || do_calc_color)
+                            .then(|| cut.map(|(p, s)| aggregate_colors(img, &p, &s, aggregation)));
+                        let parts = averages.as_ref().map(|a| a.as_slice());
+                        if !guides.splits(pos, size, parts, &limits) {
+                            return None;
+                        }
+                        if let Some(averages) = averages.filter(|_| do_calc_color) {
+                            subs[0].1.color = Some(*P::from_slice(&averages[0]));
+                            subs[1].1.color = Some(*P::from_slice(&averages[1]));
+                        }
+                    }
+                    Some((*id, subs))
+                })
+                .flatten(),
+        );
+        rects_in = Vec::with_capacity(rects_out.len() * 2);
+        for (parent, subs) in rects_out {
+            for vq in subs {
+                let size = vq.1.size.unwrap();
+                rects_in.push((quads.add_child(parent, vq.0, size, vq.1), (vq.0, size)));
+            }
+        }
+        curr_depth += 1;
+    }
+
+    if do_calc_color && quads.node(root).leaf {
+        quads.node_mut(root).quad.color = Some(*P::from_slice(&aggregate_colors(
+            img,
+            &Vec2::ZERO,
+            &quads.sizes[0],
+            aggregation,
+        )));
+    }
+    quads
+}
+
+/// splits the image in a grid of square cells, each split in a square quadtree
+pub(crate) fn calc_cells<I, P>(
+    img: &I,
+    min_quad_size: &Vec2,
+    min_depth: u8,
+    treshold: &P,
+    do_calc_color: bool,
+    aggregation: Aggregation,
+    guides: &Guides,
+) -> QuadTree<P>
+where
+    I: GenericImageView<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    let img_size = Vec2::from(img.dimensions());
+    // bigger cells would leave a single root too large to split evenly
+    let side = fitting_cell_side(&img_size, guides.cell_size);
+    // the part of a square inside the image
+    let clip = |pos: &Vec2, side: u32| Vec2 {
+        x: side.min(img_size.x - pos.x),
+        y: side.min(img_size.y - pos.y),
+    };
+    // the squares of the given side at the given offsets from a position, inside the image
+    let squares = |pos: Vec2, side: u32, count: Vec2| {
+        (0..count.y)
+            .flat_map(move |y| (0..count.x).map(move |x| (x, y)))
+            .map(move |(x, y)| Vec2 {
+                x: pos.x + x * side,
+                y: pos.y + y * side,
+            })
+            .filter(|p| p.x < img_size.x && p.y < img_size.y)
+    };
+
+    let mut quads = QuadTree::new(img_size);
+    let grid = padded_size(&img_size, side);
+    let cells: Vec<Vec2> = squares(
+        Vec2::ZERO,
+        side,
+        Vec2 {
+            x: grid.x / side,
+            y: grid.y / side,
+        },
+    )
+    .collect();
+    trace!("tiling with {} cells of side {side}", cells.len());
+    let mut nodes: Vec<(NodeId, Vec2)> = Vec::with_capacity(cells.len());
+    for pos in cells.iter() {
+        let size = clip(pos, side);
+        let mut quad = Quad::new(0);
+        quad.size = Some(size);
+        if do_calc_color {
+            quad.color = Some(*P::from_slice(&aggregate_colors(
+                img,
+                pos,
+                &size,
+                aggregation,
+            )));
+        }
+        nodes.push((quads.add_root(*pos, size, quad), *pos));
+    }
+
+    let mut curr_depth: u8 = 1;
+    while !nodes.is_empty() {
+        let parent_side = side >> (curr_depth - 1);
+        let curr_side = parent_side / 2;
+        if curr_side == 0
+            || &(Vec2 {
+                x: curr_side,
+                y: curr_side,
+            }) < min_quad_size
+        {
+            trace!("reached minimum possible quad size!");
+            break;
+        }
+        trace!("Iteration: {}, side {}", curr_depth, curr_side);
+
+        let nodes_out = Vec::from_par_iter(
+            nodes
+                .par_iter()
+                .map(|(id, node)| -> Option<(NodeId, Vec<VecQuad<P>>)> {
+                    let parent_size = clip(node, parent_side);
+                    let limits =
+                        guides.limits(node, &parent_size, min_quad_size, min_depth, treshold)?;
+                    if (Vec2 {
+                        x: curr_side,
+                        y: curr_side,
+                    }) < limits.min_size
+                    {
+                        return None;
+                    }
+                    let mut subs: Vec<_> = squares(*node, curr_side, Vec2 { x: 2, y: 2 })
+                        .map(|p| {
+                            let mut quad = Quad::new(curr_depth);
+                            quad.size = Some(clip(&p, curr_side));
+                            VecQuad(p, quad)
+                        })
+                        .collect();
+                    if curr_depth > limits.min_depth || do_calc_color {
+                        // colors are needed only to be compared or kept
+                        let averages = (guides.split.by_color() || do_calc_color).then(|| {
+                            subs.iter()
+                                .map(|s| {
+                                    aggregate_colors(img, &s.0, &s.1.size.unwrap(), aggregation)
+                                })
+                                .collect::<Vec<_>>()
+                        });
+                        if !guides.splits(node, &parent_size, averages.as_deref(), &limits) {
+                            return None;
+                        }
+                        if let Some(averages) = averages.filter(|_| do_calc_color) {
+                            for (sub, avg) in subs.iter_mut().zip(averages) {
+                                sub.1.color = Some(*P::from_slice(&avg));
+                            }
+                        }
+                    }
+                    Some((*id, subs))
+                })
+                .flatten(),
+        );
+        nodes = Vec::with_capacity(nodes_out.len() * 4);
+        for (parent, subs) in nodes_out {
+            for vq in subs {
+                let size = vq.1.size.unwrap();
+                nodes.push((quads.add_child(parent, vq.0, size, vq.1), vq.0));
+            }
+        }
+        curr_depth += 1;
+    }
+    quads
+}
+
+/// the two rectangles the given one is cut in, None if they would be smaller than the minimum
+fn cut<I, P>(
+    img: &I,
+    pos: &Vec2,
+    size: &Vec2,
+    min: &Vec2,
+    partition: RectPartition,
+) -> Option<[Rect; 2]>
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let (vertical, at) = match partition {
+        RectPartition::Binary => {
+            let vertical = size.x >= size.y;
+            let (len, min) = if vertical {
+                (size.x, min.x)
+            } else {
+                (size.y, min.y)
+            };
+            let at = len / 2;
+            (at >= min.max(1)).then_some((vertical, at))?
+        }
+        _ => best_cut(img, pos, size, min)?,
+    };
+    Some(match vertical {
+        true => [
+            (*pos, Vec2 { x: at, y: size.y }),
+            (
+                Vec2 {
+                    x: pos.x + at,
+                    y: pos.y,
+                },
+                Vec2 {
+                    x: size.x - at,
+                    y: size.y,
+                },
+            ),
+        ],
+        false => [
+            (*pos, Vec2 { x: size.x, y: at }),
+            (
+                Vec2 {
+                    x: pos.x,
+                    y: pos.y + at,
+                },
+                Vec2 {
+                    x: size.x,
+                    y: size.y - at,
+                },
+            ),
+        ],
+    })
+}
+
+/// the cut with the least sum of squared errors of the luma of the two sides,
+/// as if the cut is vertical and its position. Ties go to the most centered cut
+fn best_cut<I, P>(img: &I, pos: &Vec2, size: &Vec2, min: &Vec2) -> Option<(bool, u32)>
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    // sums of the luma and of its square of each column and row
+    let mut cols = vec![(0.0, 0.0); size.x as usize];
+    let mut rows = vec![(0.0, 0.0); size.y as usize];
+    for (x, y, p) in img.view(pos.x, pos.y, size.x, size.y).pixels() {
+        let l = luma(&p);
+        for (s, sq) in [&mut cols[x as usize], &mut rows[y as usize]] {
+            *s += l;
+            *sq += l * l;
+        }
+    }
+
+    let mut best: Option<(f64, f64, bool, u32)> = None;
+    for (vertical, lines, min, across) in
+        [(true, &cols, min.x, size.y), (false, &rows, min.y, size.x)]
+    {
+        let len = lines.len() as u32;
+        let (total, total_sq) = lines
+            .iter()
+            .fold((0.0, 0.0), |(s, sq), l| (s + l.0, sq + l.1));
+        let sse = |s: f64, sq: f64, n: u32| sq - s * s / (n as f64 * across as f64);
+        let (mut s, mut sq) = (0.0, 0.0);
+        for (at, line) in (1..len).zip(lines) {
+            s += line.0;
+            sq += line.1;
+            if at < min.max(1) || len - at < min.max(1) {
+                continue;
+            }
+            let cost = sse(s, sq, at) + sse(total - s, total_sq - sq, len - at);
+            // how far the cut is from the center
+            let off = (at as f64 / len as f64 - 0.5).abs();
+            let better = match best {
+                None => true,
+                Some((c, o, ..)) if (cost - c).abs() <= f64::EPSILON * (1.0 + c.abs()) * 64.0 => {
+                    off < o
+                }
+                Some((c, ..)) => cost < c,
+            };
+            if better {
+                best = Some((cost, off, vertical, at));
+            }
+        }
+    }
+    best.map(|(.., vertical, at)| (vertical, at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quad::*;
+    use crate::utils::QuadStructure;
+    use image::{Rgba, RgbaImage};
+    use test_case::test_case;
+
+    fn calc(img: &RgbaImage, partition: RectPartition, min_size: Vec2) -> QuadStructure {
+        QuadStructure::from(calc_quads(
+            img,
+            &min_size,
+            0,
+            &Rgba([0, 0, 0, 0]),
+            true,
+            Aggregation::Mean,
+            &Guides {
+                partition,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// sum of the areas of the rectangles
+    fn area(structure: &QuadStructure) -> u32 {
+        structure
+            .map
+            .values()
+            .map(|q| structure.size_of(q))
+            .map(|s| s.x * s.y)
+            .sum()
+    }
+
+    #[test_case(Vec2 { x: 1920, y: 1080 } => 1024; "full-hd")]
+    #[test_case(Vec2 { x: 64, y: 300 } => 64; "power")]
+    #[test_case(Vec2 { x: 1, y: 1 } => 1; "pixel")]
+    fn sizes_cells(size: Vec2) -> u32 {
+        cell_side(&size)
+    }
+
+    #[test_case(None => 32; "default")]
+    #[test_case(Some(8) => 8; "given")]
+    #[test_case(Some(256) => 32; "too-big")]
+    fn fits_cells(cell_size: Option<u32>) -> u32 {
+        fitting_cell_side(&Vec2 { x: 100, y: 40 }, cell_size)
+    }
+
+    #[test]
+    fn tiles_square_cells() {
+        let img = RgbaImage::from_fn(100, 40, |x, y| {
+            let v = ((x * x + 3 * y * y) % 251) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let structure = calc(&img, RectPartition::Square, Vec2 { x: 4, y: 4 });
+        assert_eq!(area(&structure), 100 * 40);
+        for (pos, q) in structure.map.iter() {
+            let side = 32 >> q.depth;
+            let size = structure.size_of(q);
+            // squares aligned to their side, unless cut by the edges
+            assert_eq!((pos.x % side, pos.y % side), (0, 0));
+            assert_eq!(size.x, side.min(100 - pos.x));
+            assert_eq!(size.y, side.min(40 - pos.y));
+        }
+        assert!(structure.depth() > 1);
+    }
+
+    #[test]
+    fn clamps_cell_size() {
+        let img = RgbaImage::from_fn(100, 40, |x, y| Rgba([(x * 2) as u8, (y * 6) as u8, 0, 255]));
+        let guides = Guides {
+            partition: RectPartition::Square,
+            cell_size: Some(256),
+            ..Default::default()
+        };
+        let structure = QuadStructure::from(calc_quads(
+            &img,
+            &Vec2 { x: 4, y: 4 },
+            0,
+            &Rgba([0, 0, 0, 0]),
+            true,
+            Aggregation::Mean,
+            &guides,
+        ));
+        let expected = calc(&img, RectPartition::Square, Vec2 { x: 4, y: 4 });
+        assert_eq!(structure.map.len(), expected.map.len());
+        assert_eq!(area(&structure), 100 * 40);
+    }
+
+    #[test_case(Vec2 { x: 64, y: 16 } => Some((true, 32)); "wide")]
+    #[test_case(Vec2 { x: 16, y: 64 } => Some((false, 32)); "tall")]
+    #[test_case(Vec2 { x: 7, y: 7 } => Some((true, 3)); "odd")]
+    #[test_case(Vec2 { x: 5, y: 3 } => None; "too-small")]
+    fn cuts_binary(size: Vec2) -> Option<(bool, u32)> {
+        let img = RgbaImage::new(size.x, size.y);
+        let [a, b] = cut(
+            &img,
+            &Vec2::ZERO,
+            &size,
+            &Vec2 { x: 3, y: 3 },
+            RectPartition::Binary,
+        )?;
+        assert_eq!(a.1.x * a.1.y + b.1.x * b.1.y, size.x * size.y);
+        Some((a.1.y == size.y, if a.1.y == size.y { a.1.x } else { a.1.y }))
+    }
+
+    #[test_case(5, 16 => Some((true, 5)); "vertical")]
+    #[test_case(16, 11 => Some((false, 11)); "horizontal")]
+    #[test_case(16, 16 => Some((true, 8)); "flat")]
+    fn cuts_at_best_position(x_edge: u32, y_edge: u32) -> Option<(bool, u32)> {
+        let img = RgbaImage::from_fn(16, 16, |x, y| match x < x_edge && y < y_edge {
+            true => Rgba([255, 255, 255, 255]),
+            false => Rgba([0, 0, 0, 255]),
+        });
+        best_cut(
+            &img,
+            &Vec2::ZERO,
+            &Vec2 { x: 16, y: 16 },
+            &Vec2 { x: 1, y: 1 },
+        )
+    }
+
+    #[test_case(RectPartition::Binary; "binary")]
+    #[test_case(RectPartition::Kd; "kd")]
+    fn covers_panoramas(partition: RectPartition) {
+        let img = RgbaImage::from_fn(96, 20, |x, y| {
+            let v = ((x * x + 3 * y * y) % 251) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let structure = calc(&img, partition, Vec2 { x: 4, y: 4 });
+        assert_eq!(area(&structure), 96 * 20);
+        for (pos, q) in structure.map.iter() {
+            let size = structure.size_of(q);
+            assert!(size.x >= 4 && size.y >= 4);
+            assert!(pos.x + size.x <= 96 && pos.y + size.y <= 20);
+            // cutting the longer side keeps the rectangles close to squares
+            if partition == RectPartition::Binary {
+                assert!(size.x <= size.y * 2 + 1 && size.y <= size.x * 2 + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn kd_cuts_on_the_edge() {
+        // a single cut separates the two colors
+        let img = RgbaImage::from_fn(20, 8, |x, _| match x < 13 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 255]),
+        });
+        let structure = calc(&img, RectPartition::Kd, Vec2 { x: 1, y: 1 });
+        assert_eq!(structure.map.len(), 2);
+        assert_eq!(
+            structure.map[&Vec2 { x: 13, y: 0 }].size,
+            Some(Vec2 { x: 7, y: 8 })
+        );
+    }
+}
@@ -13,24 +13,286 @@
  * limitations under the License.
  */
 
+use crate::edges::EdgeMap;
+use crate::entropy::{EntropyMap, ENTROPY_BINS};
+use crate::mask::{MaskMode, RegionMask};
+use crate::partition::{calc_cells, calc_rects, RectPartition};
+use crate::tree::{NodeId, QuadNode, QuadTree};
 use crate::utils::*;
 use image::*;
 use log::trace;
+use num_traits::{NumCast, ToPrimitive};
 use rayon::prelude::*;
+use std::collections::HashMap;
 
 pub(super) const DEFAULT_MIN_DEPTH: u8 = 4;
 pub(super) const DEFAULT_COLOR: Rgba<u8> = Rgba([255, 20, 147, 255]); //DeepPink
 pub(super) const DEFAULT_TRESHOLD: Rgba<u8> = Rgba([8, 8, 8, 8]);
 pub(super) const DEFAULT_MIN_SIZE: Vec2 = Vec2 { x: 4, y: 4 };
+pub(super) const DEFAULT_EDGE_THRESHOLD: f64 = 0.05;
+pub(super) const DEFAULT_ENTROPY_THRESHOLD: f64 = 4.0;
+pub(super) const DEFAULT_DISTANCE_THRESHOLD: f64 = 0.04;
+/// maximum number of pixels compared with each other by the vector median
+const VECTOR_MEDIAN_SAMPLES: usize = 256;
+/// bits kept of each channel when building the histogram for the dominant color
+const DOMINANT_BITS: u32 = 4;
+
+/// how the color of a quad is calculated from its pixels
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum Aggregation {
+    /// Arithmetic mean of each channel
+    Mean,
+    /// Median of each channel
+    Median,
+    /// Pixel with the least distance from all the others, estimated on a sample of pixels
+    VectorMedian,
+    /// Mean of the most common colors
+    Dominant,
+}
+
+/// what decides if a quad is split
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub(crate) enum Criterion {
+    /// The colors of the quadrants differ more than the threshold
+    Color,
+    /// The edge density of the quad is above the edge threshold
+    Edges,
+    /// The entropy of the luma histogram of the quad is above the entropy threshold
+    Entropy,
+}
+
+/// how the colors of the quadrants are compared
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub(crate) enum Distance {
+    /// Each channel differs at most by the threshold color
+    #[default]
+    Channels,
+    /// The luma differs at most by the distance threshold
+    Luma,
+    /// The weighted euclidean distance of the channels is at most the distance threshold
+    Weighted,
+}
+
+/// compares the colors of the quadrants
+#[derive(Clone, Copy, Debug)]
+pub struct ColorDistance {
+    pub distance: Distance,
+    pub luma: LumaStandard,
+    /// RGBA weights of the weighted distance
+    pub weights: [f64; 4],
+    /// the alpha channel is not compared, nor premultiplied in the luma
+    pub ignore_alpha: bool,
+    /// maximum luma or weighted distance, from 0 to 1
+    pub threshold: f64,
+}
+
+impl Default for ColorDistance {
+    fn default() -> Self {
+        Self {
+            distance: Distance::Channels,
+            luma: LumaStandard::Rec709,
+            weights: [1.0; 4],
+            ignore_alpha: false,
+            threshold: DEFAULT_DISTANCE_THRESHOLD,
+        }
+    }
+}
+
+impl ColorDistance {
+    /// if the colors are close enough to not split.
+    /// `treshold` is used by the channels distance, `scalar_treshold` by the others
+    fn are_le_treshold<T: Primitive, P: Pixel<Subpixel = T>>(
+        &self,
+        sub_averages: &[[T; 4]],
+        treshold: &P,
+        scalar_treshold: f64,
+    ) -> bool {
+        match self.distance {
+            Distance::Channels => {
+                let mut treshold = *treshold;
+                if self.ignore_alpha {
+                    treshold.channels_mut()[3] = T::DEFAULT_MAX_VALUE;
+                }
+                are_le_treshold(sub_averages, &treshold)
+            }
+            Distance::Luma => {
+                let lumas: Vec<f64> = sub_averages
+                    .iter()
+                    .map(|c| {
+                        let c = normalized(c);
+                        let alpha = if self.ignore_alpha { 1.0 } else { c[3] };
+                        self.luma.luma(&c) * alpha
+                    })
+                    .collect();
+                let max = lumas.iter().copied().fold(f64::MIN, f64::max);
+                let min = lumas.iter().copied().fold(f64::MAX, f64::min);
+                max - min <= scalar_treshold
+            }
+            Distance::Weighted => {
+                let mut weights = self.weights;
+                if self.ignore_alpha {
+                    weights[3] = 0.0;
+                }
+                let total: f64 = weights.iter().sum();
+                let colors: Vec<_> = sub_averages.iter().map(|c| normalized(c)).collect();
+                // every pair of quadrants
+                (0..colors.len()).all(|i| {
+                    (i + 1..colors.len()).all(|j| {
+                        let d: f64 = (0..4)
+                            .map(|k| weights[k] * (colors[i][k] - colors[j][k]).powi(2))
+                            .sum();
+                        total == 0.0 || (d / total).sqrt() <= scalar_treshold
+                    })
+                })
+            }
+        }
+    }
+}
+
+/// the split criterion with the data it needs
+pub enum SplitBy {
+    Color(ColorDistance),
+    /// edge density of the image and the threshold
+    Edges(EdgeMap, f64),
+    /// luma histograms of the image and the threshold in bits
+    Entropy(EntropyMap, f64),
+}
+
+impl Default for SplitBy {
+    fn default() -> Self {
+        SplitBy::Color(ColorDistance::default())
+    }
+}
+
+impl SplitBy {
+    /// if the colors of the parts of a node are needed to decide the split
+    pub(crate) fn by_color(&self) -> bool {
+        matches!(self, SplitBy::Color(_))
+    }
+
+    /// threshold and its maximum, unused by the channels color distance
+    fn threshold(&self) -> (f64, f64) {
+        match self {
+            SplitBy::Color(d) => (d.threshold, 1.0),
+            SplitBy::Edges(_, t) => (*t, 1.0),
+            SplitBy::Entropy(_, t) => (*t, (ENTROPY_BINS as f64).log2()),
+        }
+    }
+}
+
+/// data prepared once per image guiding the subdivision
+#[derive(Default)]
+pub struct Guides<'a> {
+    /// regions of interest
+    pub mask: Option<&'a RegionMask>,
+    pub split: SplitBy,
+    pub partition: RectPartition,
+    /// side of the square cells, chosen from the image size if missing
+    pub cell_size: Option<u32>,
+}
+
+/// arguments of the split of a node, changed by the mask for its region
+pub(crate) struct NodeLimits<P> {
+    pub min_size: Vec2,
+    pub min_depth: u8,
+    pub treshold: P,
+    pub split_treshold: f64,
+}
+
+impl Guides<'_> {
+    /// the arguments of the split of the node, None if it's excluded by the mask
+    pub(crate) fn limits<P: RgbaPixel>(
+        &self,
+        pos: &Vec2,
+        size: &Vec2,
+        min_quad_size: &Vec2,
+        min_depth: u8,
+        treshold: &P,
+    ) -> Option<NodeLimits<P>> {
+        let mut limits = NodeLimits {
+            min_size: *min_quad_size,
+            min_depth,
+            treshold: *treshold,
+            split_treshold: self.split.threshold().0,
+        };
+        if let Some(mask) = self.mask {
+            if mask.excludes(pos, size) {
+                return None;
+            }
+            if mask.mode() == MaskMode::Modulate {
+                let weight = mask.weight(pos, size);
+                limits.min_size = mask.min_size(min_quad_size, weight);
+                limits.min_depth = mask.min_depth(min_depth, weight);
+                let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+                for t in limits.treshold.channels_mut() {
+                    let v = mask.threshold(t.to_f64().unwrap(), max, weight);
+                    *t = NumCast::from(v).unwrap();
+                }
+                let (t, split_max) = self.split.threshold();
+                limits.split_treshold = mask.threshold(t, split_max, weight);
+            }
+        }
+        Some(limits)
+    }
+
+    /// if the node is split, given the colors of its parts when splitting by color
+    pub(crate) fn splits<P: RgbaPixel>(
+        &self,
+        pos: &Vec2,
+        size: &Vec2,
+        parts: Option<&[[P::Subpixel; 4]]>,
+        limits: &NodeLimits<P>,
+    ) -> bool {
+        match self.split {
+            SplitBy::Color(ref distance) => {
+                !distance.are_le_treshold(parts.unwrap(), &limits.treshold, limits.split_treshold)
+            }
+            SplitBy::Edges(ref edges, _) => edges.density(pos, size) > limits.split_treshold,
+            SplitBy::Entropy(ref entropy, _) => entropy.entropy(pos, size) > limits.split_treshold,
+        }
+    }
+}
 
 //TODO add more tests
-pub fn calc_quads(
-    img: &DynamicImage,
+pub fn calc_quads<I, P>(
+    img: &I,
     min_quad_size: &Vec2,
     min_depth: u8,
-    treshold: &Rgba<u8>,
+    treshold: &P,
     do_calc_color: bool,
-) -> QuadStructure {
+    aggregation: Aggregation,
+    guides: &Guides,
+) -> QuadTree<P>
+where
+    I: GenericImageView<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    // the other partitions keep their own sizes
+    match guides.partition {
+        RectPartition::Quad => {}
+        RectPartition::Binary | RectPartition::Kd => {
+            return calc_rects(
+                img,
+                min_quad_size,
+                min_depth,
+                treshold,
+                do_calc_color,
+                aggregation,
+                guides,
+            )
+        }
+        RectPartition::Square => {
+            return calc_cells(
+                img,
+                min_quad_size,
+                min_depth,
+                treshold,
+                do_calc_color,
+                aggregation,
+                guides,
+            )
+        }
+    }
     trace!(
         "will {} keeping color averages",
         match do_calc_color {
@@ -42,11 +304,9 @@ pub fn calc_quads(
     let max_depth = ((img.width() * img.height()) as f64).log2() as u8 / 2;
     trace!("Max iterations: {max_depth}");
 
-    let mut quads = QuadStructure {
-        map: QuadMap::new(),
-        sizes: vec![Vec2::from(img.dimensions())],
-    };
-    let mut quadinf_in: Vec<Vec2> = vec![Vec2::ZERO];
+    let mut quads = QuadTree::new(Vec2::from(img.dimensions()));
+    let root = quads.add_root(Vec2::ZERO, quads.sizes[0], Quad::new(0));
+    let mut quadinf_in: Vec<NodeId> = vec![root];
     let mut curr_depth: u8 = 1;
 
     // fino a che non è finita l'immagine o
@@ -54,7 +314,8 @@ pub fn calc_quads(
         // halves size at each iteration
         let (curr_size, modulo) = quads.sizes.last().unwrap().half();
 
-        if &curr_size < min_quad_size {
+        // leaves with no pixels wouldn't tile anything
+        if &curr_size < min_quad_size || curr_size.x == 0 || curr_size.y == 0 {
             trace!("reached minimum possible quad size!");
             break;
         }
@@ -70,109 +331,236 @@ pub fn calc_quads(
         let quadinf_out = Vec::from_par_iter(
             quadinf_in
                 .par_iter()
-                .map(|node| -> Option<[VecQuad; 4]> {
-                    let mut subs = generate_subnodes(node, &curr_size, &modulo, curr_depth);
-                    if curr_depth > min_depth || do_calc_color {
-                        let averages = [
-                            average_colors(img, &subs[0].0, &curr_size),
-                            average_colors(img, &subs[1].0, &curr_size),
-                            average_colors(img, &subs[2].0, &curr_size),
-                            average_colors(img, &subs[3].0, &curr_size),
-                        ];
-                        if are_le_treshold(&averages, treshold) {
+                .map(|id| -> Option<(NodeId, [VecQuad<P>; 4])> {
+                    // the exact region, the halved size can be a pixel smaller
+                    let QuadNode { pos, size, .. } = quads.node(*id);
+                    let limits = guides.limits(pos, size, min_quad_size, min_depth, treshold)?;
+                    if curr_size < limits.min_size {
+                        return None;
+                    }
+
+                    let mut subs = generate_subnodes(pos, size, &curr_size, curr_depth);
+                    if curr_depth > limits.min_depth || do_calc_color {
+                        // colors are needed only to be compared or kept
+                        let average = |vq: &VecQuad<P>| {
+                            let size = vq.1.size.unwrap_or(curr_size);
+                            aggregate_colors(img, &vq.0, &size, aggregation)
+                        };
+                        let averages = (guides.split.by_color() || do_calc_color).then(|| {
+                            [
+                                average(&subs[0]),
+                                average(&subs[1]),
+                                average(&subs[2]),
+                                average(&subs[3]),
+                            ]
+                        });
+                        let parts = averages.as_ref().map(|a| a.as_slice());
+                        if !guides.splits(pos, size, parts, &limits) {
                             return None;
                         }
                         // assign colors
-                        if do_calc_color {
-                            subs[0].1.color = Some(image::Rgba(averages[0]));
-                            subs[1].1.color = Some(image::Rgba(averages[1]));
-                            subs[2].1.color = Some(image::Rgba(averages[2]));
-                            subs[3].1.color = Some(image::Rgba(averages[3]));
+                        if let Some(averages) = averages.filter(|_| do_calc_color) {
+                            subs[0].1.color = Some(*P::from_slice(&averages[0]));
+                            subs[1].1.color = Some(*P::from_slice(&averages[1]));
+                            subs[2].1.color = Some(*P::from_slice(&averages[2]));
+                            subs[3].1.color = Some(*P::from_slice(&averages[3]));
                         }
                     }
-                    Some(subs)
+                    Some((*id, subs))
                 })
-                .flatten() // flats Option, removes None
-                .flatten(), // SelectMany
+                .flatten(), // flats Option, removes None
         );
         // doing it sequencially avoids concurrency issues. it's still fast af tho
-        quadinf_in = Vec::with_capacity(quadinf_out.len());
-        for vq in quadinf_out {
-            quads.map.insert(vq.0, vq.1);
-            quadinf_in.push(vq.0);
+        quadinf_in = Vec::with_capacity(quadinf_out.len() * 4);
+        for (parent, subs) in quadinf_out {
+            for vq in subs {
+                let size = vq.1.size.unwrap_or(curr_size);
+                quadinf_in.push(quads.add_child(parent, vq.0, size, vq.1));
+            }
         }
         curr_depth += 1;
     }
 
-    if do_calc_color && !quads.map.contains_key(&Vec2::ZERO) {
-        quads.map.insert(
-            Vec2::ZERO,
-            Quad::from(Rgba(average_colors(
-                img,
-                &Vec2::ZERO,
-                &Vec2::from(img.dimensions()),
-            ))),
-        );
+    if do_calc_color && quads.node(root).leaf {
+        quads.node_mut(root).quad.color = Some(*P::from_slice(&aggregate_colors(
+            img,
+            &Vec2::ZERO,
+            &quads.sizes[0],
+            aggregation,
+        )));
     }
     quads
 }
 
-// create subnodes of the specified size for a given pos and with the given modulo in between
-fn generate_subnodes(pos: &Vec2, size: &Vec2, modulo: &Vec2, depth: u8) -> [VecQuad; 4] {
+/// create the subnodes tiling exactly the given region, the first ones
+/// taking the remainder; sizes other than the halved one are kept in the quads
+fn generate_subnodes<P>(pos: &Vec2, size: &Vec2, halved: &Vec2, depth: u8) -> [VecQuad<P>; 4] {
+    let (first, second) = size.split();
+    let sub = |x: bool, y: bool| {
+        let size = Vec2 {
+            x: if x { second.x } else { first.x },
+            y: if y { second.y } else { first.y },
+        };
+        let quad = Quad {
+            depth,
+            color: None,
+            size: (&size != halved).then_some(size),
+        };
+        let pos = Vec2 {
+            x: pos.x + x as u32 * first.x,
+            y: pos.y + y as u32 * first.y,
+        };
+        VecQuad(pos, quad)
+    };
     [
-        VecQuad(Vec2 { x: pos.x, y: pos.y }, Quad::new(depth)),
-        VecQuad(
-            Vec2 {
-                x: pos.x + size.x + modulo.x,
-                y: pos.y,
-            },
-            Quad::new(depth),
-        ),
-        VecQuad(
-            Vec2 {
-                x: pos.x,
-                y: pos.y + size.y + modulo.y,
-            },
-            Quad::new(depth),
-        ),
-        VecQuad(
-            Vec2 {
-                x: pos.x + size.x + modulo.x,
-                y: pos.y + size.y + modulo.y,
-            },
-            Quad::new(depth),
-        ),
+        sub(false, false),
+        sub(true, false),
+        sub(false, true),
+        sub(true, true),
     ]
 }
 
 /// if all the differences between each max and min RGBA are LESS than the treshold
-fn are_le_treshold(sub_averages: &[[u8; 4]; 4], treshold: &Rgba<u8>) -> bool {
+fn are_le_treshold<T: Primitive, P: Pixel<Subpixel = T>>(
+    sub_averages: &[[T; 4]],
+    treshold: &P,
+) -> bool {
     (0..4) // index R, G, B, A
         .all(|i| {
-            let max = sub_averages.iter().map(|&a| a[i]).max().unwrap_or(255);
-            let min = sub_averages.iter().map(|&a| a[i]).min().unwrap_or(0);
-            (max - min) <= treshold[i]
+            let (min, max) = sub_averages
+                .iter()
+                .map(|a| a[i])
+                .fold((T::max_value(), T::min_value()), |(min, max), v| {
+                    (if v < min { v } else { min }, if v > max { v } else { max })
+                });
+            (max - min) <= treshold.channels()[i]
         })
 }
 
+/// calculates the color of the section with the given aggregation
+pub(crate) fn aggregate_colors<I, P>(
+    img: &I,
+    pos: &Vec2,
+    size: &Vec2,
+    aggregation: Aggregation,
+) -> [P::Subpixel; 4]
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    match aggregation {
+        Aggregation::Mean => average_colors(img, pos, size),
+        Aggregation::Median => median_colors(img, pos, size),
+        Aggregation::VectorMedian => vector_median_color(img, pos, size),
+        Aggregation::Dominant => dominant_color(img, pos, size),
+    }
+}
+
 /// calculates the average of each RGBA component individually
-fn average_colors(img: &DynamicImage, pos: &Vec2, size: &Vec2) -> [u8; 4] {
+fn average_colors<I, P>(img: &I, pos: &Vec2, size: &Vec2) -> [P::Subpixel; 4]
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
     let section = img.view(pos.x, pos.y, size.x, size.y);
     let mut c: u64 = 0;
-    let mut tot: [u64; 4] = [0, 0, 0, 0];
+    // exact for any realistic size, also when truncating integer channels
+    let mut tot: [f64; 4] = [0.0; 4];
     for (_, _, p) in section.pixels() {
-        tot[0] += p[0] as u64; //R
-        tot[1] += p[1] as u64; //G
-        tot[2] += p[2] as u64; //B
-        tot[3] += p[3] as u64; //A
+        let p = p.channels();
+        tot[0] += p[0].to_f64().unwrap(); //R
+        tot[1] += p[1].to_f64().unwrap(); //G
+        tot[2] += p[2].to_f64().unwrap(); //B
+        tot[3] += p[3].to_f64().unwrap(); //A
         c += 1;
     }
-    [
-        (tot[0] / c) as u8,
-        (tot[1] / c) as u8,
-        (tot[2] / c) as u8,
-        (tot[3] / c) as u8,
-    ]
+    assert_ne!(c, 0, "attempt to divide by zero");
+    tot.map(|t| NumCast::from(t / c as f64).unwrap())
+}
+
+/// calculates the median of each RGBA component individually, the lower one if even
+fn median_colors<I, P>(img: &I, pos: &Vec2, size: &Vec2) -> [P::Subpixel; 4]
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let section = img.view(pos.x, pos.y, size.x, size.y);
+    let mut channels: [Vec<P::Subpixel>; 4] = Default::default();
+    for (_, _, p) in section.pixels() {
+        for (ch, &v) in channels.iter_mut().zip(p.channels()) {
+            ch.push(v);
+        }
+    }
+    assert!(!channels[0].is_empty(), "attempt to divide by zero");
+    channels.map(|mut ch| {
+        let mid = (ch.len() - 1) / 2;
+        *ch.select_nth_unstable_by(mid, |a, b| {
+            a.to_f64().unwrap().total_cmp(&b.to_f64().unwrap())
+        })
+        .1
+    })
+}
+
+/// finds the pixel with the least sum of distances from the others.
+/// Only an evenly spaced sample of pixels is compared on large sections
+fn vector_median_color<I, P>(img: &I, pos: &Vec2, size: &Vec2) -> [P::Subpixel; 4]
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let section = img.view(pos.x, pos.y, size.x, size.y);
+    let count = (size.x * size.y) as usize;
+    assert_ne!(count, 0, "attempt to divide by zero");
+    let step = count.div_ceil(VECTOR_MEDIAN_SAMPLES);
+    let samples: Vec<[f64; 4]> = section
+        .pixels()
+        .step_by(step)
+        .map(|(_, _, p)| to_f64(p.channels()))
+        .collect();
+    let distance = |a: &[f64; 4]| -> f64 {
+        samples
+            .iter()
+            .map(|b| (0..4).map(|i| (a[i] - b[i]).powi(2)).sum::<f64>().sqrt())
+            .sum()
+    };
+    let best = samples
+        .iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .unwrap();
+    best.map(|v| NumCast::from(v).unwrap())
+}
+
+/// calculates the mean of the pixels falling in the most common bin of a coarse histogram
+fn dominant_color<I, P>(img: &I, pos: &Vec2, size: &Vec2) -> [P::Subpixel; 4]
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+{
+    let section = img.view(pos.x, pos.y, size.x, size.y);
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    let levels = (1u32 << DOMINANT_BITS) as f64;
+    // bin : (count, sum of each channel)
+    let mut bins: HashMap<[u8; 4], (u64, [f64; 4])> = HashMap::new();
+    for (_, _, p) in section.pixels() {
+        let c = to_f64(p.channels());
+        let bin = c.map(|v| ((v / max * levels) as u32).min(levels as u32 - 1) as u8);
+        let (n, tot) = bins.entry(bin).or_insert((0, [0.0; 4]));
+        *n += 1;
+        for (t, v) in tot.iter_mut().zip(c) {
+            *t += v;
+        }
+    }
+    // ties are broken by the bin itself, to be deterministic
+    let (n, tot) = bins
+        .into_iter()
+        .max_by_key(|(bin, (n, _))| (*n, std::cmp::Reverse(*bin)))
+        .map(|(_, v)| v)
+        .expect("attempt to divide by zero");
+    tot.map(|t| NumCast::from(t / n as f64).unwrap())
+}
+
+fn to_f64<T: Primitive>(c: &[T]) -> [f64; 4] {
+    [c[0], c[1], c[2], c[3]].map(|v| v.to_f64().unwrap())
 }
 
 #[cfg(test)]
@@ -184,11 +572,20 @@ mod tests {
 
     mod calcs {
         use super::{test_case, *};
+        use crate::edges::EdgeOperator;
 
         #[test]
         fn gens_only_one_quad() {
             let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, BLACK));
-            let quadimg = calc_quads(&img, &DEFAULT_MIN_SIZE, 0, &Rgba::<u8>([0, 0, 0, 0]), true);
+            let quadimg = QuadStructure::from(calc_quads(
+                &img,
+                &DEFAULT_MIN_SIZE,
+                0,
+                &Rgba::<u8>([0, 0, 0, 0]),
+                true,
+                Aggregation::Mean,
+                &Guides::default(),
+            ));
             assert_eq!(
                 quadimg.map,
                 QuadMap::from([(Vec2::ZERO, Quad::from(BLACK))])
@@ -199,18 +596,226 @@ mod tests {
             )
         }
 
-        #[test_case(Vec2{x:0,y:0},Vec2{x:2,y:2},Vec2::ZERO,1 => vec![Vec2{x:0,y:0},Vec2{x:2,y:0},Vec2{x:0,y:2},Vec2{x:2,y:2}]; "even")]
-        #[test_case(Vec2{x:0,y:0},Vec2{x:2,y:2},Vec2{x:0,y:1},2 => vec![Vec2{x:0,y:0},Vec2{x:2,y:0},Vec2{x:0,y:3},Vec2{x:2,y:3}]; "even_modulo_y")]
-        #[test_case(Vec2{x:0,y:0},Vec2{x:2,y:2},Vec2{x:1,y:0},4 => vec![Vec2{x:0,y:0},Vec2{x:3,y:0},Vec2{x:0,y:2},Vec2{x:3,y:2}]; "even_modulo_x")]
-        #[test_case(Vec2{x:0,y:0},Vec2{x:7,y:7},Vec2{x:0,y:0},3 => vec![Vec2{x:0,y:0},Vec2{x:7,y:0},Vec2{x:0,y:7},Vec2{x:7,y:7}]; "odd")]
-        #[test_case(Vec2{x:16,y:15},Vec2{x:3,y:4},Vec2{x:0,y:0},9 => vec![Vec2{x:16,y:15},Vec2{x:19,y:15},Vec2{x:16,y:19},Vec2{x:19,y:19}]; "uneven_from_pos")]
-        fn generates_subnodes(pos: Vec2, size: Vec2, modulo: Vec2, depth: u8) -> Vec<Vec2> {
-            let subnodes = generate_subnodes(&pos, &size, &modulo, depth);
+        #[test]
+        fn builds_tree() {
+            let tree = calc_quads(
+                &noise(),
+                &Vec2 { x: 2, y: 2 },
+                0,
+                &Rgba([8, 8, 8, 8]),
+                true,
+                Aggregation::Mean,
+                &Guides::default(),
+            );
+            for (_, node) in tree.nodes() {
+                assert_eq!(node.leaf, node.children.is_empty());
+                assert_eq!(node.parent.is_none(), node.quad.depth == 0);
+                for &child in node.children.iter() {
+                    let child = tree.node(child);
+                    assert_eq!(child.size, node.size.half().0);
+                    assert_eq!(child.quad.depth, node.quad.depth + 1);
+                }
+            }
+            let leaves = tree.leaves().count();
+            assert!(leaves > 4);
+            assert_eq!(tree.roots().len(), 1);
+            assert_eq!(QuadStructure::from(tree).map.len(), leaves);
+        }
+
+        #[test]
+        fn splits_high_bit_depth() {
+            // the two halves are the same color at 8 bits
+            let mut img = ImageBuffer::from_pixel(4, 4, Rgba::<u16>([1000, 0, 0, u16::MAX]));
+            for y in 0..4 {
+                for x in 2..4 {
+                    img.put_pixel(x, y, Rgba([1100, 0, 0, u16::MAX]));
+                }
+            }
+            let quadimg = QuadStructure::from(calc_quads(
+                &img,
+                &Vec2 { x: 1, y: 1 },
+                0,
+                &Rgba([50, 0, 0, 0]),
+                true,
+                Aggregation::Mean,
+                &Guides::default(),
+            ));
+            assert_eq!(quadimg.map.len(), 4);
+            assert_eq!(
+                quadimg.map[&Vec2 { x: 2, y: 0 }].color,
+                Some(Rgba([1100, 0, 0, u16::MAX]))
+            );
+        }
+
+        /// noise, always split by color
+        fn noise() -> RgbaImage {
+            RgbaImage::from_fn(16, 16, |x, y| {
+                let v = (x * 31 + y * 17).wrapping_mul(2654435761) >> 24;
+                Rgba([v as u8, v as u8, v as u8, 255])
+            })
+        }
+
+        /// left half black, right half white
+        fn half_mask(mode: MaskMode) -> RegionMask {
+            let mask = GrayImage::from_fn(16, 16, |x, _| Luma([if x < 8 { 0 } else { 255 }]));
+            RegionMask::new(mask, mode)
+        }
+
+        fn calc_masked(mask: &RegionMask, min_depth: u8) -> QuadStructure {
+            QuadStructure::from(calc_quads(
+                &noise(),
+                &Vec2 { x: 1, y: 1 },
+                min_depth,
+                &Rgba([8, 8, 8, 8]),
+                false,
+                Aggregation::Mean,
+                &Guides {
+                    mask: Some(mask),
+                    ..Default::default()
+                },
+            ))
+        }
+
+        #[test]
+        fn splits_by_edges() {
+            // a thin line, the average colors of the quadrants are too close to split
+            let img = RgbaImage::from_fn(16, 16, |x, _| match x {
+                5 => Rgba([40, 40, 40, 255]),
+                _ => Rgba([32, 32, 32, 255]),
+            });
+            let calc = |split| {
+                QuadStructure::from(calc_quads(
+                    &img,
+                    &Vec2 { x: 1, y: 1 },
+                    0,
+                    &Rgba([8, 8, 8, 8]),
+                    false,
+                    Aggregation::Mean,
+                    &Guides {
+                        split,
+                        ..Default::default()
+                    },
+                ))
+            };
+            assert!(calc(SplitBy::default()).map.is_empty());
+
+            let edges = EdgeMap::new(&img, EdgeOperator::Sobel);
+            let quadimg = calc(SplitBy::Edges(edges, 0.001));
+            let depth = |x, y| quadimg.map[&Vec2 { x, y }].depth;
+            // smaller along the line, untouched far from it
+            assert_eq!(depth(4, 0), 3);
+            assert_eq!(depth(8, 0), 1);
+        }
+
+        #[test]
+        fn splits_by_entropy() {
+            // noise on the left, a strong but flat contrast on the right
+            let img = RgbaImage::from_fn(16, 16, |x, y| match x {
+                0..8 => {
+                    let v = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) as u8;
+                    Rgba([v, v, v, 255])
+                }
+                8..12 => Rgba([0, 0, 0, 255]),
+                _ => Rgba([255, 255, 255, 255]),
+            });
+            let quadimg = QuadStructure::from(calc_quads(
+                &img,
+                &Vec2 { x: 1, y: 1 },
+                0,
+                &Rgba([8, 8, 8, 8]),
+                false,
+                Aggregation::Mean,
+                &Guides {
+                    split: SplitBy::Entropy(EntropyMap::new(&img), 1.5),
+                    ..Default::default()
+                },
+            ));
+            let depth = |x, y| quadimg.map[&Vec2 { x, y }].depth;
+            assert!(depth(0, 0) > 1);
+            assert_eq!(depth(8, 0), 1);
+        }
+
+        #[test_case(0; "no-min-depth")]
+        #[test_case(4; "min-depth")]
+        fn excludes_masked_regions(min_depth: u8) {
+            let quadimg = calc_masked(&half_mask(MaskMode::Exclude), min_depth);
+            let left = quadimg.map.keys().filter(|p| p.x < 8).count();
+            let right = quadimg.map.keys().filter(|p| p.x >= 8).count();
+            // only the first split, as the whole image is not masked out
+            assert_eq!(left, 2);
+            assert_eq!(right, 32);
+        }
+
+        #[test]
+        fn modulates_masked_regions() {
+            let quadimg = calc_masked(&half_mask(MaskMode::Modulate), 0);
+            let left = quadimg.map.keys().filter(|p| p.x < 8).count();
+            let right = quadimg.map.keys().filter(|p| p.x >= 8).count();
+            assert!(left < right);
+        }
+
+        #[test_case(Vec2{x:0,y:0},Vec2{x:4,y:4},1 => vec![Vec2{x:0,y:0},Vec2{x:2,y:0},Vec2{x:0,y:2},Vec2{x:2,y:2}]; "even")]
+        #[test_case(Vec2{x:0,y:0},Vec2{x:4,y:5},2 => vec![Vec2{x:0,y:0},Vec2{x:2,y:0},Vec2{x:0,y:3},Vec2{x:2,y:3}]; "even_modulo_y")]
+        #[test_case(Vec2{x:0,y:0},Vec2{x:5,y:4},4 => vec![Vec2{x:0,y:0},Vec2{x:3,y:0},Vec2{x:0,y:2},Vec2{x:3,y:2}]; "even_modulo_x")]
+        #[test_case(Vec2{x:0,y:0},Vec2{x:14,y:14},3 => vec![Vec2{x:0,y:0},Vec2{x:7,y:0},Vec2{x:0,y:7},Vec2{x:7,y:7}]; "odd")]
+        #[test_case(Vec2{x:16,y:15},Vec2{x:6,y:8},9 => vec![Vec2{x:16,y:15},Vec2{x:19,y:15},Vec2{x:16,y:19},Vec2{x:19,y:19}]; "uneven_from_pos")]
+        fn generates_subnodes(pos: Vec2, size: Vec2, depth: u8) -> Vec<Vec2> {
+            let halved = size.half().0;
+            let subnodes = generate_subnodes::<Rgba<u8>>(&pos, &size, &halved, depth);
             assert!(subnodes
                 .iter()
                 .all(|vq| { vq.1.depth == depth && vq.1.color.is_none() }));
+            // the first ones take the remainder, so the four of them cover the region
+            let sizes = subnodes.each_ref().map(|vq| vq.1.size.unwrap_or(halved));
+            assert_eq!(sizes[0].x + sizes[1].x, size.x);
+            assert_eq!(sizes[0].y + sizes[2].y, size.y);
             subnodes.iter().map(|vq| vq.0).collect()
         }
+
+        proptest::proptest! {
+            #[test]
+            fn leaves_tile_image(
+                w in 1u32..80,
+                h in 1u32..80,
+                seed in 0u32..256,
+                min in 1u32..4,
+                partition in proptest::sample::select(vec![
+                    RectPartition::Quad,
+                    RectPartition::Binary,
+                    RectPartition::Kd,
+                    RectPartition::Square,
+                ]),
+            ) {
+                let img = RgbaImage::from_fn(w, h, |x, y| {
+                    let v = ((x * 31 + y * 17 + seed).wrapping_mul(2654435761) >> 24) as u8;
+                    Rgba([v, v, v, 255])
+                });
+                let structure = QuadStructure::from(calc_quads(
+                    &img,
+                    &Vec2 { x: min, y: min },
+                    0,
+                    &Rgba([16, 16, 16, 16]),
+                    true,
+                    Aggregation::Mean,
+                    &Guides {
+                        partition,
+                        ..Default::default()
+                    },
+                ));
+                // every pixel is covered by exactly one leaf
+                let mut covered = vec![0u8; (w * h) as usize];
+                for (pos, quad) in structure.map.iter() {
+                    let size = structure.size_of(quad);
+                    proptest::prop_assert!(pos.x + size.x <= w && pos.y + size.y <= h);
+                    for y in pos.y..pos.y + size.y {
+                        for x in pos.x..pos.x + size.x {
+                            covered[(y * w + x) as usize] += 1;
+                        }
+                    }
+                }
+                proptest::prop_assert!(covered.iter().all(|&c| c == 1));
+            }
+        }
     }
 
     mod colors {
@@ -239,6 +844,63 @@ mod tests {
             are_le_treshold(&matrix, &treshold)
         }
 
+        #[test_case(TEST_AVERAGES_APHAONLY, Rgba([8, 8, 8, 8]) => true; "alpha-only")]
+        #[test_case(TEST_AVERAGES_SIMPLE, Rgba([96, 96, 96, 255]) => true; "avg-le")]
+        #[test_case(TEST_AVERAGES_SIMPLE, Rgba([10, 10, 10, 255]) => false; "avg-gt")]
+        fn treshold_ignoring_alpha(matrix: [[u8; 4]; 4], treshold: Rgba<u8>) -> bool {
+            let distance = ColorDistance {
+                ignore_alpha: true,
+                ..Default::default()
+            };
+            distance.are_le_treshold(&matrix, &treshold, 0.0)
+        }
+
+        // pure red and pure blue differ a lot in Rec.601 but little in Rec.709
+        const TEST_AVERAGES_RED_BLUE: [[u8; 4]; 4] = [
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+        ];
+
+        #[test_case(TEST_AVERAGES_RED_BLUE, LumaStandard::Rec709, false => true; "rec709")]
+        #[test_case(TEST_AVERAGES_RED_BLUE, LumaStandard::Rec601, false => false; "rec601")]
+        #[test_case(TEST_AVERAGES_APHAONLY, LumaStandard::Rec709, true => true; "alpha-ignored")]
+        #[test_case(TEST_AVERAGES_APHAONLY, LumaStandard::Rec709, false => false; "alpha-premult")]
+        fn luma_treshold(matrix: [[u8; 4]; 4], luma: LumaStandard, ignore_alpha: bool) -> bool {
+            let mut matrix = matrix;
+            if ignore_alpha || matrix == TEST_AVERAGES_APHAONLY {
+                // white with varying alpha
+                matrix.iter_mut().for_each(|c| c[..3].fill(255));
+            }
+            let distance = ColorDistance {
+                distance: Distance::Luma,
+                luma,
+                ignore_alpha,
+                ..Default::default()
+            };
+            distance.are_le_treshold(&matrix, &Rgba([0; 4]), 0.15)
+        }
+
+        #[test_case([1.0, 1.0, 1.0, 1.0] => false; "uniform")]
+        #[test_case([0.0, 1.0, 0.0, 1.0] => true; "green-alpha")]
+        #[test_case([1.0, 2.0, 1.0, 0.0] => false; "green-twice")]
+        fn weighted_treshold(weights: [f64; 4]) -> bool {
+            // only red and blue change
+            let matrix: [[u8; 4]; 4] = [
+                [64, 100, 0, 255],
+                [0, 100, 64, 255],
+                [0, 101, 0, 255],
+                [64, 100, 64, 255],
+            ];
+            let distance = ColorDistance {
+                distance: Distance::Weighted,
+                weights,
+                ..Default::default()
+            };
+            distance.are_le_treshold(&matrix, &Rgba([0; 4]), 0.1)
+        }
+
         /*  creates an image split in half diagonally in black and white with a gray line in between
          *  0,0|\   |
          *     |#\  |
@@ -266,7 +928,82 @@ mod tests {
         #[test_case(Vec2{x:500,y:500}, Vec2::ZERO => panics "assertion failed: u64::from(x) + u64::from(width) <= u64::from(self.width())"; 
             "out of bounds")]
         fn averages_colors(rectp: Vec2, rects: Vec2) -> [u8; 4] {
-            average_colors(&TEST_IMAGE, &rectp, &rects)
+            average_colors(&*TEST_IMAGE, &rectp, &rects)
+        }
+
+        /* mostly white, a third red and a black column: the mean is a muddy pink
+         *  |BWWWWWRRR|
+         *  |BWWWWWRRR|
+         */
+        static TEST_STRADDLING: Lazy<DynamicImage> = Lazy::new(|| {
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 6, |x, _| -> Rgba<u8> {
+                match x {
+                    0 => Rgba([0, 0, 0, 255]),
+                    1..=5 => Rgba([255, 255, 255, 255]),
+                    _ => Rgba([255, 0, 0, 255]),
+                }
+            }))
+        });
+
+        #[test_case(Aggregation::Mean => [226, 141, 141, 255]; "mean")]
+        #[test_case(Aggregation::Median => [255, 255, 255, 255]; "median")]
+        #[test_case(Aggregation::VectorMedian => [255, 255, 255, 255]; "vector median")]
+        #[test_case(Aggregation::Dominant => [255, 255, 255, 255]; "dominant")]
+        fn aggregates_colors(aggregation: Aggregation) -> [u8; 4] {
+            aggregate_colors(
+                &*TEST_STRADDLING,
+                &Vec2::ZERO,
+                &Vec2 { x: 9, y: 6 },
+                aggregation,
+            )
+        }
+
+        #[test]
+        fn medians_channels_independently() {
+            let img = RgbaImage::from_fn(3, 1, |x, _| match x {
+                0 => Rgba([10, 200, 0, 255]),
+                1 => Rgba([20, 100, 0, 255]),
+                _ => Rgba([30, 0, 0, 255]),
+            });
+            assert_eq!(
+                median_colors(&img, &Vec2::ZERO, &Vec2 { x: 3, y: 1 }),
+                [20, 100, 0, 255]
+            );
+        }
+
+        #[test]
+        fn medians_nan() {
+            let img = Rgba32FImage::from_fn(3, 1, |x, _| match x {
+                0 => Rgba([f32::NAN, 0.0, 0.0, 1.0]),
+                1 => Rgba([0.2, 0.0, 0.0, 1.0]),
+                _ => Rgba([0.1, 0.0, 0.0, 1.0]),
+            });
+            assert_eq!(
+                median_colors(&img, &Vec2::ZERO, &Vec2 { x: 3, y: 1 }),
+                [0.2, 0.0, 0.0, 1.0]
+            );
+        }
+
+        #[test]
+        fn dominant_averages_bin() {
+            let img = RgbaImage::from_fn(4, 1, |x, _| match x {
+                0 => Rgba([100, 0, 0, 255]),
+                1 => Rgba([102, 0, 0, 255]),
+                _ => Rgba([0, 0, 255, x as u8 * 60]),
+            });
+            assert_eq!(
+                dominant_color(&img, &Vec2::ZERO, &Vec2 { x: 4, y: 1 }),
+                [101, 0, 0, 255]
+            );
+        }
+
+        #[test]
+        fn aggregates_high_bit_depth() {
+            let img = ImageBuffer::from_fn(3, 1, |x, _| Rgba([x as u16 * 1000, 0, 0, u16::MAX]));
+            assert_eq!(
+                aggregate_colors(&img, &Vec2::ZERO, &Vec2 { x: 3, y: 1 }, Aggregation::Median),
+                [1000, 0, 0, u16::MAX]
+            );
         }
     }
 }
@@ -0,0 +1,228 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::utils::{QuadStructure, Vec2};
+
+/// side of a quad another one can be found next to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// grid of square buckets over the leaves of a structure, to look them up by region
+pub struct QuadIndex {
+    /// position and size of every leaf
+    rects: Vec<(Vec2, Vec2)>,
+    /// the buckets side is 2^shift
+    shift: u32,
+    columns: u32,
+    rows: u32,
+    /// leaves overlapping each bucket, by rows
+    buckets: Vec<Vec<usize>>,
+}
+
+impl<P> QuadStructure<P> {
+    /// builds the index to query the leaves by region
+    pub fn index(&self) -> QuadIndex {
+        let rects: Vec<_> = self
+            .map
+            .iter()
+            .map(|(pos, q)| (*pos, self.size_of(q)))
+            .filter(|(_, size)| size.x > 0 && size.y > 0)
+            .collect();
+        QuadIndex::new(self.sizes[0], rects)
+    }
+}
+
+impl QuadIndex {
+    fn new(bounds: Vec2, rects: Vec<(Vec2, Vec2)>) -> Self {
+        // about one leaf per bucket, when they are all the same size
+        let area = bounds.x as f64 * bounds.y as f64;
+        let side = (area / rects.len().max(1) as f64).sqrt().max(1.0);
+        let shift = side.log2().floor() as u32;
+        let columns = bounds.x.div_ceil(1 << shift).max(1);
+        let rows = bounds.y.div_ceil(1 << shift).max(1);
+
+        let mut index = Self {
+            rects: Vec::new(),
+            shift,
+            columns,
+            rows,
+            buckets: vec![Vec::new(); (columns * rows) as usize],
+        };
+        for (i, (pos, size)) in rects.iter().enumerate() {
+            for b in index.buckets_of(pos, size) {
+                index.buckets[b].push(i);
+            }
+        }
+        index.rects = rects;
+        index
+    }
+
+    /// buckets overlapping the region
+    fn buckets_of(&self, pos: &Vec2, size: &Vec2) -> impl Iterator<Item = usize> {
+        let (x0, y0) = (pos.x >> self.shift, pos.y >> self.shift);
+        let x1 = ((pos.x + size.x - 1) >> self.shift).min(self.columns - 1);
+        let y1 = ((pos.y + size.y - 1) >> self.shift).min(self.rows - 1);
+        let columns = self.columns;
+        (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| (y * columns + x) as usize))
+    }
+
+    /// position of the leaf containing the pixel
+    pub fn leaf_at(&self, point: &Vec2) -> Option<Vec2> {
+        if point.x >> self.shift >= self.columns || point.y >> self.shift >= self.rows {
+            return None;
+        }
+        let bucket = (point.y >> self.shift) * self.columns + (point.x >> self.shift);
+        self.buckets[bucket as usize]
+            .iter()
+            .map(|&i| self.rects[i])
+            .find(|(pos, size)| contains(pos, size, point))
+            .map(|(pos, _)| pos)
+    }
+
+    /// positions of the leaves intersecting the region, by rows
+    pub fn leaves_in(&self, pos: &Vec2, size: &Vec2) -> Vec<Vec2> {
+        if size.x == 0 || size.y == 0 {
+            return Vec::new();
+        }
+        let mut found: Vec<usize> = self
+            .buckets_of(pos, size)
+            .flat_map(|b| self.buckets[b].iter().copied())
+            .filter(|&i| intersects(&self.rects[i], &(*pos, *size)))
+            .collect();
+        found.sort_unstable();
+        found.dedup();
+        let mut leaves: Vec<Vec2> = found.iter().map(|&i| self.rects[i].0).collect();
+        leaves.sort_by_key(|p| (p.y, p.x));
+        leaves
+    }
+
+    /// positions of the leaves touching the side of the region, by rows
+    pub fn neighbors(&self, pos: &Vec2, size: &Vec2, direction: Direction) -> Vec<Vec2> {
+        // the line of pixels just outside the side
+        let (strip_pos, strip_size) = match direction {
+            Direction::Up if pos.y > 0 => ((pos.x, pos.y - 1), (size.x, 1)),
+            Direction::Down => ((pos.x, pos.y + size.y), (size.x, 1)),
+            Direction::Left if pos.x > 0 => ((pos.x - 1, pos.y), (1, size.y)),
+            Direction::Right => ((pos.x + size.x, pos.y), (1, size.y)),
+            _ => return Vec::new(),
+        };
+        self.leaves_in(&strip_pos.into(), &strip_size.into())
+    }
+}
+
+fn contains(pos: &Vec2, size: &Vec2, point: &Vec2) -> bool {
+    (pos.x..pos.x + size.x).contains(&point.x) && (pos.y..pos.y + size.y).contains(&point.y)
+}
+
+fn intersects(a: &(Vec2, Vec2), b: &(Vec2, Vec2)) -> bool {
+    let ((pa, sa), (pb, sb)) = (a, b);
+    pa.x < pb.x + sb.x && pb.x < pa.x + sa.x && pa.y < pb.y + sb.y && pb.y < pa.y + sa.y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{Quad, QuadMap};
+    pub use test_case::test_case;
+
+    /* 0,0|A  |B|C|   a 8x8 image
+     *    |   |D|E|
+     *    |---|---|
+     *    |F  |G  |
+     */
+    fn structure() -> QuadStructure {
+        QuadStructure {
+            map: QuadMap::from([
+                (Vec2 { x: 0, y: 0 }, Quad::new(1)), //A
+                (Vec2 { x: 4, y: 0 }, Quad::new(2)), //B
+                (Vec2 { x: 6, y: 0 }, Quad::new(2)), //C
+                (Vec2 { x: 4, y: 2 }, Quad::new(2)), //D
+                (Vec2 { x: 6, y: 2 }, Quad::new(2)), //E
+                (Vec2 { x: 0, y: 4 }, Quad::new(1)), //F
+                (Vec2 { x: 4, y: 4 }, Quad::new(1)), //G
+            ]),
+            sizes: vec![
+                Vec2 { x: 8, y: 8 },
+                Vec2 { x: 4, y: 4 },
+                Vec2 { x: 2, y: 2 },
+            ],
+        }
+    }
+
+    #[test_case(Vec2{x:3,y:3},Some(Vec2{x:0,y:0}); "big")]
+    #[test_case(Vec2{x:5,y:3},Some(Vec2{x:4,y:2}); "small")]
+    #[test_case(Vec2{x:7,y:7},Some(Vec2{x:4,y:4}); "corner")]
+    #[test_case(Vec2{x:8,y:0},None; "outside")]
+    fn finds_leaf_at(point: Vec2, expects: Option<Vec2>) {
+        assert_eq!(structure().index().leaf_at(&point), expects)
+    }
+
+    #[test_case(Vec2{x:3,y:1},Vec2{x:2,y:2},vec![(0,0),(4,0),(4,2)]; "across")]
+    #[test_case(Vec2{x:0,y:0},Vec2{x:8,y:8},vec![(0,0),(4,0),(6,0),(4,2),(6,2),(0,4),(4,4)]; "all")]
+    #[test_case(Vec2{x:6,y:6},Vec2{x:9,y:9},vec![(4,4)]; "past-bounds")]
+    #[test_case(Vec2{x:1,y:1},Vec2{x:0,y:3},vec![]; "empty")]
+    fn finds_leaves_in(pos: Vec2, size: Vec2, expects: Vec<(u32, u32)>) {
+        let expects: Vec<Vec2> = expects.into_iter().map(Vec2::from).collect();
+        assert_eq!(structure().index().leaves_in(&pos, &size), expects)
+    }
+
+    #[test_case(Vec2{x:0,y:0},Vec2{x:4,y:4},Direction::Right,vec![(4,0),(4,2)]; "smaller")]
+    #[test_case(Vec2{x:6,y:2},Vec2{x:2,y:2},Direction::Down,vec![(4,4)]; "bigger")]
+    #[test_case(Vec2{x:4,y:2},Vec2{x:2,y:2},Direction::Up,vec![(4,0)]; "up")]
+    #[test_case(Vec2{x:4,y:4},Vec2{x:4,y:4},Direction::Left,vec![(0,4)]; "left")]
+    #[test_case(Vec2{x:0,y:0},Vec2{x:4,y:4},Direction::Up,vec![]; "border")]
+    #[test_case(Vec2{x:6,y:0},Vec2{x:2,y:2},Direction::Right,vec![]; "outside")]
+    fn finds_neighbors(pos: Vec2, size: Vec2, direction: Direction, expects: Vec<(u32, u32)>) {
+        let expects: Vec<Vec2> = expects.into_iter().map(Vec2::from).collect();
+        assert_eq!(
+            structure().index().neighbors(&pos, &size, direction),
+            expects
+        )
+    }
+
+    #[test]
+    fn indexes_many_leaves() {
+        // 1x1 leaves make buckets of a single pixel
+        let map = (0..16)
+            .flat_map(|y| (0..16).map(move |x| (Vec2 { x, y }, Quad::new(4))))
+            .collect();
+        let s: QuadStructure = QuadStructure {
+            map,
+            sizes: vec![
+                Vec2 { x: 16, y: 16 },
+                Vec2 { x: 8, y: 8 },
+                Vec2 { x: 4, y: 4 },
+                Vec2 { x: 2, y: 2 },
+                Vec2 { x: 1, y: 1 },
+            ],
+        };
+        let index = s.index();
+        assert_eq!(
+            index.leaf_at(&Vec2 { x: 9, y: 3 }),
+            Some(Vec2 { x: 9, y: 3 })
+        );
+        assert_eq!(
+            index
+                .leaves_in(&Vec2 { x: 2, y: 2 }, &Vec2 { x: 3, y: 2 })
+                .len(),
+            6
+        );
+    }
+}
@@ -0,0 +1,231 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::Rgba;
+use log::{debug, warn};
+
+use crate::args::QuadArgs;
+use crate::quad::{Criterion, Distance, DEFAULT_TRESHOLD};
+use crate::utils::Vec2;
+
+/// steps the threshold is searched among, one for each channel value
+const LEVELS: u32 = 255;
+/// highest entropy of a luma histogram, in bits
+const MAX_ENTROPY: f64 = 8.0;
+
+/// what the threshold is searched for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Target {
+    /// at most this number of leaves
+    Leaves(u64),
+    /// an output of at most this number of bytes
+    FileSize(u64),
+    /// a PSNR of the filled quads of at least this many decibels
+    Psnr(f64),
+}
+
+impl Target {
+    /// if the measure of an output meets the target
+    fn fits(&self, measure: f64) -> bool {
+        match *self {
+            Target::Leaves(n) => measure <= n as f64,
+            Target::FileSize(bytes) => measure <= bytes as f64,
+            Target::Psnr(db) => measure >= db,
+        }
+    }
+
+    /// if the target is met by higher thresholds and sizes, or by lower ones
+    fn rising(&self) -> bool {
+        !matches!(self, Target::Psnr(_))
+    }
+}
+
+/// the arguments with the threshold, and the minimum size if asked, searched to meet the target:
+/// the most detailed output that fits for the leaves and the bytes, the least for the PSNR.
+/// `measure` gives the leaves, the bytes or the PSNR of the output of the arguments
+pub(crate) fn search<F>(
+    calc: &QuadArgs,
+    target: Target,
+    min_size: bool,
+    max_side: u32,
+    mut measure: F,
+) -> QuadArgs
+where
+    F: FnMut(&QuadArgs) -> f64,
+{
+    let mut fits = |calc: &QuadArgs| {
+        let m = measure(calc);
+        debug!("{} measures {m}", describe(calc));
+        target.fits(m)
+    };
+    let level = boundary(0, LEVELS, target.rising(), |level| {
+        fits(&with_level(calc, level))
+    });
+    let mut best = with_level(calc, level.unwrap_or_else(|l| l));
+    let mut met = level.is_ok();
+    if min_size {
+        let side = boundary(1, max_side.max(1), target.rising(), |side| {
+            fits(&with_min_side(&best, side))
+        });
+        best = with_min_side(&best, side.unwrap_or_else(|s| s));
+        met |= side.is_ok();
+    }
+    if !met {
+        warn!(
+            "{target:?} can't be met, using the closest {}",
+            describe(&best)
+        );
+    }
+    best
+}
+
+/// the searched parameters of the arguments
+pub(crate) fn describe(calc: &QuadArgs) -> String {
+    let threshold = match (calc.criterion, calc.distance) {
+        (Criterion::Color, Distance::Channels) => {
+            let t = calc.threshold.unwrap_or(DEFAULT_TRESHOLD);
+            format!("{:02x}{:02x}{:02x}{:02x}", t[0], t[1], t[2], t[3])
+        }
+        (Criterion::Color, _) => format!("{:.4}", calc.distance_threshold),
+        (Criterion::Edges, _) => format!("{:.4}", calc.edge_threshold),
+        (Criterion::Entropy, _) => format!("{:.4} bits", calc.entropy_threshold),
+    };
+    let size = calc.min_quad_size;
+    format!("threshold {threshold}, min quad size {}x{}", size.x, size.y)
+}
+
+/// the first value where `fits` becomes true if rising, the last where it still is otherwise.
+/// When no value fits, the one closest to fitting as an error
+fn boundary(
+    mut lo: u32,
+    mut hi: u32,
+    rising: bool,
+    mut fits: impl FnMut(u32) -> bool,
+) -> Result<u32, u32> {
+    // the end that would fit if any value does, is checked last
+    let last = if rising { hi } else { lo };
+    while lo < hi {
+        if rising {
+            let mid = lo + (hi - lo) / 2;
+            match fits(mid) {
+                true => hi = mid,
+                false => lo = mid + 1,
+            }
+        } else {
+            let mid = lo + (hi - lo).div_ceil(2);
+            match fits(mid) {
+                true => lo = mid,
+                false => hi = mid - 1,
+            }
+        }
+    }
+    match lo != last || fits(lo) {
+        true => Ok(lo),
+        false => Err(lo),
+    }
+}
+
+/// the arguments with the threshold of their criterion at the level, from 0 to `LEVELS`.
+/// The channels of a threshold keep their proportions, with the largest at the level
+fn with_level(calc: &QuadArgs, level: u32) -> QuadArgs {
+    let mut calc = calc.clone();
+    let fraction = level as f64 / LEVELS as f64;
+    match (calc.criterion, calc.distance) {
+        (Criterion::Color, Distance::Channels) => {
+            let t = calc.threshold.unwrap_or(DEFAULT_TRESHOLD);
+            let max = *t.0.iter().max().unwrap() as u32;
+            calc.threshold = Some(Rgba(t.0.map(|c| match max {
+                0 => level as u8,
+                _ => ((c as u32 * level + max / 2) / max) as u8,
+            })));
+        }
+        (Criterion::Color, _) => calc.distance_threshold = fraction,
+        (Criterion::Edges, _) => calc.edge_threshold = fraction,
+        (Criterion::Entropy, _) => calc.entropy_threshold = fraction * MAX_ENTROPY,
+    }
+    calc
+}
+
+fn with_min_side(calc: &QuadArgs, side: u32) -> QuadArgs {
+    QuadArgs {
+        min_quad_size: Vec2 { x: side, y: side },
+        ..calc.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::{CliArgs, Commands};
+    use clap::Parser;
+    pub use test_case::test_case;
+
+    fn calc(args: &[&str]) -> QuadArgs {
+        let io = ["qom", "render", "-i", "in.png", "-o", "out.png"];
+        match CliArgs::parse_from(io.iter().chain(args)).command {
+            Commands::Render(render) => render.calc,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test_case(true, 0 => Ok(0); "rising-all")]
+    #[test_case(true, 37 => Ok(37); "rising")]
+    #[test_case(true, 255 => Ok(255); "rising-last")]
+    #[test_case(true, 300 => Err(255); "rising-none")]
+    #[test_case(false, 37 => Ok(36); "falling")]
+    #[test_case(false, 1 => Ok(0); "falling-first")]
+    #[test_case(false, 0 => Err(0); "falling-none")]
+    #[test_case(false, 300 => Ok(255); "falling-all")]
+    fn finds_boundary(rising: bool, first_true: u32) -> Result<u32, u32> {
+        // rising fits from the value on, falling before it
+        boundary(0, 255, rising, |v| (v >= first_true) == rising)
+    }
+
+    #[test_case(&[] => "threshold 40404040, min quad size 4x4"; "channels")]
+    #[test_case(&["--threshold", "#ffffff00"] => "threshold 40404000, min quad size 4x4"; "alpha-only")]
+    #[test_case(&["--threshold", "#20100800"] => "threshold 40201000, min quad size 4x4"; "proportional")]
+    #[test_case(&["--threshold", "#00000000"] => "threshold 40404040, min quad size 4x4"; "zero")]
+    #[test_case(&["--distance", "luma"] => "threshold 0.2510, min quad size 4x4"; "luma")]
+    #[test_case(&["--criterion", "edges"] => "threshold 0.2510, min quad size 4x4"; "edges")]
+    #[test_case(&["--criterion", "entropy"] => "threshold 2.0078 bits, min quad size 4x4"; "entropy")]
+    fn sets_level(args: &[&str]) -> String {
+        describe(&with_level(&calc(args), 64))
+    }
+
+    #[test_case(Target::Leaves(100) => "threshold 22222222, min quad size 4x4"; "leaves")]
+    #[test_case(Target::FileSize(100) => "threshold 22222222, min quad size 4x4"; "bytes")]
+    #[test_case(Target::Psnr(25.0) => "threshold 32323232, min quad size 4x4"; "psnr")]
+    fn searches_threshold(target: Target) -> String {
+        // the measures fall as the threshold grows
+        let found = search(&calc(&[]), target, false, 16, |c| {
+            let t = c.threshold.unwrap()[0] as f64;
+            match target {
+                Target::Psnr(_) => 50.0 - t / 2.0,
+                _ => 200.0 - t * 3.0,
+            }
+        });
+        describe(&found)
+    }
+
+    #[test]
+    fn searches_min_size() {
+        // leaves shrink with the threshold down to 20, not enough, then with the size
+        let found = search(&calc(&[]), Target::Leaves(10), true, 64, |c| {
+            let t = c.threshold.unwrap()[0] as f64;
+            (200.0 - t).max(20.0) * 4.0 / c.min_quad_size.x as f64
+        });
+        assert_eq!(found.min_quad_size, Vec2 { x: 8, y: 8 });
+        assert_eq!(found.threshold, Some(Rgba([255; 4])));
+    }
+}
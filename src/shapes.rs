@@ -0,0 +1,489 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::{GenericImageView, ImageBuffer};
+use log::trace;
+use rayon::prelude::*;
+
+use crate::palette::ColorRegions;
+use crate::partition::{cell_side, padded_size};
+use crate::quad::{aggregate_colors, Aggregation, Guides};
+use crate::utils::{PixelBuffer, RgbaPixel, Vec2};
+
+/// a point of the image plane, pixels are centered at half coordinates
+type Point = (f64, f64);
+
+/// a convex region of a hierarchical tessellation of the image
+pub(crate) trait Shape: Copy + Send + Sync + Sized {
+    /// the shapes tiling the image of the given size
+    fn roots(size: &Vec2) -> Vec<Self>;
+    /// the smaller shapes the shape is split into
+    fn children(&self) -> Vec<Self>;
+    /// the corners, in order along the border
+    fn vertices(&self) -> Vec<Point>;
+
+    /// if the point is inside the shape or on its border
+    fn contains(&self, p: Point) -> bool {
+        let v = self.vertices();
+        // on the same side of every edge
+        let sides: Vec<f64> = (0..v.len())
+            .map(|i| {
+                let (a, b) = (v[i], v[(i + 1) % v.len()]);
+                (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+            })
+            .collect();
+        sides.iter().all(|s| *s >= -1e-9) || sides.iter().all(|s| *s <= 1e-9)
+    }
+
+    /// the smallest rectangle of pixels containing the shape, as position and size.
+    /// Can go past the image
+    fn bounds(&self) -> (Point, Point) {
+        let v = self.vertices();
+        let min = v
+            .iter()
+            .fold((f64::MAX, f64::MAX), |m, p| (m.0.min(p.0), m.1.min(p.1)));
+        let max = v
+            .iter()
+            .fold((f64::MIN, f64::MIN), |m, p| (m.0.max(p.0), m.1.max(p.1)));
+        (min, (max.0 - min.0, max.1 - min.1))
+    }
+
+    /// the part of the bounds inside the image, None if there's none
+    fn clip(&self, size: &Vec2) -> Option<(Vec2, Vec2)> {
+        let (pos, extent) = self.bounds();
+        let x0 = pos.0.floor().max(0.0) as u32;
+        let y0 = pos.1.floor().max(0.0) as u32;
+        let x1 = ((pos.0 + extent.0).ceil().max(0.0) as u32).min(size.x);
+        let y1 = ((pos.1 + extent.1).ceil().max(0.0) as u32).min(size.y);
+        (x1 > x0 && y1 > y0).then(|| {
+            (
+                Vec2 { x: x0, y: y0 },
+                Vec2 {
+                    x: x1 - x0,
+                    y: y1 - y0,
+                },
+            )
+        })
+    }
+
+    /// the pixels of the image whose center is inside the shape
+    fn pixels(&self, size: &Vec2) -> Vec<(u32, u32)> {
+        let Some((pos, extent)) = self.clip(size) else {
+            return Vec::new();
+        };
+        (pos.y..pos.y + extent.y)
+            .flat_map(|y| (pos.x..pos.x + extent.x).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.contains((x as f64 + 0.5, y as f64 + 0.5)))
+            .collect()
+    }
+}
+
+/// right isosceles triangle, split in two by the altitude of the right angle
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Triangle {
+    /// the vertex of the right angle
+    right: Point,
+    b: Point,
+    c: Point,
+}
+
+impl Shape for Triangle {
+    /// two triangles for each square cell
+    fn roots(size: &Vec2) -> Vec<Self> {
+        let side = cell_side(size);
+        let grid = padded_size(size, side);
+        let side = side as f64;
+        (0..grid.y / side as u32)
+            .flat_map(|y| (0..grid.x / side as u32).map(move |x| (x as f64, y as f64)))
+            .flat_map(|(x, y)| {
+                let (x0, y0, x1, y1) = (x * side, y * side, (x + 1.0) * side, (y + 1.0) * side);
+                [
+                    Triangle {
+                        right: (x0, y0),
+                        b: (x0, y1),
+                        c: (x1, y0),
+                    },
+                    Triangle {
+                        right: (x1, y1),
+                        b: (x1, y0),
+                        c: (x0, y1),
+                    },
+                ]
+            })
+            .collect()
+    }
+
+    fn children(&self) -> Vec<Self> {
+        let m = ((self.b.0 + self.c.0) / 2.0, (self.b.1 + self.c.1) / 2.0);
+        vec![
+            Triangle {
+                right: m,
+                b: self.c,
+                c: self.right,
+            },
+            Triangle {
+                right: m,
+                b: self.right,
+                c: self.b,
+            },
+        ]
+    }
+
+    fn vertices(&self) -> Vec<Point> {
+        vec![self.right, self.b, self.c]
+    }
+}
+
+/// pointy top hexagon of an aperture 4 hierarchy: each level halves the radius
+/// and a hexagon keeps its center and three of the six hexagons on its edges
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Hexagon {
+    /// axial coordinates in the grid of its level
+    q: i64,
+    r: i64,
+    radius: f64,
+}
+
+impl Hexagon {
+    fn center(&self) -> Point {
+        let w = self.radius * 3f64.sqrt();
+        (
+            w * (self.q as f64 + self.r as f64 / 2.0),
+            self.radius * 1.5 * self.r as f64,
+        )
+    }
+}
+
+impl Shape for Hexagon {
+    /// the hexagons as big as the square cells covering the image
+    fn roots(size: &Vec2) -> Vec<Self> {
+        let radius = cell_side(size) as f64 / 2.0;
+        let rows = (size.y as f64 / (radius * 1.5)).ceil() as i64 + 1;
+        let cols = (size.x as f64 / (radius * 3f64.sqrt())).ceil() as i64 + 1;
+        (0..=rows)
+            .flat_map(|r| {
+                // rows are shifted by half a hexagon each
+                (-r / 2 - 1..=cols - r / 2).map(move |q| Hexagon { q, r, radius })
+            })
+            .filter(|h| h.clip(size).is_some())
+            .collect()
+    }
+
+    fn children(&self) -> Vec<Self> {
+        let (q, r) = (self.q * 2, self.r * 2);
+        let radius = self.radius / 2.0;
+        [(0, 0), (1, 0), (-1, 1), (0, -1)]
+            .iter()
+            .map(|(dq, dr)| Hexagon {
+                q: q + dq,
+                r: r + dr,
+                radius,
+            })
+            .collect()
+    }
+
+    fn vertices(&self) -> Vec<Point> {
+        let (x, y) = self.center();
+        (0..6)
+            .map(|i| {
+                let a = std::f64::consts::FRAC_PI_3 * i as f64 - std::f64::consts::FRAC_PI_2;
+                (x + self.radius * a.cos(), y - self.radius * a.sin())
+            })
+            .collect()
+    }
+}
+
+/// a shape and its color
+pub struct Cell<S, P> {
+    pub shape: S,
+    pub depth: u8,
+    pub color: Option<P>,
+}
+
+/// the shapes of every depth, the deeper ones come later and are drawn over the others
+pub struct ShapeStructure<S, P> {
+    pub cells: Vec<Cell<S, P>>,
+    pub size: Vec2,
+}
+
+impl<S, P> ShapeStructure<S, P> {
+    /// number of subdivisions of the deepest shape
+    pub fn depth(&self) -> u8 {
+        self.cells.last().map_or(0, |c| c.depth)
+    }
+}
+
+impl<S: Shape, P: RgbaPixel> ColorRegions<P> for ShapeStructure<S, P> {
+    fn colors(&self) -> impl Iterator<Item = (P, f64)> {
+        self.cells.iter().filter_map(|c| {
+            let (_, extent) = c.shape.bounds();
+            c.color.map(|color| (color, extent.0 * extent.1))
+        })
+    }
+
+    fn colors_mut(&mut self) -> impl Iterator<Item = &mut P> {
+        self.cells.iter_mut().filter_map(|c| c.color.as_mut())
+    }
+}
+
+/// color of the pixels inside the shape, None if it has none
+fn shape_color<I, P, S>(img: &I, shape: &S, aggregation: Aggregation) -> Option<[P::Subpixel; 4]>
+where
+    I: GenericImageView<Pixel = P>,
+    P: RgbaPixel,
+    S: Shape,
+{
+    let pixels = shape.pixels(&Vec2::from(img.dimensions()));
+    // the aggregations don't care about the position of the pixels
+    let n = pixels.len() as u32;
+    let row: PixelBuffer<P> = ImageBuffer::from_fn(n, 1, |i, _| {
+        img.get_pixel(pixels[i as usize].0, pixels[i as usize].1)
+    });
+    (n > 0).then(|| aggregate_colors(&row, &Vec2::ZERO, &Vec2 { x: n, y: 1 }, aggregation))
+}
+
+/// subdivides the image in shapes, with the same criteria of the quads
+pub(crate) fn calc_shapes<S, I, P>(
+    img: &I,
+    min_quad_size: &Vec2,
+    min_depth: u8,
+    treshold: &P,
+    do_calc_color: bool,
+    aggregation: Aggregation,
+    guides: &Guides,
+) -> ShapeStructure<S, P>
+where
+    S: Shape,
+    I: GenericImageView<Pixel = P> + Sync,
+    P: RgbaPixel,
+{
+    let size = Vec2::from(img.dimensions());
+    let mut structure = ShapeStructure {
+        cells: Vec::new(),
+        size,
+    };
+    let mut nodes = S::roots(&size);
+    trace!("tiling with {} shapes", nodes.len());
+    for shape in nodes.iter() {
+        let color = shape_color(img, shape, aggregation).filter(|_| do_calc_color);
+        structure.cells.push(Cell {
+            shape: *shape,
+            depth: 0,
+            color: color.map(|c| *P::from_slice(&c)),
+        });
+    }
+
+    let mut curr_depth: u8 = 1;
+    while !nodes.is_empty() && curr_depth < u8::MAX {
+        trace!("Iteration: {}, shapes {}", curr_depth, nodes.len());
+        let cells_out = Vec::from_par_iter(
+            nodes
+                .par_iter()
+                .map(|node| -> Option<Vec<Cell<S, P>>> {
+                    let (pos, extent) = node.clip(&size)?;
+                    let limits =
+                        guides.limits(&pos, &extent, min_quad_size, min_depth, treshold)?;
+                    let children: Vec<_> = node
+                        .children()
+                        .into_iter()
+                        .filter_map(|c| c.clip(&size).map(|_| c))
+                        .collect();
+                    // the size of the children, unclipped
+                    let (_, child) = children.first()?.bounds();
+                    let child = Vec2 {
+                        x: child.0.round() as u32,
+                        y: child.1.round() as u32,
+                    };
+                    if child < limits.min_size || child.x == 0 || child.y == 0 {
+                        return None;
+                    }
+
+                    let mut subs: Vec<_> = children
+                        .into_iter()
+                        .map(|shape| Cell {
+                            shape,
+                            depth: curr_depth,
+                            color: None,
+                        })
+                        .collect();
+                    if curr_depth > limits.min_depth || do_calc_color {
+                        // colors are needed only to be compared or kept
+                        let colors = (guides.split.by_color() || do_calc_color).then(|| {
+                            subs.iter()
+                                .map(|s| shape_color(img, &s.shape, aggregation))
+                                .collect::<Vec<_>>()
+                        });
+                        // children with no pixels have no color to compare
+                        let averages: Option<Vec<_>> = colors
+                            .as_ref()
+                            .map(|c| c.iter().flatten().copied().collect());
+                        if !guides.splits(&pos, &extent, averages.as_deref(), &limits) {
+                            return None;
+                        }
+                        if let Some(colors) = colors.filter(|_| do_calc_color) {
+                            for (sub, color) in subs.iter_mut().zip(colors) {
+                                sub.color = color.map(|c| *P::from_slice(&c));
+                            }
+                        }
+                    }
+                    Some(subs)
+                })
+                .flatten()
+                .flatten(),
+        );
+        nodes = cells_out.iter().map(|c| c.shape).collect();
+        structure.cells.extend(cells_out);
+        curr_depth += 1;
+    }
+    structure
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::collections::HashMap;
+    use test_case::test_case;
+
+    /// how many times each pixel is covered by the shapes
+    fn coverage<S: Shape>(shapes: &[S], size: &Vec2) -> HashMap<(u32, u32), usize> {
+        let mut covered = HashMap::new();
+        for s in shapes {
+            for p in s.pixels(size) {
+                *covered.entry(p).or_insert(0) += 1;
+            }
+        }
+        covered
+    }
+
+    #[test_case(Vec2 { x: 20, y: 12 }; "wide")]
+    #[test_case(Vec2 { x: 7, y: 9 }; "odd")]
+    fn triangles_tile(size: Vec2) {
+        let roots = Triangle::roots(&size);
+        let covered = coverage(&roots, &size);
+        assert_eq!(covered.len() as u32, size.x * size.y);
+
+        let children: Vec<_> = roots.iter().flat_map(|t| t.children()).collect();
+        assert_eq!(children.len(), roots.len() * 2);
+        assert_eq!(coverage(&children, &size).len() as u32, size.x * size.y);
+    }
+
+    #[test]
+    fn bisects_triangles() {
+        let t = Triangle {
+            right: (0.0, 0.0),
+            b: (0.0, 8.0),
+            c: (8.0, 0.0),
+        };
+        let [a, b] = t.children()[..] else {
+            panic!("not bisected")
+        };
+        assert_eq!(a.right, (4.0, 4.0));
+        assert_eq!(b.right, (4.0, 4.0));
+        // still right isosceles, with half the area
+        for child in [a, b] {
+            let (_, extent) = child.bounds();
+            assert_eq!(extent.0 * extent.1 / 2.0, 16.0);
+        }
+    }
+
+    #[test_case(Vec2 { x: 20, y: 12 }; "wide")]
+    #[test_case(Vec2 { x: 64, y: 64 }; "square")]
+    fn hexagons_tile(size: Vec2) {
+        let roots = Hexagon::roots(&size);
+        assert_eq!(coverage(&roots, &size).len() as u32, size.x * size.y);
+    }
+
+    #[test]
+    fn hexagon_children_are_unique() {
+        // every hexagon of the next level has a single parent
+        let parents: Vec<_> = (-2..2)
+            .flat_map(|q| (-2..2).map(move |r| Hexagon { q, r, radius: 8.0 }))
+            .collect();
+        let mut children: Vec<_> = parents
+            .iter()
+            .flat_map(|h| h.children())
+            .map(|h| (h.q, h.r))
+            .collect();
+        let count = children.len();
+        children.sort();
+        children.dedup();
+        assert_eq!(children.len(), count);
+        assert_eq!(count, parents.len() * 4);
+    }
+
+    #[test]
+    fn hexagon_children_are_on_edges() {
+        let h = Hexagon {
+            q: 1,
+            r: 1,
+            radius: 8.0,
+        };
+        let center = h.center();
+        let apothem = 8.0 * 3f64.sqrt() / 2.0;
+        for (i, child) in h.children().iter().enumerate() {
+            let c = child.center();
+            let d = ((c.0 - center.0).powi(2) + (c.1 - center.1).powi(2)).sqrt();
+            let expected = if i == 0 { 0.0 } else { apothem };
+            assert!((d - expected).abs() < 1e-9);
+        }
+    }
+
+    /// the deepest shapes entirely on the left and on the right, away from the middle
+    fn depths<S: Shape>(structure: &ShapeStructure<S, Rgba<u8>>) -> (u8, u8) {
+        let deepest = |side: &dyn Fn(f64) -> bool| {
+            structure
+                .cells
+                .iter()
+                .filter(|c| c.shape.vertices().iter().all(|v| side(v.0)))
+                .map(|c| c.depth)
+                .max()
+                .unwrap_or(0)
+        };
+        (deepest(&|x| x <= 10.0), deepest(&|x| x >= 22.0))
+    }
+
+    #[test_case(true; "triangle")]
+    #[test_case(false; "hexagon")]
+    fn refines_details(triangle: bool) {
+        // flat on the left, a steep gradient on the right
+        let img = RgbaImage::from_fn(32, 32, |x, y| match x < 16 {
+            true => Rgba([0, 0, 0, 255]),
+            false => Rgba([((x - 16) * 8 + y * 4) as u8, 0, 0, 255]),
+        });
+        let min_size = Vec2 { x: 2, y: 2 };
+        let treshold = Rgba([8, 8, 8, 8]);
+        let (left, right) = match triangle {
+            true => depths(&calc_shapes::<Triangle, _, _>(
+                &img,
+                &min_size,
+                0,
+                &treshold,
+                true,
+                Aggregation::Mean,
+                &Guides::default(),
+            )),
+            false => depths(&calc_shapes::<Hexagon, _, _>(
+                &img,
+                &min_size,
+                0,
+                &treshold,
+                true,
+                Aggregation::Mean,
+                &Guides::default(),
+            )),
+        };
+        assert!(right > left + 1, "{left} {right}");
+    }
+}
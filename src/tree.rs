@@ -0,0 +1,175 @@
+/* Copyright 2023 Comparin Jacopo
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use image::Rgba;
+
+use crate::utils::{Quad, QuadMap, QuadStructure, Vec2};
+
+/// index of a node in its tree
+pub type NodeId = usize;
+
+/// a quad of the tree and the region of the image it covers
+#[derive(Clone, Debug)]
+pub struct QuadNode<P = Rgba<u8>> {
+    pub pos: Vec2,
+    pub size: Vec2,
+    pub quad: Quad<P>,
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    /// if the quad isn't split, kept up to date as children are added
+    pub leaf: bool,
+}
+
+/// the quads of every depth with their parents and children, stored in an arena.
+/// The partitions tiling the image with cells have one root per cell
+pub struct QuadTree<P = Rgba<u8>> {
+    nodes: Vec<QuadNode<P>>,
+    roots: Vec<NodeId>,
+    /// as in `QuadStructure`
+    pub sizes: Vec<Vec2>,
+}
+
+impl<P> QuadTree<P> {
+    pub fn new(size: Vec2) -> Self {
+        Self {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            sizes: vec![size],
+        }
+    }
+
+    pub fn add_root(&mut self, pos: Vec2, size: Vec2, quad: Quad<P>) -> NodeId {
+        let id = self.push(pos, size, quad, None);
+        self.roots.push(id);
+        id
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, pos: Vec2, size: Vec2, quad: Quad<P>) -> NodeId {
+        let id = self.push(pos, size, quad, Some(parent));
+        let parent = &mut self.nodes[parent];
+        parent.children.push(id);
+        parent.leaf = false;
+        id
+    }
+
+    fn push(&mut self, pos: Vec2, size: Vec2, quad: Quad<P>, parent: Option<NodeId>) -> NodeId {
+        self.nodes.push(QuadNode {
+            pos,
+            size,
+            quad,
+            parent,
+            children: Vec::new(),
+            leaf: true,
+        });
+        self.nodes.len() - 1
+    }
+
+    pub fn node(&self, id: NodeId) -> &QuadNode<P> {
+        &self.nodes[id]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut QuadNode<P> {
+        &mut self.nodes[id]
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// every node, parents before their children
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &QuadNode<P>)> {
+        self.nodes.iter().enumerate()
+    }
+
+    pub fn leaves(&self) -> impl Iterator<Item = (NodeId, &QuadNode<P>)> {
+        self.nodes().filter(|(_, n)| n.leaf)
+    }
+}
+
+/// the leaves by position, as they're drawn.
+/// A root as big as the image that was never split is drawn only if it has a color
+impl<P: Clone> From<QuadTree<P>> for QuadStructure<P> {
+    fn from(tree: QuadTree<P>) -> Self {
+        let image = tree.sizes[0];
+        let map: QuadMap<P> = tree
+            .leaves()
+            .filter(|(_, n)| {
+                let whole = n.parent.is_none() && n.quad.size.is_none() && n.size == image;
+                !whole || n.quad.color.is_some()
+            })
+            .map(|(_, n)| (n.pos, n.quad.clone()))
+            .collect();
+        QuadStructure {
+            map,
+            sizes: tree.sizes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    /// a root split in two, the first child split in two again
+    fn tree() -> QuadTree {
+        let mut tree = QuadTree::new(Vec2 { x: 4, y: 4 });
+        let root = tree.add_root(Vec2::ZERO, Vec2 { x: 4, y: 4 }, Quad::new(0));
+        let a = tree.add_child(root, Vec2::ZERO, Vec2 { x: 2, y: 4 }, Quad::new(1));
+        tree.add_child(root, Vec2 { x: 2, y: 0 }, Vec2 { x: 2, y: 4 }, Quad::new(1));
+        tree.add_child(a, Vec2::ZERO, Vec2 { x: 2, y: 2 }, Quad::new(2));
+        tree.add_child(a, Vec2 { x: 0, y: 2 }, Vec2 { x: 2, y: 2 }, Quad::new(2));
+        tree
+    }
+
+    #[test]
+    fn navigates() {
+        let tree = tree();
+        assert_eq!(tree.roots(), &[0]);
+        assert_eq!(tree.node(0).children, vec![1, 2]);
+        assert_eq!(tree.node(1).children, vec![3, 4]);
+        assert_eq!(tree.node(3).parent, Some(1));
+        assert_eq!(tree.node(1).parent, Some(0));
+        assert_eq!(tree.node(0).parent, None);
+    }
+
+    #[test]
+    fn flags_leaves() {
+        let tree = tree();
+        let leaves: Vec<_> = tree.leaves().map(|(id, _)| id).collect();
+        assert_eq!(leaves, vec![2, 3, 4]);
+        assert!(!tree.node(1).leaf);
+    }
+
+    #[test]
+    fn converts_to_map() {
+        let structure = QuadStructure::from(tree());
+        assert_eq!(structure.map.len(), 3);
+        assert_eq!(structure.map[&Vec2::ZERO].depth, 2);
+        assert_eq!(structure.map[&Vec2 { x: 2, y: 0 }].depth, 1);
+    }
+
+    #[test_case(None => 0; "uncolored")]
+    #[test_case(Some(Rgba([1, 2, 3, 4])) => 1; "colored")]
+    fn converts_unsplit_root(color: Option<Rgba<u8>>) -> usize {
+        let mut tree = QuadTree::new(Vec2 { x: 4, y: 4 });
+        let root = tree.add_root(Vec2::ZERO, Vec2 { x: 4, y: 4 }, Quad::new(0));
+        tree.node_mut(root).quad.color = color;
+        QuadStructure::from(tree).map.len()
+    }
+}
@@ -14,10 +14,23 @@
  */
 use std::{cmp::Ordering, collections::HashMap, hash::Hash};
 
-use image::Rgba;
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive, Rgba};
+use num_traits::{NumCast, ToPrimitive};
 
 /* data structures */
 
+/// image buffer of the given pixel type
+pub type PixelBuffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// RGBA pixels quads can be calculated and drawn with: 8 bit, 16 bit and float
+pub trait RgbaPixel: Pixel + Send + Sync + std::fmt::Debug + 'static {
+    /// converts the image to a buffer of this pixel type
+    fn to_buffer(img: &DynamicImage) -> PixelBuffer<Self>;
+    /// borrows the image as a buffer if it already is of this pixel type
+    fn as_buffer(img: &DynamicImage) -> Option<&PixelBuffer<Self>>;
+    fn into_dynamic(buf: PixelBuffer<Self>) -> DynamicImage;
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct Vec2 {
     pub x: u32,
@@ -25,17 +38,19 @@ pub struct Vec2 {
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
-pub struct Quad {
+pub struct Quad<P = Rgba<u8>> {
     pub depth: u8,
-    pub color: Option<Rgba<u8>>,
+    pub color: Option<P>,
+    /// exact size, when it's not the size of its depth
+    pub size: Option<Vec2>,
 }
 
-pub struct VecQuad(pub Vec2, pub Quad);
-pub type QuadMap = HashMap<Vec2, Quad>;
+pub struct VecQuad<P = Rgba<u8>>(pub Vec2, pub Quad<P>);
+pub type QuadMap<P = Rgba<u8>> = HashMap<Vec2, Quad<P>>;
 
-pub struct QuadStructure {
+pub struct QuadStructure<P = Rgba<u8>> {
     /// position : quad info
-    pub map: QuadMap,
+    pub map: QuadMap<P>,
     /// starts with the image size and then the halved sizes based on depth
     pub sizes: Vec<Vec2>,
 }
@@ -58,6 +73,18 @@ impl Vec2 {
             },
         )
     }
+
+    /// returns the two sizes halving it exactly, the first one keeping the remainder
+    pub fn split(&self) -> (Vec2, Vec2) {
+        let (half, modulo) = self.half();
+        (
+            Vec2 {
+                x: half.x + modulo.x,
+                y: half.y + modulo.y,
+            },
+            half,
+        )
+    }
 }
 impl From<(u32, u32)> for Vec2 {
     fn from(src: (u32, u32)) -> Self {
@@ -79,24 +106,111 @@ impl std::fmt::Display for Vec2 {
     }
 }
 
-impl Quad {
+impl<P> Quad<P> {
     pub fn new(d: u8) -> Self {
         Self {
             depth: d,
             color: None,
+            size: None,
         }
     }
 }
 
-impl From<Rgba<u8>> for Quad {
-    fn from(color: Rgba<u8>) -> Self {
+impl<P: RgbaPixel> From<P> for Quad<P> {
+    fn from(color: P) -> Self {
         Self {
             depth: 0,
             color: Some(color),
+            size: None,
         }
     }
 }
 
+impl<P> QuadStructure<P> {
+    /// size of the quad, its own or the one of its depth
+    pub fn size_of(&self, quad: &Quad<P>) -> Vec2 {
+        quad.size.unwrap_or_else(|| self.sizes[quad.depth as usize])
+    }
+
+    /// number of subdivisions of the deepest quad
+    pub fn depth(&self) -> u8 {
+        self.map.values().map(|q| q.depth).max().unwrap_or(0)
+    }
+}
+
+impl RgbaPixel for Rgba<u8> {
+    fn to_buffer(img: &DynamicImage) -> PixelBuffer<Self> {
+        img.to_rgba8()
+    }
+    fn as_buffer(img: &DynamicImage) -> Option<&PixelBuffer<Self>> {
+        img.as_rgba8()
+    }
+    fn into_dynamic(buf: PixelBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba8(buf)
+    }
+}
+impl RgbaPixel for Rgba<u16> {
+    fn to_buffer(img: &DynamicImage) -> PixelBuffer<Self> {
+        img.to_rgba16()
+    }
+    fn as_buffer(img: &DynamicImage) -> Option<&PixelBuffer<Self>> {
+        img.as_rgba16()
+    }
+    fn into_dynamic(buf: PixelBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba16(buf)
+    }
+}
+impl RgbaPixel for Rgba<f32> {
+    fn to_buffer(img: &DynamicImage) -> PixelBuffer<Self> {
+        img.to_rgba32f()
+    }
+    fn as_buffer(img: &DynamicImage) -> Option<&PixelBuffer<Self>> {
+        img.as_rgba32f()
+    }
+    fn into_dynamic(buf: PixelBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba32F(buf)
+    }
+}
+
+/// scales an 8 bit color to the range of the pixel type
+pub fn scale_color<P: RgbaPixel>(c: &Rgba<u8>) -> P {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    *P::from_slice(&c.0.map(|v| NumCast::from(v as f64 * max / 255.0).unwrap()))
+}
+
+/// coefficients of the RGB channels in the luma
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum LumaStandard {
+    /// HDTV coefficients
+    #[default]
+    Rec709,
+    /// SDTV coefficients
+    Rec601,
+}
+
+impl LumaStandard {
+    /// luma of the normalized RGB channels, from 0 to 1
+    pub fn luma(&self, c: &[f64]) -> f64 {
+        let k = match self {
+            LumaStandard::Rec709 => [0.2126, 0.7152, 0.0722],
+            LumaStandard::Rec601 => [0.299, 0.587, 0.114],
+        };
+        k[0] * c[0] + k[1] * c[1] + k[2] * c[2]
+    }
+}
+
+/// channels scaled from 0 to 1
+pub fn normalized<T: Primitive>(c: &[T]) -> [f64; 4] {
+    let max = T::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    std::array::from_fn(|i| c[i].to_f64().unwrap() / max)
+}
+
+/// Rec. 709 luma premultiplied by alpha, from 0 to 1
+pub fn luma<P: RgbaPixel>(p: &P) -> f64 {
+    let c = normalized(p.channels());
+    LumaStandard::Rec709.luma(&c) * c[3]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,6 +224,35 @@ mod tests {
         v2in.half()
     }
 
+    #[test_case(Vec2{x:10,y:10} => (Vec2{x:5,y:5}, Vec2{x:5,y:5}); "even")]
+    #[test_case(Vec2{x:7,y:6} => (Vec2{x:4,y:3}, Vec2{x:3,y:3}); "odd-x")]
+    #[test_case(Vec2{x:1,y:13} => (Vec2{x:1,y:7}, Vec2{x:0,y:6}); "single")]
+    fn vec2_splits(v2in: Vec2) -> (Vec2, Vec2) {
+        v2in.split()
+    }
+
+    #[test_case(Rgba([0, 8, 128, 255]) => Rgba([0u16, 2056, 32896, 65535]); "u16")]
+    fn scales_color_u16(c: Rgba<u8>) -> Rgba<u16> {
+        scale_color(&c)
+    }
+
+    #[test_case(Rgba([0, 51, 255, 255]) => Rgba([0.0f32, 0.2, 1.0, 1.0]); "f32")]
+    fn scales_color_f32(c: Rgba<u8>) -> Rgba<f32> {
+        scale_color(&c)
+    }
+
+    #[test]
+    fn scales_color_u8() {
+        assert!((0..=255).all(|v| scale_color::<Rgba<u8>>(&Rgba([v; 4])) == Rgba([v; 4])))
+    }
+
+    #[test_case(Rgba([255, 255, 255, 255]) => 1.0; "white")]
+    #[test_case(Rgba([255, 255, 255, 0]) => 0.0; "transparent")]
+    #[test_case(Rgba([0, 255, 0, 255]) => 0.7152; "green")]
+    fn lumas(c: Rgba<u8>) -> f64 {
+        (luma(&c) * 1e6).round() / 1e6
+    }
+
     #[test]
     fn vec2_formats() {
         assert_eq!(Vec2 { x: 104, y: 6 }.to_string(), "(104,6)")
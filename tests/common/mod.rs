@@ -1,8 +1,8 @@
 use std::{
     fs::File,
-    io::Read,
+    io::{Read, Write},
     path::{Path, PathBuf},
-    process::{Command, Output},
+    process::{Command, Output, Stdio},
 };
 
 // CONSTANTS
@@ -37,6 +37,25 @@ pub fn run(args: Vec<&str>) -> Output {
         .expect("Error running the executable!")
 }
 
+pub fn run_piped(args: Vec<&str>, stdin: &[u8]) -> Output {
+    println!("arguments: {args:?}");
+    let mut child = Command::new(BIN_PATH)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Error launching the executable!");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin)
+        .expect("Error writing to stdin!");
+    child
+        .wait_with_output()
+        .expect("Error running the executable!")
+}
+
 pub const FILE_ERR_MSG: &str = "Error opening file!";
 
 pub fn assert_images_eq(size: usize, a: &PathBuf, b: &PathBuf) {
@@ -68,3 +68,395 @@ fn input_not_found() {
     assert!(!output.status.success());
     assert_eq!(output.status.code(), Some(101));
 }
+
+#[test]
+fn batch_output_name() {
+    let inp = PathBuf::from(TMP_DIR).join("batch.in");
+    let outp = PathBuf::from(TMP_DIR).join("batch.out");
+    std::fs::create_dir_all(&inp).unwrap();
+    std::fs::create_dir_all(&outp).unwrap();
+    std::fs::copy(resource(RES_SQUARE), inp.join("square.png")).unwrap();
+
+    let output = run(vec![
+        "--input",
+        strpath(&inp),
+        "--output",
+        strpath(&outp),
+        "--output-name",
+        "{stem}-d{depth}.{ext}",
+        "--output-format",
+        "bmp",
+    ]);
+
+    assert!(output.status.success());
+    assert!(outp.join("square-d4.bmp").is_file());
+}
+
+#[test]
+fn stdin_stdout() {
+    let input = std::fs::read(resource(RES_SQUARE)).unwrap();
+
+    let output = run_piped(
+        vec![
+            "-vvv",
+            "--color",
+            "red",
+            "--input",
+            "-",
+            "--output",
+            "-",
+            "--output-format",
+            "png",
+        ],
+        &input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        std::fs::read(resource(RES_EXP_SIMPLE)).unwrap()
+    );
+}
+
+#[test]
+fn stdout_requires_format() {
+    let output = run(vec![
+        "--input",
+        strpath(&resource(RES_SQUARE)),
+        "--output",
+        "-",
+    ]);
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn fill_palette() {
+    let outp = PathBuf::from(TMP_DIR).join("test.palette.png");
+
+    let output = run(vec![
+        "--fill",
+        "--palette",
+        "black",
+        "white",
+        "--input",
+        strpath(&resource(RES_SQUARE)),
+        "--output",
+        strpath(&outp),
+    ]);
+
+    assert!(output.status.success());
+    let img = image::open(&outp).unwrap().to_rgba8();
+    assert!(img
+        .pixels()
+        .all(|p| p.0 == [0, 0, 0, 255] || p.0 == [255, 255, 255, 255]));
+}
+
+#[test]
+fn palette_requires_fill() {
+    let output = run(vec![
+        "--palette-size",
+        "4",
+        "--input",
+        strpath(&resource(RES_SQUARE)),
+        "--output",
+        strpath(&PathBuf::from(TMP_DIR).join("shouldnt-exist.png")),
+    ]);
+
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn mask_exclude() {
+    let mask = PathBuf::from(TMP_DIR).join("mask.black.png");
+    let outp = PathBuf::from(TMP_DIR).join("test.mask.png");
+    image::GrayImage::new(4, 4).save(&mask).unwrap();
+
+    let output = run(vec![
+        "--mask",
+        strpath(&mask),
+        "--mask-mode",
+        "exclude",
+        "--input",
+        strpath(&resource(RES_SQUARE)),
+        "--output",
+        strpath(&outp),
+    ]);
+
+    // nothing is split, so nothing is drawn
+    assert!(output.status.success());
+    assert_eq!(
+        image::open(&outp).unwrap().to_rgba8(),
+        image::open(resource(RES_SQUARE)).unwrap().to_rgba8()
+    );
+}
+
+#[test]
+fn square_cells_pad() {
+    let inp = PathBuf::from(TMP_DIR).join("wide.png");
+    let outp = PathBuf::from(TMP_DIR).join("test.cells.png");
+    image::RgbaImage::from_pixel(20, 12, image::Rgba([255, 0, 0, 255]))
+        .save(&inp)
+        .unwrap();
+
+    let output = run(vec![
+        "--partition",
+        "square",
+        "--cell-edges",
+        "pad",
+        "--fill",
+        "--input",
+        strpath(&inp),
+        "--output",
+        strpath(&outp),
+    ]);
+
+    // cells of 8 pixels, the padding is cropped from the output
+    assert!(output.status.success());
+    let img = image::open(&outp).unwrap().to_rgba8();
+    assert_eq!(img.dimensions(), (20, 12));
+    assert_eq!(img.get_pixel(19, 11), &image::Rgba([255, 0, 0, 255]));
+}
+
+#[test]
+fn square_cells_pad_stats() {
+    let inp = PathBuf::from(TMP_DIR).join("wide.stats.png");
+    image::RgbaImage::from_pixel(20, 12, image::Rgba([255, 0, 0, 255]))
+        .save(&inp)
+        .unwrap();
+
+    let output = run_piped(
+        vec![
+            "stats",
+            "--partition",
+            "square",
+            "--cell-edges",
+            "pad",
+            "--format",
+            "json",
+            "--input",
+            strpath(&inp),
+        ],
+        &[],
+    );
+
+    // the 4x4 leaves of the 24x16 padded image, only 5x3 of them cover the image
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains(r#""leaves":15,"#));
+}
+
+#[test]
+fn shape_partitions() {
+    let inp = PathBuf::from(TMP_DIR).join("shapes.png");
+    image::RgbaImage::from_fn(64, 40, |x, y| match x < 32 {
+        true => image::Rgba([0, 0, 255, 255]),
+        false => image::Rgba([(x * 4) as u8, (y * 6) as u8, 0, 255]),
+    })
+    .save(&inp)
+    .unwrap();
+
+    for partition in ["triangle", "hexagon"] {
+        let outp = PathBuf::from(TMP_DIR).join(format!("test.{partition}.png"));
+        let output = run(vec![
+            "--partition",
+            partition,
+            "--fill",
+            "--input",
+            strpath(&inp),
+            "--output",
+            strpath(&outp),
+        ]);
+
+        assert!(output.status.success());
+        let img = image::open(&outp).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (64, 40));
+        // every pixel is drawn
+        assert!(img.pixels().all(|p| p[3] == 255));
+    }
+}
+
+#[test]
+fn prints_stats() {
+    let outp = PathBuf::from(TMP_DIR).join("test.stats.png");
+
+    let output = run_piped(
+        vec![
+            "--stats",
+            "json",
+            "--color",
+            "red",
+            "--input",
+            strpath(&resource(RES_SQUARE)),
+            "--output",
+            strpath(&outp),
+        ],
+        &[],
+    );
+
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with(r#"{"input":"#));
+    assert!(report.contains(r#""leaves":"#));
+    assert!(report.contains(r#""error":{"mse":"#));
+    assert!(report.contains(r#""ssim":"#));
+    // the image is the same as without statistics
+    assert_images_eq(16 * 16, &outp, &resource(RES_EXP_SIMPLE))
+}
+
+#[test]
+fn searches_threshold() {
+    let outp = PathBuf::from(TMP_DIR).join("test.search.png");
+
+    let output = run_piped(
+        vec![
+            "--target-leaves",
+            "12",
+            "--min-depth",
+            "0",
+            "--stats",
+            "json",
+            "--input",
+            strpath(&resource(RES_SQUARE)),
+            "--output",
+            strpath(&outp),
+        ],
+        &[],
+    );
+
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let leaves: u32 = report
+        .split(r#""leaves":"#)
+        .nth(1)
+        .and_then(|s| s.split(',').next())
+        .and_then(|s| s.parse().ok())
+        .unwrap();
+    assert!(leaves <= 12);
+}
+
+#[test]
+fn config_file() {
+    let outp = PathBuf::from(TMP_DIR).join("test.config.png");
+    let config = PathBuf::from(TMP_DIR).join("test.config.toml");
+    std::fs::write(&config, "color = \"red\"\nmin-depth = 0\n").unwrap();
+
+    // the min depth of the command line overrides the one of the file
+    let output = run(vec![
+        "--config",
+        strpath(&config),
+        "--min-depth",
+        "4",
+        "--input",
+        strpath(&resource(RES_SQUARE)),
+        "--output",
+        strpath(&outp),
+    ]);
+
+    assert!(output.status.success());
+    assert_images_eq(16 * 16, &outp, &resource(RES_EXP_SIMPLE))
+}
+
+#[test]
+fn encode_decode() {
+    let encoded = PathBuf::from(TMP_DIR).join("test.encoded.qom");
+    let decoded = PathBuf::from(TMP_DIR).join("test.decoded.png");
+    let filled = PathBuf::from(TMP_DIR).join("test.filled.png");
+    let input = resource(RES_SQUARE);
+
+    let encoding = run(vec![
+        "encode",
+        "--input",
+        strpath(&input),
+        "--output",
+        strpath(&encoded),
+    ]);
+    let decoding = run(vec![
+        "decode",
+        "--input",
+        strpath(&encoded),
+        "--output",
+        strpath(&decoded),
+    ]);
+    let rendering = run(vec![
+        "render",
+        "--fill",
+        "--input",
+        strpath(&input),
+        "--output",
+        strpath(&filled),
+    ]);
+
+    assert!(encoding.status.success());
+    assert!(decoding.status.success());
+    assert!(rendering.status.success());
+    // decoding draws the quads as filling them
+    assert_images_eq(16 * 16, &decoded, &filled)
+}
+
+#[test]
+fn export_json() {
+    let output = run_piped(
+        vec![
+            "export",
+            "--min-depth",
+            "1",
+            "--input",
+            strpath(&resource(RES_SQUARE)),
+            "--output",
+            "-",
+        ],
+        &[],
+    );
+
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert!(json.starts_with(r#"{"width":16,"height":16,"leaves":[{"x":0,"y":0,"#));
+}
+
+#[test]
+fn export_json_at() {
+    let output = run_piped(
+        vec![
+            "export",
+            "--min-depth",
+            "1",
+            "--at",
+            "15,0",
+            "--neighbors",
+            "--input",
+            strpath(&resource(RES_SQUARE)),
+            "--output",
+            "-",
+        ],
+        &[],
+    );
+
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(json.matches(r#""x":"#).count(), 1);
+    assert!(json.contains(r#""neighbors":{"up":[],"#));
+}
+
+#[test]
+fn compare_same() {
+    let input = resource(RES_SQUARE);
+
+    let output = run_piped(
+        vec![
+            "compare",
+            "--format",
+            "json",
+            "--input",
+            strpath(&input),
+            "--reference",
+            strpath(&input),
+        ],
+        &[],
+    );
+
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert!(json.contains(r#""mse":0.0,"#));
+    assert!(json.contains(r#""psnr":null,"#));
+}